@@ -0,0 +1,153 @@
+//! Throughput of the hot paths of a download, on synthetic data.
+//!
+//! Run with `cargo bench`, which prints the time taken by each variant.
+
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use smart_gnomad_downloader::filter::filter_vcf_stream;
+use smart_gnomad_downloader::SortedIntervalIntersect;
+
+const RECORDS: u64 = 1_000_000;
+
+fn synthetic_lines() -> Vec<String> {
+    (1..=RECORDS)
+        .map(|pos| {
+            format!(
+                "chr1\t{}\trs{}\tA\tG\t.\tPASS\tAC=12;AN=152000;AF=7.89e-05;nhomalt=0",
+                pos * 10,
+                pos
+            )
+        })
+        .collect()
+}
+
+fn bench_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("smart_gnomad_downloader_bench_{}", name))
+}
+
+fn time<F: FnOnce()>(name: &str, f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<40} {:>8.1} ms  {:>6.2} M lines/s",
+        name,
+        elapsed.as_secs_f64() * 1000.0,
+        RECORDS as f64 / elapsed.as_secs_f64() / 1e6
+    );
+    elapsed
+}
+
+/// Writing lines to a bgzf file, straight to the file with `writeln!` as before, and through
+/// a buffer with whole-line writes as `smart_save_vcf_from_url` does.
+fn bench_bgzf_writes(lines: &[String]) {
+    let unbuffered_path = bench_path("unbuffered.vcf.gz");
+    time("bgzf, unbuffered writeln!", || {
+        let mut writer = noodles::bgzf::io::Writer::new(File::create(&unbuffered_path).unwrap());
+        for line in lines {
+            writeln!(writer, "{}", line).unwrap();
+        }
+        writer.finish().unwrap();
+    });
+
+    let buffered_path = bench_path("buffered.vcf.gz");
+    time("bgzf, buffered whole lines", || {
+        let file = BufWriter::with_capacity(1 << 20, File::create(&buffered_path).unwrap());
+        let mut writer = noodles::bgzf::io::Writer::new(file);
+        for line in lines {
+            writer.write_all(line.as_bytes()).unwrap();
+            writer.write_all(b"\n").unwrap();
+        }
+        writer.finish().unwrap().flush().unwrap();
+    });
+
+    assert_eq!(
+        std::fs::read(&unbuffered_path).unwrap(),
+        std::fs::read(&buffered_path).unwrap()
+    );
+    remove(&[&unbuffered_path, &buffered_path]);
+}
+
+/// Compressing the same lines with the single-threaded and the multithreaded bgzf writers.
+fn bench_bgzf_compression(lines: &[String]) {
+    let write = |path: &Path, workers: usize| {
+        let file = BufWriter::with_capacity(1 << 20, File::create(path).unwrap());
+        let name = format!("bgzf, {} compression thread(s)", workers);
+        if workers == 1 {
+            time(&name, || {
+                let mut writer = noodles::bgzf::io::Writer::new(file);
+                for line in lines {
+                    writer.write_all(line.as_bytes()).unwrap();
+                    writer.write_all(b"\n").unwrap();
+                }
+                writer.finish().unwrap().flush().unwrap();
+            });
+        } else {
+            time(&name, || {
+                let mut writer = noodles::bgzf::io::MultithreadedWriter::with_worker_count(
+                    NonZeroUsize::new(workers).unwrap(),
+                    file,
+                );
+                for line in lines {
+                    writer.write_all(line.as_bytes()).unwrap();
+                    writer.write_all(b"\n").unwrap();
+                }
+                writer.finish().unwrap().flush().unwrap();
+            });
+        }
+    };
+
+    let paths: Vec<PathBuf> = [1, 2, 4]
+        .into_iter()
+        .map(|workers| {
+            let path = bench_path(&format!("compression_{}.vcf.gz", workers));
+            write(&path, workers);
+            path
+        })
+        .collect();
+    let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+    remove(&paths);
+}
+
+/// Filtering a VCF stream, the way it was done with a `String` per line and a split to find
+/// POS, and with `filter_vcf_stream`.
+fn bench_filter(lines: &[String]) {
+    let mut vcf = lines.join("\n");
+    vcf.push('\n');
+    let regions = [(1_000_000, 2_000_000), (5_000_000, 6_000_000)];
+
+    let mut reference = Vec::new();
+    time("filter, String per line", || {
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+        for line in vcf.as_bytes().lines() {
+            let line = line.unwrap();
+            let pos: u64 = line.split('\t').nth(1).unwrap().parse().unwrap();
+            if intersection_check.in_interval(pos) == Some(true) {
+                writeln!(reference, "{}", line).unwrap();
+            }
+        }
+    });
+
+    let mut filtered = Vec::new();
+    time("filter, reused line buffer", || {
+        filter_vcf_stream(vcf.as_bytes(), &mut filtered, regions.into_iter()).unwrap();
+    });
+    assert_eq!(reference, filtered);
+}
+
+fn remove(paths: &[&Path]) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn main() {
+    let lines = synthetic_lines();
+    bench_bgzf_writes(&lines);
+    bench_bgzf_compression(&lines);
+    bench_filter(&lines);
+}
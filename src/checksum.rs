@@ -0,0 +1,401 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use indicatif::ProgressBar;
+use sha2::digest::common::hazmat::SerializableState;
+use sha2::Digest;
+
+use crate::error::DownloadError;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// MD5, 32 hex digits
+    Md5,
+    /// SHA-256, 64 hex digits
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// Guesses the algorithm of a hex checksum from its length.
+    pub fn detect(checksum: &str) -> Option<Self> {
+        match checksum.len() {
+            32 => Some(ChecksumAlgorithm::Md5),
+            64 => Some(ChecksumAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Algorithm used to check `checksum`: `algorithm` if given, otherwise the detected one.
+    pub fn resolve(algorithm: Option<Self>, checksum: &str) -> Result<Self, DownloadError> {
+        algorithm.or_else(|| Self::detect(checksum)).ok_or_else(|| {
+            DownloadError::Parse(format!(
+                "Cannot tell the algorithm of checksum {}, use --checksum-algo",
+                checksum
+            ))
+        })
+    }
+}
+
+enum Hasher {
+    Md5(md5::Md5),
+    Sha256(sha2::Sha256),
+    /// Verification is disabled, the download is only counted
+    None,
+}
+
+/// State of the MD5 of a download after its first `offset` bytes, saved in a `.md5state` file
+/// next to its part file, so that a resumed download does not hash the part file again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Md5Checkpoint {
+    pub offset: u64,
+    state: Vec<u8>,
+}
+
+impl Md5Checkpoint {
+    /// Reads a checkpoint written by `write`, `None` if there is none or it cannot be read.
+    pub fn read(path: &Path) -> Option<Self> {
+        let content = std::fs::read_to_string(path).ok()?;
+        let (offset, state) = content.trim_end().split_once(' ')?;
+        let state = (0..state.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(state.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Md5Checkpoint {
+            offset: offset.parse().ok()?,
+            state,
+        })
+    }
+
+    /// Writes the checkpoint as `<offset> <hex state>`, replacing the previous one at once.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let state: String = self.state.iter().map(|b| format!("{:02x}", b)).collect();
+        let tmp_path = path.with_extension("md5state.tmp");
+        std::fs::write(&tmp_path, format!("{} {}\n", self.offset, state))?;
+        std::fs::rename(tmp_path, path)
+    }
+
+    fn hasher(&self) -> Option<md5::Md5> {
+        md5::Md5::deserialize(self.state.as_slice().try_into().ok()?).ok()
+    }
+}
+
+/// Hashes the raw download as it goes through, advancing the progress bar (and the bar of the
+/// whole run, if any).
+pub(crate) struct ChecksumWriter {
+    hasher: Hasher,
+    progress_bar: ProgressBar,
+    total_progress_bar: Option<ProgressBar>,
+    bytes: u64,
+    /// Bytes already in the hash, restored from a checkpoint: only counted when written again
+    hashed: u64,
+    /// Where to save a checkpoint every so many bytes, and the offset of the next one
+    checkpoints: Option<(PathBuf, u64, u64)>,
+}
+
+/// Every `write` consumes the whole buffer, there is no sink to apply backpressure. Callers
+/// still go through `write_all` (as `tee::TeeReader` does), so that a short write would not
+/// silently leave bytes out of the checksum.
+impl Write for ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let already_hashed = self.hashed.saturating_sub(self.bytes).min(buf.len() as u64);
+        let new = &buf[already_hashed as usize..];
+        match &mut self.hasher {
+            Hasher::Md5(hasher) => hasher.update(new),
+            Hasher::Sha256(hasher) => hasher.update(new),
+            Hasher::None => {}
+        }
+        self.bytes += buf.len() as u64;
+        self.save_checkpoint();
+        self.progress_bar.inc(buf.len() as u64);
+        if let Some(total_progress_bar) = &self.total_progress_bar {
+            total_progress_bar.inc(buf.len() as u64);
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ChecksumWriter {
+    /// Without an `algorithm`, nothing is hashed and `digest` always succeeds.
+    pub fn new(
+        algorithm: Option<ChecksumAlgorithm>,
+        progress_bar: ProgressBar,
+        total_progress_bar: Option<ProgressBar>,
+    ) -> Self {
+        let hasher = match algorithm {
+            Some(ChecksumAlgorithm::Md5) => Hasher::Md5(md5::Md5::new()),
+            Some(ChecksumAlgorithm::Sha256) => Hasher::Sha256(sha2::Sha256::new()),
+            None => Hasher::None,
+        };
+        ChecksumWriter {
+            hasher,
+            progress_bar,
+            total_progress_bar,
+            bytes: 0,
+            hashed: 0,
+            checkpoints: None,
+        }
+    }
+
+    /// Starts from `checkpoint`, as if its first `offset` bytes had been hashed: they still have
+    /// to be written, but are only counted. Fails (returning `false`) unless the hash is an MD5
+    /// and nothing was written yet.
+    pub fn resume(&mut self, checkpoint: &Md5Checkpoint) -> bool {
+        let (Hasher::Md5(hasher), 0) = (&mut self.hasher, self.bytes) else {
+            return false;
+        };
+        let Some(restored) = checkpoint.hasher() else {
+            return false;
+        };
+        *hasher = restored;
+        self.hashed = checkpoint.offset;
+        true
+    }
+
+    /// Saves an `Md5Checkpoint` to `path` every `interval` bytes, if the hash is an MD5.
+    pub fn save_checkpoints(&mut self, path: PathBuf, interval: u64) {
+        if matches!(self.hasher, Hasher::Md5(_)) {
+            let next = (self.bytes / interval + 1) * interval;
+            self.checkpoints = Some((path, interval, next));
+        }
+    }
+
+    fn save_checkpoint(&mut self) {
+        let (Hasher::Md5(hasher), Some((path, interval, next))) =
+            (&self.hasher, &mut self.checkpoints)
+        else {
+            return;
+        };
+        // Bytes restored from a checkpoint are not in the hasher yet
+        if self.bytes < *next || self.bytes < self.hashed {
+            return;
+        }
+        *next = (self.bytes / *interval + 1) * *interval;
+        let checkpoint = Md5Checkpoint {
+            offset: self.bytes,
+            state: hasher.serialize().to_vec(),
+        };
+        if let Err(e) = checkpoint.write(path) {
+            log::warn!("Cannot save the MD5 state to {}: {}", path.display(), e);
+            self.checkpoints = None;
+        }
+    }
+
+    /// Number of bytes written so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Lowercase hex checksum of everything written so far.
+    fn hex_digest(self) -> (Option<String>, ProgressBar) {
+        let checksum = match self.hasher {
+            Hasher::Md5(hasher) => Some(hex(&hasher.finalize())),
+            Hasher::Sha256(hasher) => Some(hex(&hasher.finalize())),
+            Hasher::None => None,
+        };
+        (checksum, self.progress_bar)
+    }
+
+    /// Compares the checksum to `expected_checksum`, returning it if they match
+    /// (`None` if nothing was hashed). A mismatch is logged along with `source`, the URL or
+    /// file that was hashed.
+    pub fn digest(
+        self,
+        expected_checksum: &str,
+        source: &str,
+    ) -> Result<Option<String>, DownloadError> {
+        let (checksum, progress_bar) = self.hex_digest();
+        let Some(checksum) = checksum else {
+            progress_bar.set_message("Downloaded file, checksum not verified");
+            progress_bar.finish();
+            return Ok(None);
+        };
+        if checksum.eq_ignore_ascii_case(expected_checksum) {
+            progress_bar.set_message("Successfully downloaded file");
+            progress_bar.finish();
+            Ok(Some(checksum))
+        } else {
+            log::warn!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                source,
+                expected_checksum,
+                checksum
+            );
+            progress_bar.set_message(format!(
+                "Error: checksums do not match (expected {}, got {})!",
+                expected_checksum, checksum
+            ));
+            progress_bar.finish();
+            Err(DownloadError::ChecksumMismatch {
+                expected: expected_checksum.to_string(),
+                actual: checksum,
+            })
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{md5_hex, test_dir};
+    use std::io::Read;
+
+    fn checksum_of(algorithm: ChecksumAlgorithm, data: &[u8]) -> String {
+        let mut writer = ChecksumWriter::new(Some(algorithm), ProgressBar::hidden(), None);
+        writer.write_all(data).unwrap();
+        writer.hex_digest().0.unwrap()
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let progress_bar = ProgressBar::hidden();
+        let mut writer =
+            ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), progress_bar.clone(), None);
+        writer.write_all(b"abc").unwrap();
+        let expected = "0123456789abcdef0123456789abcdef";
+        let actual = "900150983cd24fb0d6963f7d28e17f72";
+
+        let error = writer
+            .digest(expected, "http://example.com/chr1.vcf.bgz")
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            DownloadError::ChecksumMismatch { expected: e, actual: a } if e == expected && a == actual
+        ));
+        for message in [error.to_string(), progress_bar.message()] {
+            assert!(
+                message.contains(expected) && message.contains(actual),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_known_vectors() {
+        assert_eq!(
+            checksum_of(ChecksumAlgorithm::Md5, b"abc"),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        assert_eq!(
+            checksum_of(ChecksumAlgorithm::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_detect_algorithm() {
+        assert_eq!(
+            ChecksumAlgorithm::detect("900150983cd24fb0d6963f7d28e17f72"),
+            Some(ChecksumAlgorithm::Md5)
+        );
+        assert_eq!(
+            ChecksumAlgorithm::detect(
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            ),
+            Some(ChecksumAlgorithm::Sha256)
+        );
+        assert!(ChecksumAlgorithm::resolve(None, "abcdef").is_err());
+        assert_eq!(
+            ChecksumAlgorithm::resolve(Some(ChecksumAlgorithm::Sha256), "abcdef").unwrap(),
+            ChecksumAlgorithm::Sha256
+        );
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let expected = md5_hex(&data);
+        let dir = test_dir("md5_checkpoint");
+        let path = dir.join("chr1.vcf.gz.part.md5state");
+
+        // Interrupted after 6500 bytes, the last checkpoint being after the chunk crossing 6000
+        let mut writer =
+            ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), ProgressBar::hidden(), None);
+        writer.save_checkpoints(path.clone(), 1000);
+        for chunk in data[..6500].chunks(700) {
+            writer.write_all(chunk).unwrap();
+        }
+        let checkpoint = Md5Checkpoint::read(&path).unwrap();
+        assert_eq!(checkpoint.offset, 6300);
+
+        // Resumed, the whole download goes through again
+        let mut writer =
+            ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), ProgressBar::hidden(), None);
+        assert!(writer.resume(&checkpoint));
+        writer.save_checkpoints(path.clone(), 1000);
+        for chunk in data.chunks(1024) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.bytes(), data.len() as u64);
+        assert_eq!(writer.hex_digest().0.unwrap(), expected);
+        assert_eq!(Md5Checkpoint::read(&path).unwrap().offset, 10_000);
+
+        // The state is trusted: one of other bytes gives another hash
+        let mut other =
+            ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), ProgressBar::hidden(), None);
+        other.save_checkpoints(path.clone(), 100);
+        other.write_all(&[0; 100]).unwrap();
+        let mut writer =
+            ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), ProgressBar::hidden(), None);
+        assert!(writer.resume(&Md5Checkpoint::read(&path).unwrap()));
+        writer.write_all(&data).unwrap();
+        assert_ne!(writer.hex_digest().0.unwrap(), expected);
+
+        let mut sha256 =
+            ChecksumWriter::new(Some(ChecksumAlgorithm::Sha256), ProgressBar::hidden(), None);
+        assert!(!sha256.resume(&checkpoint));
+        std::fs::write(&path, "100 xyz\n").unwrap();
+        assert_eq!(Md5Checkpoint::read(&path), None);
+    }
+
+    /// Reader returning at most `chunk_size` bytes per read.
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.chunk_size).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_digest_independent_of_chunk_boundaries() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for algorithm in [ChecksumAlgorithm::Md5, ChecksumAlgorithm::Sha256] {
+            let expected = checksum_of(algorithm, &data);
+            for chunk_size in [1, 3, 7, 64, 1000, 4099] {
+                let mut writer = ChecksumWriter::new(Some(algorithm), ProgressBar::hidden(), None);
+                for chunk in data.chunks(chunk_size) {
+                    writer.write_all(chunk).unwrap();
+                }
+                assert_eq!(writer.bytes(), data.len() as u64);
+                assert_eq!(writer.hex_digest().0.unwrap(), expected);
+
+                // As in a download, through a tee with odd-sized reads
+                let mut writer = ChecksumWriter::new(Some(algorithm), ProgressBar::hidden(), None);
+                let reader = ChunkedReader {
+                    data: &data,
+                    chunk_size,
+                };
+                let mut copied = Vec::new();
+                tee::TeeReader::new(reader, &mut writer)
+                    .read_to_end(&mut copied)
+                    .unwrap();
+                assert_eq!(copied, data);
+                assert_eq!(writer.hex_digest().0.unwrap(), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::download::{get_range_from_url, CancellableReader, DownloadOptions};
+use crate::error::DownloadError;
+use crate::throttle::ThrottledReader;
+
+/// Number of chunk files created so far by this process, to give each a unique name.
+static CHUNK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn chunk_path(dir: &Path) -> PathBuf {
+    dir.join(format!(
+        "smart_gnomad_downloader_{}_{}.chunk",
+        std::process::id(),
+        CHUNK_COUNT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Byte ranges (both ends included) splitting `length` bytes into `count` chunks of about the same size.
+fn chunk_ranges(length: u64, count: u64) -> Vec<(u64, u64)> {
+    let count = count.clamp(1, length.max(1));
+    let chunk_length = length.div_ceil(count);
+    (0..length)
+        .step_by(chunk_length.max(1) as usize)
+        .map(|start| (start, (start + chunk_length).min(length) - 1))
+        .collect()
+}
+
+/// Downloads the bytes `start..=end` of `url` into a temporary file in `dir`, returning its path.
+/// The download stops as soon as the chunk is `abandoned`.
+fn download_chunk(
+    url: &str,
+    (start, end): (u64, u64),
+    dir: &Path,
+    options: &DownloadOptions,
+    abandoned: Arc<AtomicBool>,
+) -> Result<PathBuf, DownloadError> {
+    let response = get_range_from_url(url, start, Some(end), options)?;
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::Http {
+            url: url.to_string(),
+            reason: format!(
+                "server responded with {} to the request of bytes {}-{}",
+                response.status(),
+                start,
+                end
+            ),
+        });
+    }
+    let path = chunk_path(dir);
+    let mut file = File::create(&path).map_err(DownloadError::io(format!(
+        "Cannot create chunk file {}",
+        path.display()
+    )))?;
+    let response = CancellableReader::new(response, abandoned.clone());
+    let mut response: Box<dyn Read> = match &options.rate_limit {
+        Some(limit) => Box::new(ThrottledReader::new(response, limit.clone())),
+        None => Box::new(response),
+    };
+    let copied = std::io::copy(&mut response, &mut file);
+    // Nobody will read the chunk anymore
+    if abandoned.load(Ordering::Relaxed) {
+        let _ = std::fs::remove_file(&path);
+        return Err(DownloadError::Cancelled);
+    }
+    let copied = copied.map_err(|e| DownloadError::Http {
+        url: url.to_string(),
+        reason: format!("cannot download bytes {}-{}: {}", start, end, e),
+    })?;
+    if copied != end - start + 1 {
+        let _ = std::fs::remove_file(&path);
+        return Err(DownloadError::Http {
+            url: url.to_string(),
+            reason: format!(
+                "got {} bytes instead of {} for bytes {}-{}",
+                copied,
+                end - start + 1,
+                start,
+                end
+            ),
+        });
+    }
+    Ok(path)
+}
+
+/// Reader of a whole download fetched over several connections: the first chunk is streamed from
+/// the initial response, while the other chunks are downloaded at the same time into temporary
+/// files, which are read back in order once the first chunk is exhausted.
+pub(crate) struct ChunkedReader {
+    current: Box<dyn Read>,
+    current_path: Option<PathBuf>,
+    pending: VecDeque<thread::JoinHandle<Result<PathBuf, DownloadError>>>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl ChunkedReader {
+    /// Splits the `length` bytes of `url` into `connections` chunks, the first one being read
+    /// from `first_response`, a response for the whole file, and the others downloaded to `dir`.
+    pub fn new<R: Read + 'static>(
+        url: &str,
+        first_response: R,
+        dir: PathBuf,
+        length: u64,
+        connections: u64,
+        options: &DownloadOptions,
+    ) -> Self {
+        let ranges = chunk_ranges(length, connections);
+        let options = options.clone();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let pending = ranges[1..]
+            .iter()
+            .map(|&range| {
+                let url = url.to_string();
+                let options = options.clone();
+                let abandoned = abandoned.clone();
+                let dir = dir.clone();
+                thread::spawn(move || download_chunk(&url, range, &dir, &options, abandoned))
+            })
+            .collect();
+        let first_length = ranges.first().map_or(0, |(start, end)| end - start + 1);
+        let first_chunk = first_response.take(first_length);
+        ChunkedReader {
+            current: match &options.rate_limit {
+                Some(limit) => Box::new(ThrottledReader::new(first_chunk, limit.clone())),
+                None => Box::new(first_chunk),
+            },
+            current_path: None,
+            pending,
+            abandoned,
+        }
+    }
+
+    fn remove_current_chunk(&mut self) {
+        if let Some(path) = self.current_path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let Some(next) = self.pending.pop_front() else {
+                return Ok(0);
+            };
+            self.remove_current_chunk();
+            let path = next
+                .join()
+                .map_err(|_| std::io::Error::other("chunk download panicked"))?
+                .map_err(std::io::Error::other)?;
+            self.current = Box::new(File::open(&path)?);
+            self.current_path = Some(path);
+        }
+    }
+}
+
+impl Drop for ChunkedReader {
+    fn drop(&mut self) {
+        self.remove_current_chunk();
+        self.abandoned.store(true, Ordering::Relaxed);
+        // Chunks already downloaded but never read
+        for handle in self.pending.drain(..) {
+            if handle.is_finished() {
+                if let Ok(Ok(path)) = handle.join() {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::throttle::RateLimit;
+
+    #[test]
+    fn test_chunk_ranges() {
+        assert_eq!(chunk_ranges(10, 3), [(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(chunk_ranges(2, 4), [(0, 0), (1, 1)]);
+        assert_eq!(chunk_ranges(10, 1), [(0, 9)]);
+    }
+
+    #[test]
+    fn test_abandoned_chunk() {
+        let (url, _) = crate::test_utils::serve(vec![0; 100_000]);
+        // About 10 seconds to download the chunk
+        let options = DownloadOptions {
+            rate_limit: Some(Arc::new(RateLimit::new(10_000))),
+            ..Default::default()
+        };
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let abandon = abandoned.clone();
+        let started = std::time::Instant::now();
+        thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(100));
+            abandon.store(true, Ordering::Relaxed);
+        });
+        assert!(matches!(
+            download_chunk(
+                &url,
+                (1, 99_999),
+                &std::env::temp_dir(),
+                &options,
+                abandoned
+            ),
+            Err(DownloadError::Cancelled)
+        ));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,3150 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use indicatif::ProgressBar;
+use md5::Digest;
+use noodles::bgzf::io::writer::CompressionLevel;
+use noodles::bgzf::VirtualPosition;
+
+use crate::checksum::{hex, ChecksumAlgorithm, ChecksumWriter, Md5Checkpoint};
+use crate::chunked::ChunkedReader;
+use crate::error::DownloadError;
+use crate::filter::{filter_vcf_lines, parse_vcf_end, LineFilter};
+use crate::index::{IndexFormat, VcfIndexer};
+use crate::intervals::EmptyIntervals;
+use crate::rate::smoothed_style;
+use crate::records::{pad_regions, BedSection, BedSectionIntervals, DroppedIntervals};
+use crate::throttle::{RateLimit, ThrottledReader};
+
+/// Settings shared by every download of a run.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub resume: bool,
+    pub retries: u32,
+    pub retry_base_delay: Duration,
+    pub index: Option<IndexFormat>,
+    pub compression_level: CompressionLevel,
+    pub compress: bool,
+    /// Only count the records falling in the regions, without writing any output
+    pub count_only: bool,
+    /// Also keep the download as is, next to the output (see `raw_output_path`). It is kept
+    /// even if the download fails, to look into what went wrong
+    pub keep_raw: bool,
+    /// Find out which regions no kept record falls in (see `DownloadStats::empty_intervals`).
+    /// Records kept for their span (`LineFilter::sv_aware`) count for every region they overlap
+    pub report_empty_intervals: bool,
+    /// Format of the outputs. BCF is always compressed, and cannot be indexed
+    pub output_format: OutputFormat,
+    pub skip_existing: bool,
+    pub write_md5: bool,
+    /// Algorithm of the expected checksums, guessed from their length when unset
+    pub checksum_algorithm: Option<ChecksumAlgorithm>,
+    /// Whether the download is checked against its expected checksum at all
+    pub verify: bool,
+    pub line_filter: LineFilter,
+    /// Progress of the whole run, advanced by every download
+    pub total_progress: Option<TotalProgress>,
+    /// Cap on the download rate, shared by all the downloads (and connections) running at the
+    /// same time
+    pub rate_limit: Option<Arc<RateLimit>>,
+    /// Extra headers sent with every request (e.g. `Authorization`)
+    pub headers: Vec<(String, String)>,
+    /// Timeout of connecting and of every single read, so that a long but steady download is
+    /// never interrupted. `None` waits forever
+    pub timeout: Option<Duration>,
+    /// Proxy every request goes through. When unset, the `HTTP_PROXY`/`HTTPS_PROXY`
+    /// environment variables are used, if any
+    pub proxy: Option<String>,
+    /// Redirects followed by a request before giving up, so that a redirect loop fails
+    /// instead of hanging
+    pub max_redirects: usize,
+    /// Client shared by every download, so that connections to a host are reused instead of
+    /// being set up again (with their TLS handshake) for each chromosome. When unset, each
+    /// request builds its own client from the options above
+    pub client: Option<Arc<reqwest::blocking::Client>>,
+    /// Set (e.g. on Ctrl-C) to stop the run: downloads in progress fail with
+    /// `DownloadError::Cancelled`, their output being renamed to `.partial`, and queued ones
+    /// are not started
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The first download of `run_downloads` failing with a checksum mismatch or a network
+    /// error sets `cancel`, stopping the others instead of letting them run to the end
+    pub fail_fast: bool,
+    /// Replace outputs that already exist. Otherwise, a download whose output exists fails
+    /// before anything is written, unless it is resumed or skipped
+    pub overwrite: bool,
+    /// Number of connections each download is split over, when the server accepts range
+    /// requests
+    pub connections_per_file: usize,
+    /// Write the output to a `.tmp` file first, only renamed to its final name once the
+    /// download is verified
+    pub verify_before_write: bool,
+    /// Directory of the `.part`, `.md5state`, `.tmp` and `.chunk` files, next to the outputs if
+    /// `None`
+    pub tmp_dir: Option<PathBuf>,
+    /// Number of threads compressing each bgzf output, on top of the threads of `run_downloads`.
+    /// Indexed outputs are always compressed by a single thread, since the index needs the
+    /// position of every record in the compressed stream
+    pub write_threads: usize,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            resume: false,
+            retries: 3,
+            retry_base_delay: Duration::from_secs(1),
+            index: None,
+            compression_level: CompressionLevel::default(),
+            compress: true,
+            count_only: false,
+            keep_raw: false,
+            report_empty_intervals: false,
+            output_format: OutputFormat::default(),
+            skip_existing: false,
+            write_md5: false,
+            checksum_algorithm: None,
+            verify: true,
+            line_filter: LineFilter::default(),
+            total_progress: None,
+            rate_limit: None,
+            headers: Vec::new(),
+            timeout: Some(Duration::from_secs(10)),
+            proxy: None,
+            max_redirects: 10,
+            client: None,
+            cancel: None,
+            fail_fast: false,
+            overwrite: false,
+            connections_per_file: 1,
+            write_threads: 1,
+            verify_before_write: false,
+            tmp_dir: None,
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Extension of the output files: `bcf`, `vcf.gz` or `vcf`.
+    pub fn output_extension(&self) -> &'static str {
+        match self.output_format {
+            OutputFormat::Bcf => "bcf",
+            OutputFormat::Vcf if self.compress => "vcf.gz",
+            OutputFormat::Vcf => "vcf",
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// VCF, as downloaded
+    #[default]
+    Vcf,
+    /// BCF, the records being parsed against the header of the download
+    Bcf,
+}
+
+/// What became of the records of a downloaded VCF.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadStats {
+    /// Data lines of the downloaded VCF
+    pub total_records: u64,
+    /// Malformed data lines skipped, see `filter::BadLinePolicy`
+    pub bad_lines: u64,
+    /// Data lines written to the output, i.e. falling in the regions
+    pub kept_records: u64,
+    /// Size of the raw (bgzf) download, including what was resumed from a part file
+    pub bytes_downloaded: u64,
+    /// Checksum of the raw download, `None` if it was not verified
+    pub actual_checksum: Option<String>,
+    /// With `options.report_empty_intervals`, the regions without any kept record
+    pub empty_intervals: Option<Vec<(u64, u64)>>,
+}
+
+/// Aggregate progress bar of a run, counting the bytes of every download.
+///
+/// Sizes only become known as downloads start: the bar shows a percentage once every download
+/// has started with a known length, and just the downloaded bytes otherwise.
+#[derive(Debug, Clone)]
+pub struct TotalProgress {
+    progress_bar: ProgressBar,
+    not_started: Arc<AtomicUsize>,
+    unknown_length: Arc<AtomicBool>,
+}
+
+impl TotalProgress {
+    pub fn new(progress_bar: ProgressBar, downloads: usize) -> Self {
+        progress_bar.set_length(0);
+        progress_bar.set_style(smoothed_style(
+            "Total: [{elapsed_precise}] {bytes} ({smoothed_rate}) {msg}",
+        ));
+        TotalProgress {
+            progress_bar,
+            not_started: Arc::new(AtomicUsize::new(downloads)),
+            unknown_length: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn progress_bar(&self) -> &ProgressBar {
+        &self.progress_bar
+    }
+
+    /// Accounts for a download starting, of `length` bytes if known.
+    fn add_download(&self, length: Option<u64>) {
+        match length {
+            Some(length) => self.progress_bar.inc_length(length),
+            None => self.unknown_length.store(true, Ordering::SeqCst),
+        }
+        let last = self.not_started.fetch_sub(1, Ordering::SeqCst) == 1;
+        if last && !self.unknown_length.load(Ordering::SeqCst) {
+            self.progress_bar.set_style(
+                smoothed_style(
+                    "Total: [{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({percent}%, {smoothed_rate}) - [Remaining (estimate): {smoothed_eta}] {msg}",
+                )
+                .progress_chars("=>-"),
+            );
+        }
+    }
+
+    pub fn finish(&self) {
+        self.progress_bar.finish_with_message("Done");
+    }
+}
+
+/// Buffer between the bgzf writer and the output file, so that several compressed blocks are
+/// written at once.
+const OUTPUT_BUFFER_SIZE: usize = 1 << 20;
+
+/// Bytes between two saves of the MD5 state of a resumable download, in its `.md5state` file.
+const MD5_CHECKPOINT_INTERVAL: u64 = 64 << 20;
+
+/// Destination of the filtered VCF lines.
+enum VcfWriter {
+    Bgzf(noodles::bgzf::io::Writer<BufWriter<File>>),
+    MultithreadedBgzf(noodles::bgzf::io::MultithreadedWriter<BufWriter<File>>),
+    Plain(BufWriter<File>),
+    Bcf(Box<BcfWriter>),
+}
+
+/// Converts the VCF lines to BCF records. The header lines are collected until the first
+/// record, which every record is then parsed against.
+struct BcfWriter {
+    header_text: String,
+    header: Option<noodles::vcf::Header>,
+    writer: noodles::bcf::io::Writer<noodles::bgzf::io::Writer<BufWriter<File>>>,
+}
+
+impl BcfWriter {
+    /// Parses the collected header lines and writes them, if not done yet.
+    fn header(&mut self) -> std::io::Result<&noodles::vcf::Header> {
+        if self.header.is_none() {
+            let header: noodles::vcf::Header = self.header_text.parse().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid VCF header: {}", e),
+                )
+            })?;
+            self.writer.write_header(&header)?;
+            self.header = Some(header);
+        }
+        Ok(self.header.as_ref().unwrap())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        use noodles::vcf::variant::io::Write;
+
+        if self.header.is_none() && line.starts_with('#') {
+            self.header_text.push_str(line);
+            self.header_text.push('\n');
+            return Ok(());
+        }
+        let record = noodles::vcf::Record::try_from(line.as_bytes())?;
+        self.header()?;
+        let header = self.header.as_ref().unwrap();
+        self.writer
+            .write_variant_record(header, &record)
+            .map_err(|e| {
+                std::io::Error::new(e.kind(), format!("cannot convert record to BCF: {}", e))
+            })
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        // A VCF without any record left is still given its header
+        self.header()?;
+        self.writer.into_inner().finish()?.flush()
+    }
+}
+
+impl VcfWriter {
+    /// Position of the next byte in the bgzf stream, or `None` for uncompressed output and
+    /// for the multithreaded writer, which does not track it.
+    pub fn virtual_position(&self) -> Option<VirtualPosition> {
+        match self {
+            VcfWriter::Bgzf(writer) => Some(writer.virtual_position()),
+            VcfWriter::MultithreadedBgzf(_) | VcfWriter::Plain(_) | VcfWriter::Bcf(_) => None,
+        }
+    }
+
+    /// Writes `line` and its line feed, without going through the formatting machinery of
+    /// `writeln!` and its several small writes.
+    pub fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        fn write_line_to(writer: &mut impl Write, line: &str) -> std::io::Result<()> {
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")
+        }
+        match self {
+            VcfWriter::Bgzf(writer) => write_line_to(writer, line),
+            VcfWriter::MultithreadedBgzf(writer) => write_line_to(writer, line),
+            VcfWriter::Plain(writer) => write_line_to(writer, line),
+            VcfWriter::Bcf(writer) => writer.write_line(line),
+        }
+    }
+
+    /// Flushes everything to disk, including the bgzf EOF block.
+    pub fn finish(self) -> std::io::Result<()> {
+        match self {
+            VcfWriter::Bgzf(writer) => writer.finish()?.flush(),
+            VcfWriter::MultithreadedBgzf(mut writer) => writer.finish()?.flush(),
+            VcfWriter::Plain(mut writer) => writer.flush(),
+            VcfWriter::Bcf(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Where `options.keep_raw` keeps the raw download of the output at `output_path`:
+/// `chr1.vcf.gz` -> `chr1.raw.vcf.gz`.
+pub fn raw_output_path(output_path: &Path) -> PathBuf {
+    let file_name = output_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let stem = [".vcf.gz", ".vcf", ".bcf"]
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(extension))
+        .unwrap_or(&file_name);
+    output_path.with_file_name(format!("{}.raw.vcf.gz", stem))
+}
+
+/// Appends `suffix` to the file name of `path` (`chr1.vcf.gz` -> `chr1.vcf.gz.part`).
+pub fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Path of the temporary file with `suffix` (`.part`, `.tmp`) of `output_path`: in
+/// `options.tmp_dir` if given, next to `output_path` otherwise.
+fn temporary_path(output_path: &Path, suffix: &str, options: &DownloadOptions) -> PathBuf {
+    let path = path_with_suffix(output_path, suffix);
+    match (&options.tmp_dir, path.file_name()) {
+        (Some(tmp_dir), Some(file_name)) => tmp_dir.join(file_name),
+        _ => path,
+    }
+}
+
+/// Directory of the chunks of a download over several connections (`connections_per_file`):
+/// `options.tmp_dir` if given, next to `output_path` otherwise, or in the system temporary
+/// directory without any output file (e.g. when streaming to stdout).
+fn chunk_dir(output_path: Option<&Path>, options: &DownloadOptions) -> PathBuf {
+    match (&options.tmp_dir, output_path) {
+        (Some(tmp_dir), _) => tmp_dir.clone(),
+        (None, Some(output_path)) => output_path
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf),
+        (None, None) => std::env::temp_dir(),
+    }
+}
+
+/// Moves `from` to `to`, copying it then removing `from` when both are not on the same
+/// filesystem, where it cannot be renamed.
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    move_file_with(from, to, |from, to| std::fs::rename(from, to))
+}
+
+fn move_file_with<F>(from: &Path, to: &Path, rename: F) -> std::io::Result<()>
+where
+    F: FnOnce(&Path, &Path) -> std::io::Result<()>,
+{
+    match rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            log::debug!(
+                "Cannot rename {} to {} ({}), copying it",
+                from.display(),
+                to.display(),
+                e
+            );
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Creates `output_dir` if needed and makes sure files can be created in it, so that
+/// a read-only directory is reported before any download starts.
+pub fn prepare_output_dir(output_dir: &Path) -> Result<(), DownloadError> {
+    std::fs::create_dir_all(output_dir).map_err(DownloadError::io(format!(
+        "Cannot create output directory {}",
+        output_dir.display()
+    )))?;
+    let probe = output_dir.join(".smart_gnomad_downloader_write_test");
+    File::create(&probe).map_err(DownloadError::io(format!(
+        "Output directory {} is not writable",
+        output_dir.display()
+    )))?;
+    let _ = std::fs::remove_file(probe);
+    Ok(())
+}
+
+/// Builds an HTTP client honoring the timeout and proxy of `options`.
+pub fn build_client(options: &DownloadOptions) -> Result<reqwest::blocking::Client, DownloadError> {
+    let mut builder = reqwest::blocking::Client::builder().timeout(options.timeout);
+    if let Some(timeout) = options.timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(proxy) = &options.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| DownloadError::Parse(format!("Invalid proxy {}: {}", proxy, e)))?;
+        builder = builder.proxy(proxy);
+    }
+    let max_redirects = options.max_redirects;
+    builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            attempt.error(format!("more than {} redirects", max_redirects))
+        } else {
+            log::debug!(
+                "Redirected from {} to {}",
+                attempt.previous().last().map_or("", |url| url.as_str()),
+                attempt.url()
+            );
+            attempt.follow()
+        }
+    }));
+    builder
+        .build()
+        .map_err(|e| DownloadError::Parse(format!("Cannot set up the HTTP client: {}", e)))
+}
+
+/// The shared client of `options`, or a new one if there is none.
+fn http_client(options: &DownloadOptions) -> Result<Arc<reqwest::blocking::Client>, DownloadError> {
+    match &options.client {
+        Some(client) => Ok(client.clone()),
+        None => build_client(options).map(Arc::new),
+    }
+}
+
+/// Sends a GET request for `url`. A non-zero `offset` asks the server for the bytes
+/// starting at that offset only (HTTP range request).
+///
+/// Timeouts, connection errors and 5xx responses are retried up to `options.retries` times,
+/// waiting `options.retry_base_delay` before the first retry and twice as long after each
+/// subsequent failure. Any other response (including 4xx) is returned as is.
+pub fn get_blocking_reader_from_url(
+    url: &str,
+    offset: u64,
+    options: &DownloadOptions,
+) -> Result<reqwest::blocking::Response, DownloadError> {
+    get_range_from_url(url, offset, None, options)
+}
+
+/// Same as `get_blocking_reader_from_url`, only requesting the bytes from `start` to `end`
+/// (included), or to the end of the file without an `end`.
+pub(crate) fn get_range_from_url(
+    url: &str,
+    start: u64,
+    end: Option<u64>,
+    options: &DownloadOptions,
+) -> Result<reqwest::blocking::Response, DownloadError> {
+    let client = http_client(options)?;
+    let mut attempt = 0;
+    loop {
+        let mut request = client.get(url);
+        for (name, value) in &options.headers {
+            request = request.header(name, value);
+        }
+        if start > 0 || end.is_some() {
+            let end = end.map_or(String::new(), |end| end.to_string());
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-{}", start, end));
+        }
+        let error = match request.send() {
+            Ok(response) if !response.status().is_server_error() => {
+                if response.url().as_str() != url {
+                    log::debug!("Downloading {} from {}", url, response.url());
+                }
+                return Ok(response);
+            }
+            Ok(response) => format!("server responded with {}", response.status()),
+            // Only the source tells what went wrong with the redirect
+            Err(e) if e.is_redirect() => match std::error::Error::source(&e) {
+                Some(source) => format!("{}: {}", e, source),
+                None => e.to_string(),
+            },
+            Err(e) => e.to_string(),
+        };
+        if attempt >= options.retries {
+            return Err(DownloadError::Http {
+                url: url.to_string(),
+                reason: format!("giving up after {} attempts: {}", attempt + 1, error),
+            });
+        }
+        let delay = options.retry_base_delay * 2u32.pow(attempt);
+        log::warn!(
+            "Request to {} failed ({}), retrying in {:.1}s",
+            url,
+            error,
+            delay.as_secs_f64()
+        );
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Algorithm the download is checked with, `None` if verification is disabled.
+fn verification_algorithm(
+    expected_checksum: &str,
+    options: &DownloadOptions,
+) -> Result<Option<ChecksumAlgorithm>, DownloadError> {
+    options
+        .verify
+        .then(|| ChecksumAlgorithm::resolve(options.checksum_algorithm, expected_checksum))
+        .transpose()
+}
+
+/// A raw download stream, with its remaining length (if known) and the offset it starts at.
+type OpenedStream = (Box<dyn Read>, Option<u64>, u64);
+
+/// Local file a `file://` URL or an absolute path points to, `None` for any other URL.
+fn local_path(url: &str) -> Option<PathBuf> {
+    if url.starts_with("file://") {
+        reqwest::Url::parse(url).ok()?.to_file_path().ok()
+    } else {
+        Some(PathBuf::from(url)).filter(|path| path.is_absolute())
+    }
+}
+
+/// Opens the local file at `path` from `offset` (from the start if the file is shorter),
+/// returning it with its remaining length and the offset actually used.
+fn open_local(path: &Path, offset: u64) -> Result<OpenedStream, DownloadError> {
+    let open_error = |source| DownloadError::Io {
+        context: format!("Cannot open {}", path.display()),
+        source,
+    };
+    let mut file = File::open(path).map_err(open_error)?;
+    let length = file.metadata().map_err(open_error)?.len();
+    let offset = if offset <= length { offset } else { 0 };
+    file.seek(SeekFrom::Start(offset)).map_err(open_error)?;
+    Ok((Box::new(file), Some(length - offset), offset))
+}
+
+/// Requests `url` from `offset`, returning the response body with its length (if known) and the
+/// offset actually used, the server possibly sending the whole file instead. The chunks of a
+/// download over several connections are written to `chunk_dir`.
+fn open_http(
+    url: &str,
+    mut offset: u64,
+    chunk_dir: PathBuf,
+    options: &DownloadOptions,
+) -> Result<OpenedStream, DownloadError> {
+    let mut raw_reader = get_blocking_reader_from_url(url, offset, options)?;
+
+    if offset > 0 && raw_reader.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        log::warn!(
+            "Cannot resume download from {}, starting over from the beginning",
+            url
+        );
+        offset = 0;
+        if !raw_reader.status().is_success() {
+            raw_reader = get_blocking_reader_from_url(url, 0, options)?;
+        }
+    }
+
+    if !raw_reader.status().is_success() {
+        return Err(DownloadError::Http {
+            url: url.to_string(),
+            reason: format!("server responded with {}", raw_reader.status()),
+        });
+    }
+
+    let content_length = raw_reader.content_length();
+    let accepts_ranges = raw_reader
+        .headers()
+        .get(reqwest::header::ACCEPT_RANGES)
+        .is_some_and(|value| value == "bytes");
+    let raw_reader: Box<dyn Read> = match (content_length, &options.rate_limit) {
+        // An empty file has no chunk to split, and is left to `check_bgzf_magic`
+        (Some(length), _)
+            if length > 0 && offset == 0 && options.connections_per_file > 1 && accepts_ranges =>
+        {
+            Box::new(ChunkedReader::new(
+                url,
+                raw_reader,
+                chunk_dir,
+                length,
+                options.connections_per_file as u64,
+                options,
+            ))
+        }
+        (_, Some(limit)) => Box::new(ThrottledReader::new(raw_reader, limit.clone())),
+        (_, None) => Box::new(raw_reader),
+    };
+    Ok((raw_reader, content_length, offset))
+}
+
+/// Length of a bgzf block header, up to its `BC` extra subfield.
+const BGZF_HEADER_SIZE: usize = 16;
+
+/// Whether `head` starts like a bgzf block: a gzip member with the `FEXTRA` flag whose first
+/// extra subfield is `BC`.
+fn is_bgzf_header(head: &[u8]) -> bool {
+    head.len() >= BGZF_HEADER_SIZE
+        && head[..4] == [0x1f, 0x8b, 0x08, 0x04]
+        && head[12..14] == *b"BC"
+        && head[14..16] == [0x02, 0x00]
+}
+
+/// Peeks at the first bytes of the download of `url`, failing unless they are those of a bgzf
+/// file, rather than whatever error or garbage decoding it would give. Returns the whole stream.
+fn check_bgzf_magic(url: &str, mut reader: Box<dyn Read>) -> Result<Box<dyn Read>, DownloadError> {
+    let mut head = Vec::with_capacity(BGZF_HEADER_SIZE);
+    (&mut reader)
+        .take(BGZF_HEADER_SIZE as u64)
+        .read_to_end(&mut head)
+        .map_err(DownloadError::io(format!("Cannot read {}", url)))?;
+    // An empty download is left to the checksum verification
+    if !head.is_empty() && !is_bgzf_header(&head) {
+        let hint = if head.starts_with(&[0x1f, 0x8b]) {
+            ", but plain gzip: recompress it with bgzip"
+        } else if head.trim_ascii_start().starts_with(b"<") {
+            ", but what looks like an HTML page"
+        } else {
+            ""
+        };
+        return Err(DownloadError::Parse(format!(
+            "{} is not a bgzf file{}",
+            url, hint
+        )));
+    }
+    Ok(Box::new(std::io::Cursor::new(head).chain(reader)))
+}
+
+/// Reads the `.md5state` checkpoint of `part_path`, if it only covers bytes of the part file.
+fn usable_checkpoint(part_path: &Path, md5state_path: &Path) -> Option<Md5Checkpoint> {
+    let checkpoint = Md5Checkpoint::read(md5state_path)?;
+    let part_length = std::fs::metadata(part_path).map_or(0, |m| m.len());
+    if checkpoint.offset > part_length {
+        log::warn!(
+            "{} is beyond the end of {}, hashing it again",
+            md5state_path.display(),
+            part_path.display()
+        );
+        return None;
+    }
+    Some(checkpoint)
+}
+
+/// Starts downloading `url`, returning the raw (bgzf) stream and sizing `progress_bar` after it.
+/// `file://` URLs and absolute paths are read from the local filesystem instead.
+///
+/// With a `part_path`, the raw download is kept in that file and resumed from it if it exists.
+/// Temporary files are written next to `output_path` (see `chunk_dir`).
+fn open_download(
+    url: &str,
+    part_path: Option<&Path>,
+    output_path: Option<&Path>,
+    progress_bar: &ProgressBar,
+    options: &DownloadOptions,
+) -> Result<Box<dyn Read>, DownloadError> {
+    log::info!("Downloading file from {}", url);
+
+    let offset = part_path.map_or(0, |part_path| {
+        std::fs::metadata(part_path).map(|m| m.len()).unwrap_or(0)
+    });
+    let (raw_reader, content_length, offset) = match local_path(url) {
+        Some(path) => open_local(&path, offset)?,
+        None => open_http(url, offset, chunk_dir(output_path, options), options)?,
+    };
+    // A resumed download starts in the middle of a block
+    let raw_reader = if offset == 0 {
+        check_bgzf_magic(url, raw_reader)?
+    } else {
+        raw_reader
+    };
+
+    if let Some(total_progress) = &options.total_progress {
+        total_progress.add_download(content_length.map(|len| offset + len));
+    }
+    if let Some(len) = content_length {
+        progress_bar.set_length(offset + len);
+        progress_bar.set_style(
+            smoothed_style(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7}({percent}%, {smoothed_rate}) - [Remaining (estimate): {smoothed_eta}] {msg}",
+            )
+            .progress_chars("=>-"),
+        );
+    } else {
+        // Without a length (chunked transfer), there is no way to tell how much is left
+        progress_bar.set_style(smoothed_style(
+            "{spinner} [{elapsed_precise}] {bytes} ({smoothed_rate}) {msg}",
+        ));
+    }
+
+    // In resume mode, the bytes already on disk are read back first (so that they are hashed
+    // and filtered again), followed by the rest of the stream, which is appended to the part file.
+    let reader: Box<dyn Read> = if let Some(part_path) = part_path {
+        let part_file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(offset > 0)
+            .write(true)
+            .truncate(offset == 0)
+            .open(part_path)
+            .map_err(DownloadError::io(format!(
+                "Cannot create part file {}",
+                part_path.display()
+            )))?;
+        let downloaded = File::open(part_path)
+            .map_err(DownloadError::io(format!(
+                "Cannot open part file {}",
+                part_path.display()
+            )))?
+            .take(offset);
+        Box::new(downloaded.chain(tee::TeeReader::new(raw_reader, part_file)))
+    } else {
+        raw_reader
+    };
+    Ok(match &options.cancel {
+        Some(cancel) => Box::new(CancellableReader::new(reader, cancel.clone())),
+        None => reader,
+    })
+}
+
+/// Reader failing as soon as `cancel` is set, which stops the download reading from it.
+pub(crate) struct CancellableReader<R> {
+    inner: R,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<R> CancellableReader<R> {
+    pub fn new(inner: R, cancel: Arc<AtomicBool>) -> Self {
+        CancellableReader { inner, cancel }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("download cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+fn is_cancelled(options: &DownloadOptions) -> bool {
+    options
+        .cancel
+        .as_ref()
+        .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+/// Starts a thread setting `cancel` once `max_runtime` has elapsed, which stops the downloads
+/// as Ctrl-C does. The returned flag tells whether it fired.
+pub fn start_watchdog(max_runtime: Duration, cancel: Arc<AtomicBool>) -> Arc<AtomicBool> {
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let fired = timed_out.clone();
+    thread::spawn(move || {
+        thread::sleep(max_runtime);
+        if !cancel.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Maximum runtime of {}s reached, stopping the downloads",
+                max_runtime.as_secs_f64()
+            );
+            fired.store(true, Ordering::Relaxed);
+        }
+    });
+    timed_out
+}
+
+/// Error of a download that failed while writing `output_path`. If this is because the run was
+/// cancelled, the incomplete output is moved to a `.partial` file, so that it cannot be mistaken
+/// for a complete VCF.
+fn output_failed(
+    output_path: &Path,
+    error: DownloadError,
+    options: &DownloadOptions,
+) -> DownloadError {
+    if !is_cancelled(options) {
+        return error;
+    }
+    let partial_path = path_with_suffix(output_path, ".partial");
+    match std::fs::rename(output_path, &partial_path) {
+        Ok(()) => log::warn!(
+            "Interrupted: moved the incomplete {} to {}",
+            output_path.display(),
+            partial_path.display()
+        ),
+        Err(e) => log::warn!(
+            "Interrupted: cannot move the incomplete {} out of the way: {}",
+            output_path.display(),
+            e
+        ),
+    }
+    DownloadError::Cancelled
+}
+
+/// Path of the marker recording that `output_path` comes from a verified download.
+pub(crate) fn verified_marker_path(output_path: &Path) -> PathBuf {
+    path_with_suffix(output_path, ".verified")
+}
+
+fn file_md5(path: &Path) -> std::io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = md5::Md5::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(hex(&hasher.finalize()));
+        }
+        hasher.update(buf);
+        let length = buf.len();
+        reader.consume(length);
+    }
+}
+
+fn regions_md5<I: Iterator<Item = (u64, u64)>>(regions: I) -> String {
+    let mut hasher = md5::Md5::new();
+    for (start, end) in regions {
+        hasher.update(format!("{}-{},", start, end));
+    }
+    hex(&hasher.finalize())
+}
+
+/// Checksum of the settings of `line_filter` (AF thresholds, kept INFO keys and samples...),
+/// which change the output as much as the regions do.
+fn line_filter_md5(line_filter: &LineFilter) -> String {
+    hex(&md5::Md5::digest(format!("{:?}", line_filter)))
+}
+
+/// Contents of the verified marker: the checksums of the download, of the regions and of the
+/// line filter it was filtered with, and the checksum of the resulting output file.
+fn verified_marker(
+    source_checksum: &str,
+    regions_md5: &str,
+    filter_md5: &str,
+    output_md5: &str,
+) -> String {
+    format!(
+        "source_checksum\t{}\nregions_md5\t{}\nfilter_md5\t{}\noutput_md5\t{}\n",
+        source_checksum, regions_md5, filter_md5, output_md5
+    )
+}
+
+/// Whether `output_path` was already produced from a download matching `expected_checksum`,
+/// filtered with the same regions and line filter, and has not been modified since.
+fn is_already_downloaded(
+    output_path: &Path,
+    expected_checksum: &str,
+    regions_md5: &str,
+    filter_md5: &str,
+) -> bool {
+    let Ok(marker) = std::fs::read_to_string(verified_marker_path(output_path)) else {
+        return false;
+    };
+    file_md5(output_path).is_ok_and(|output_md5| {
+        marker == verified_marker(expected_checksum, regions_md5, filter_md5, &output_md5)
+    })
+}
+
+/// Downloads the bgzf VCF at `url` and writes to `output_path` its header and the records falling
+/// in `regions` (see `filter::filter_vcf_stream`), then checks the checksum of the whole download.
+///
+/// Every successful download leaves a `.verified` marker next to its output, so that
+/// `options.skip_existing` can tell, on a later run, that the output is complete.
+/// With `options.write_md5`, the checksum of the output is also written to a `.md5` file
+/// readable by `md5sum -c`.
+///
+/// Without `options.verify`, the download is not hashed and `expected_checksum` is ignored:
+/// nothing guarantees that the output is complete or uncorrupted, so no marker is written either.
+///
+/// With `options.verify_before_write`, a download that fails its checksum leaves a `.tmp` file
+/// behind instead of its output (or its index).
+///
+/// The returned counts are all zero when the download is skipped.
+///
+/// With `options.count_only`, nothing is written at all, the records being only counted.
+pub fn smart_save_vcf_from_url<I>(
+    url: &str,
+    expected_checksum: &str,
+    regions: I,
+    output_path: &Path,
+    progress_bar: ProgressBar,
+    options: &DownloadOptions,
+) -> Result<DownloadStats, DownloadError>
+where
+    I: Iterator<Item = (u64, u64)> + Clone,
+{
+    if options.count_only {
+        return count_vcf_records_from_url(url, expected_checksum, regions, progress_bar, options);
+    }
+    let algorithm = verification_algorithm(expected_checksum, options)?;
+    let regions_md5 = regions_md5(regions.clone());
+    let filter_md5 = line_filter_md5(&options.line_filter);
+    let marker_path = verified_marker_path(output_path);
+    if options.skip_existing
+        && is_already_downloaded(output_path, expected_checksum, &regions_md5, &filter_md5)
+    {
+        log::info!(
+            "{} already exists and matches {}, skipping download",
+            output_path.display(),
+            url
+        );
+        progress_bar.finish_with_message("Already downloaded");
+        if let Some(total_progress) = &options.total_progress {
+            total_progress.add_download(Some(0));
+        }
+        return Ok(DownloadStats {
+            actual_checksum: Some(expected_checksum.to_string()),
+            ..Default::default()
+        });
+    }
+    let part_path = temporary_path(output_path, ".part", options);
+    let part_path = options.resume.then_some(part_path.as_path());
+    // The output of a resumed download is the incomplete result of the same download
+    let resumed = part_path.is_some_and(Path::exists);
+    if !options.overwrite && !resumed && output_path.exists() {
+        return Err(DownloadError::Io {
+            context: format!(
+                "Not overwriting {}, use --overwrite to replace it",
+                output_path.display()
+            ),
+            source: std::io::ErrorKind::AlreadyExists.into(),
+        });
+    }
+
+    // The output is about to be overwritten, a previous marker or sidecar would not describe it anymore
+    let _ = std::fs::remove_file(&marker_path);
+    let md5_path = path_with_suffix(output_path, ".md5");
+    let _ = std::fs::remove_file(&md5_path);
+    // MD5 state of the part file, which spares hashing it again when resuming
+    let md5state_path = temporary_path(output_path, ".md5state", options);
+    let checkpoint = match part_path {
+        Some(part_path) if resumed => usable_checkpoint(part_path, &md5state_path),
+        _ => None,
+    };
+    if checkpoint.is_none() {
+        let _ = std::fs::remove_file(&md5state_path);
+    }
+    let raw_reader = open_download(url, part_path, Some(output_path), &progress_bar, options)?;
+    let raw_path = raw_output_path(output_path);
+    let raw_error = |source| DownloadError::Io {
+        context: format!("Cannot write raw download {}", raw_path.display()),
+        source,
+    };
+    let mut raw_file = match options.keep_raw {
+        true => Some(BufWriter::new(File::create(&raw_path).map_err(raw_error)?)),
+        false => None,
+    };
+    let raw_reader: Box<dyn Read + '_> = match raw_file.as_mut() {
+        Some(raw_file) => Box::new(tee::TeeReader::new(raw_reader, raw_file)),
+        None => raw_reader,
+    };
+    let mut checksum_writer = ChecksumWriter::new(
+        algorithm,
+        progress_bar,
+        options
+            .total_progress
+            .as_ref()
+            .map(|total_progress| total_progress.progress_bar.clone()),
+    );
+    if part_path.is_some() {
+        if let Some(checkpoint) = &checkpoint {
+            if !checksum_writer.resume(checkpoint) {
+                let _ = std::fs::remove_file(&md5state_path);
+            }
+        }
+        // The part file is written before the checksum writer sees its bytes, so a checkpoint
+        // never covers more than the part file
+        checksum_writer.save_checkpoints(md5state_path.clone(), MD5_CHECKPOINT_INTERVAL);
+    }
+    let actual_reader = tee::TeeReader::new(raw_reader, &mut checksum_writer);
+    let bg_reader = noodles::bgzf::Reader::new(actual_reader);
+
+    let tmp_path = temporary_path(output_path, ".tmp", options);
+    let write_path = if options.verify_before_write {
+        tmp_path.as_path()
+    } else {
+        output_path
+    };
+    let vcf_file = std::fs::File::create(write_path).map_err(DownloadError::io(format!(
+        "Cannot create output file {}",
+        write_path.display()
+    )))?;
+
+    let vcf_file = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, vcf_file);
+    let mut vcf_file_writer = match NonZeroUsize::new(options.write_threads) {
+        _ if options.output_format == OutputFormat::Bcf => VcfWriter::Bcf(Box::new(BcfWriter {
+            header_text: String::new(),
+            header: None,
+            writer: noodles::bcf::io::Writer::from(
+                noodles::bgzf::io::writer::Builder::default()
+                    .set_compression_level(options.compression_level)
+                    .build_from_writer(vcf_file),
+            ),
+        })),
+        _ if !options.compress => VcfWriter::Plain(vcf_file),
+        Some(write_threads) if write_threads.get() > 1 && options.index.is_none() => {
+            VcfWriter::MultithreadedBgzf(
+                noodles::bgzf::io::multithreaded_writer::Builder::default()
+                    .set_compression_level(options.compression_level)
+                    .set_worker_count(write_threads)
+                    .build_from_writer(vcf_file),
+            )
+        }
+        _ => VcfWriter::Bgzf(
+            noodles::bgzf::io::writer::Builder::default()
+                .set_compression_level(options.compression_level)
+                .build_from_writer(vcf_file),
+        ),
+    };
+
+    let mut indexer = options.index.map(VcfIndexer::new);
+
+    let write_error = |source| DownloadError::Io {
+        context: format!("Cannot write to {}", write_path.display()),
+        source,
+    };
+
+    let mut kept_records = 0;
+    let mut empty_intervals = options
+        .report_empty_intervals
+        .then(|| EmptyIntervals::new(regions.clone()));
+    let counts = filter_vcf_lines(bg_reader, regions, &options.line_filter, |line, pos| {
+        kept_records += u64::from(pos.is_some());
+        record_kept_span(empty_intervals.as_mut(), line, pos, options);
+        let start = vcf_file_writer.virtual_position();
+        vcf_file_writer.write_line(line).map_err(write_error)?;
+        if let (Some(indexer), Some(pos), Some(start), Some(end)) = (
+            indexer.as_mut(),
+            pos,
+            start,
+            vcf_file_writer.virtual_position(),
+        ) {
+            indexer.add_line(line, pos, start, end)?;
+        }
+        Ok(())
+    });
+    let counts = match counts {
+        Ok(counts) => counts,
+        Err(e) => {
+            drop(vcf_file_writer);
+            return Err(output_failed(write_path, e, options));
+        }
+    };
+    vcf_file_writer.finish().map_err(write_error)?;
+    if let Some(mut raw_file) = raw_file {
+        raw_file.flush().map_err(raw_error)?;
+    }
+    let bytes_downloaded = checksum_writer.bytes();
+    let success = checksum_writer.digest(expected_checksum, url);
+    if let Some(part_path) = part_path {
+        // Once the whole stream went through, the part file is either complete or corrupted:
+        // it is of no use for a later resume in both cases.
+        let _ = std::fs::remove_file(part_path);
+        let _ = std::fs::remove_file(&md5state_path);
+    }
+    if options.verify_before_write {
+        if success.is_ok() {
+            move_file(&tmp_path, output_path).map_err(DownloadError::io(format!(
+                "Cannot move {} to {}",
+                tmp_path.display(),
+                output_path.display()
+            )))?;
+        } else {
+            log::warn!(
+                "{} failed verification, its output is left in {}",
+                url,
+                tmp_path.display()
+            );
+        }
+    }
+    if let Some(indexer) = indexer.filter(|_| success.is_ok() || !options.verify_before_write) {
+        indexer.write(output_path)?;
+    }
+    let stats = DownloadStats {
+        total_records: counts.records,
+        bad_lines: counts.bad_lines,
+        kept_records,
+        bytes_downloaded,
+        actual_checksum: success?,
+        empty_intervals: empty_intervals.map(EmptyIntervals::finish),
+    };
+
+    if !options.verify && !options.write_md5 {
+        return Ok(stats);
+    }
+    let output_md5 = file_md5(output_path).map_err(DownloadError::io(format!(
+        "Cannot read back {}",
+        output_path.display()
+    )))?;
+    if options.write_md5 {
+        let file_name = output_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        std::fs::write(&md5_path, format!("{}  {}\n", output_md5, file_name)).map_err(
+            DownloadError::io(format!("Cannot write {}", md5_path.display())),
+        )?;
+    }
+    if !options.verify {
+        return Ok(stats);
+    }
+    std::fs::write(
+        &marker_path,
+        verified_marker(expected_checksum, &regions_md5, &filter_md5, &output_md5),
+    )
+    .map_err(DownloadError::io(format!(
+        "Cannot write {}",
+        marker_path.display()
+    )))?;
+    Ok(stats)
+}
+
+/// Downloads the bgzf VCF at `url` and writes to `writer`, uncompressed, the records falling in
+/// `regions`, preceded by the header if `with_header` is set, then checks the checksum of the
+/// whole download. Chromosomes can thus be streamed one after the other to a single writer.
+///
+/// Nothing is written to disk, so resuming, indexing and the sidecar files are not available.
+pub fn stream_vcf_from_url<I, W>(
+    url: &str,
+    expected_checksum: &str,
+    regions: I,
+    mut writer: W,
+    with_header: bool,
+    progress_bar: ProgressBar,
+    options: &DownloadOptions,
+) -> Result<DownloadStats, DownloadError>
+where
+    I: Iterator<Item = (u64, u64)> + Clone,
+    W: Write,
+{
+    let stats = filter_download(
+        url,
+        expected_checksum,
+        regions,
+        progress_bar,
+        options,
+        |line, pos| {
+            if pos.is_none() && !with_header {
+                return Ok(());
+            }
+            writeln!(writer, "{}", line).map_err(|source| DownloadError::Io {
+                context: "Cannot write VCF stream".to_string(),
+                source,
+            })
+        },
+    );
+    writer
+        .flush()
+        .map_err(DownloadError::io("Cannot write VCF stream"))?;
+    stats
+}
+
+/// Downloads the bgzf VCF at `url` only to count the records falling in `regions`, checking
+/// the checksum of the whole download. This is what `smart_save_vcf_from_url` does with
+/// `options.count_only`.
+pub fn count_vcf_records_from_url<I>(
+    url: &str,
+    expected_checksum: &str,
+    regions: I,
+    progress_bar: ProgressBar,
+    options: &DownloadOptions,
+) -> Result<DownloadStats, DownloadError>
+where
+    I: Iterator<Item = (u64, u64)> + Clone,
+{
+    filter_download(
+        url,
+        expected_checksum,
+        regions,
+        progress_bar,
+        options,
+        |_, _| Ok(()),
+    )
+}
+
+/// Downloads the bgzf VCF at `url`, passing to `keep` the lines `filter_vcf_lines` keeps, then
+/// checks the checksum of the whole download.
+fn filter_download<I, F>(
+    url: &str,
+    expected_checksum: &str,
+    regions: I,
+    progress_bar: ProgressBar,
+    options: &DownloadOptions,
+    mut keep: F,
+) -> Result<DownloadStats, DownloadError>
+where
+    I: Iterator<Item = (u64, u64)> + Clone,
+    F: FnMut(&str, Option<u64>) -> Result<(), DownloadError>,
+{
+    let algorithm = verification_algorithm(expected_checksum, options)?;
+    let raw_reader = open_download(url, None, None, &progress_bar, options)?;
+    let mut checksum_writer = ChecksumWriter::new(
+        algorithm,
+        progress_bar,
+        options
+            .total_progress
+            .as_ref()
+            .map(|total_progress| total_progress.progress_bar.clone()),
+    );
+    let actual_reader = tee::TeeReader::new(raw_reader, &mut checksum_writer);
+
+    let mut kept_records = 0;
+    let mut empty_intervals = options
+        .report_empty_intervals
+        .then(|| EmptyIntervals::new(regions.clone()));
+    let counts = filter_vcf_lines(
+        noodles::bgzf::Reader::new(actual_reader),
+        regions,
+        &options.line_filter,
+        |line, pos| {
+            kept_records += u64::from(pos.is_some());
+            record_kept_span(empty_intervals.as_mut(), line, pos, options);
+            keep(line, pos)
+        },
+    )?;
+    let bytes_downloaded = checksum_writer.bytes();
+    Ok(DownloadStats {
+        total_records: counts.records,
+        bad_lines: counts.bad_lines,
+        kept_records,
+        bytes_downloaded,
+        actual_checksum: checksum_writer.digest(expected_checksum, url)?,
+        empty_intervals: empty_intervals.map(EmptyIntervals::finish),
+    })
+}
+
+/// Marks the regions a kept data line (`pos` being `None` for header lines) falls in as not
+/// empty, or that it overlaps with `options.line_filter.sv_aware`.
+fn record_kept_span<I>(
+    empty_intervals: Option<&mut EmptyIntervals<I, u64>>,
+    line: &str,
+    pos: Option<u64>,
+    options: &DownloadOptions,
+) where
+    I: Iterator<Item = (u64, u64)>,
+{
+    if let (Some(empty_intervals), Some(pos)) = (empty_intervals, pos) {
+        let end = if options.line_filter.sv_aware {
+            parse_vcf_end(line, pos)
+        } else {
+            pos
+        };
+        empty_intervals.record(pos, end.saturating_add(1));
+    }
+}
+
+/// The regions of a `DownloadJob`, as VCF intervals.
+#[derive(Debug, Clone)]
+pub enum JobRegions {
+    /// Sorted and merged intervals, held in memory
+    Loaded(Vec<(u64, u64)>),
+    /// Intervals read from the BED file as the download goes (`--stream-bed`)
+    Streamed(BedSection),
+}
+
+impl From<Vec<(u64, u64)>> for JobRegions {
+    fn from(regions: Vec<(u64, u64)>) -> Self {
+        JobRegions::Loaded(regions)
+    }
+}
+
+impl JobRegions {
+    /// Same regions, widened by `padding` bases as by `pad_regions`.
+    pub fn padded(self, padding: u64) -> Self {
+        match self {
+            JobRegions::Loaded(regions) => JobRegions::Loaded(pad_regions(regions, padding)),
+            JobRegions::Streamed(section) => JobRegions::Streamed(section.padded(padding)),
+        }
+    }
+
+    /// Iterates over the intervals. Cloning the iterator does not copy the intervals.
+    pub fn iter(&self) -> JobRegionsIter<'_> {
+        match self {
+            JobRegions::Loaded(regions) => JobRegionsIter::Loaded(regions.iter().copied()),
+            JobRegions::Streamed(section) => {
+                JobRegionsIter::Streamed(Box::new(section.intervals()))
+            }
+        }
+    }
+}
+
+/// Iterator over the intervals of `JobRegions`.
+#[derive(Debug, Clone)]
+pub enum JobRegionsIter<'a> {
+    Loaded(std::iter::Copied<std::slice::Iter<'a, (u64, u64)>>),
+    Streamed(Box<BedSectionIntervals>),
+}
+
+impl Iterator for JobRegionsIter<'_> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        match self {
+            JobRegionsIter::Loaded(intervals) => intervals.next(),
+            JobRegionsIter::Streamed(intervals) => intervals.next(),
+        }
+    }
+}
+
+/// A chromosome waiting for one of the worker threads of `run_downloads`.
+pub struct DownloadJob {
+    pub chrom_name: String,
+    pub url: String,
+    pub expected_checksum: String,
+    pub regions: JobRegions,
+    pub output_path: PathBuf,
+    pub progress_bar: ProgressBar,
+}
+
+/// What became of a `DownloadJob`.
+#[derive(Debug)]
+pub struct DownloadOutcome {
+    pub chrom_name: String,
+    pub url: String,
+    pub expected_checksum: String,
+    /// Time spent downloading and filtering, without the time waiting in the queue
+    pub elapsed: Duration,
+    pub result: Result<DownloadStats, DownloadError>,
+}
+
+/// Kind of outcome of a download, as reported to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutcomeStatus {
+    Success,
+    /// The whole file was downloaded, but it is not the expected one
+    ChecksumMismatch,
+    /// The file could not be fetched, or the connection broke during the download
+    NetworkError,
+    Cancelled,
+    /// Anything else, e.g. a malformed VCF or an output that cannot be written
+    Failed,
+}
+
+impl fmt::Display for OutcomeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutcomeStatus::Success => "success",
+            OutcomeStatus::ChecksumMismatch => "checksum mismatch",
+            OutcomeStatus::NetworkError => "network error",
+            OutcomeStatus::Cancelled => "cancelled",
+            OutcomeStatus::Failed => "failed",
+        })
+    }
+}
+
+impl DownloadOutcome {
+    pub fn status(&self) -> OutcomeStatus {
+        match &self.result {
+            Ok(_) => OutcomeStatus::Success,
+            Err(DownloadError::ChecksumMismatch { .. }) => OutcomeStatus::ChecksumMismatch,
+            Err(DownloadError::Http { .. }) => OutcomeStatus::NetworkError,
+            Err(DownloadError::Cancelled) => OutcomeStatus::Cancelled,
+            Err(_) => OutcomeStatus::Failed,
+        }
+    }
+}
+
+/// Downloads every job using at most `threads` worker threads, the remaining jobs waiting in a queue.
+/// Returns the outcome of each chromosome, in completion order.
+pub fn run_downloads(
+    jobs: Vec<DownloadJob>,
+    threads: usize,
+    options: &DownloadOptions,
+) -> Vec<DownloadOutcome> {
+    let worker_count = threads.min(jobs.len());
+    let mut options = options.clone();
+    if options.fail_fast && options.cancel.is_none() {
+        options.cancel = Some(Arc::new(AtomicBool::new(false)));
+    }
+
+    let (job_sender, job_receiver) = mpsc::channel::<DownloadJob>();
+    for job in jobs {
+        job_sender.send(job).expect("Job queue is closed");
+    }
+    drop(job_sender);
+
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    let (result_sender, result_receiver) = mpsc::channel();
+
+    let workers: Vec<thread::JoinHandle<()>> = (0..worker_count)
+        .map(|_| {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            let options = options.clone();
+            thread::spawn(move || loop {
+                let job = job_receiver.lock().expect("Job queue is poisoned").recv();
+                let Ok(job) = job else {
+                    break;
+                };
+                let start = Instant::now();
+                let result = if is_cancelled(&options) {
+                    job.progress_bar.finish_with_message("Cancelled");
+                    Err(DownloadError::Cancelled)
+                } else {
+                    job.progress_bar.set_message("Downloading");
+                    catch_panic(|| {
+                        smart_save_vcf_from_url(
+                            &job.url,
+                            &job.expected_checksum,
+                            job.regions.iter(),
+                            &job.output_path,
+                            job.progress_bar,
+                            &options,
+                        )
+                    })
+                };
+                let outcome = DownloadOutcome {
+                    chrom_name: job.chrom_name,
+                    url: job.url,
+                    expected_checksum: job.expected_checksum,
+                    elapsed: start.elapsed(),
+                    result,
+                };
+                if options.fail_fast
+                    && matches!(
+                        outcome.status(),
+                        OutcomeStatus::ChecksumMismatch | OutcomeStatus::NetworkError
+                    )
+                {
+                    let cancel = options.cancel.as_ref().expect("Set for --fail-fast");
+                    if !cancel.swap(true, Ordering::Relaxed) {
+                        log::warn!(
+                            "Stopping the other downloads, as {} failed ({})",
+                            outcome.chrom_name,
+                            outcome.status()
+                        );
+                    }
+                }
+                let _ = result_sender.send(outcome);
+            })
+        })
+        .collect();
+    drop(result_sender);
+
+    let results = result_receiver.iter().collect();
+    for (threadid, worker) in workers.into_iter().enumerate() {
+        if worker.join().is_err() {
+            log::error!("Worker thread {} panicked", threadid);
+        }
+    }
+    results
+}
+
+/// Runs a download, turning a panic into a `DownloadError::Panicked` so that the worker thread
+/// goes on with the next jobs.
+fn catch_panic<F>(download: F) -> Result<DownloadStats, DownloadError>
+where
+    F: FnOnce() -> Result<DownloadStats, DownloadError>,
+{
+    // Nothing shared with other downloads is left half-updated by a panicking one
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(download)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(DownloadError::Panicked(message))
+    })
+}
+
+/// Writes one tab-separated line per job: chromosome, number of intervals, number of bases
+/// covered by the intervals and URL.
+pub fn write_download_plan<W: Write>(mut writer: W, jobs: &[DownloadJob]) -> std::io::Result<()> {
+    writeln!(writer, "chromosome\tintervals\tspan_bp\turl")?;
+    for job in jobs {
+        let (intervals, span) = job
+            .regions
+            .iter()
+            .fold((0, 0), |(intervals, span), (start, end)| {
+                (intervals + 1, span + end - start)
+            });
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}",
+            job.chrom_name, intervals, span, job.url
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes, as a tab-separated table, how many records of each chromosome were kept, how long
+/// its download and filtering took (see `DownloadOutcome::elapsed`) and how many of its BED
+/// intervals were `dropped` as too short (by `DownloadOutcome::chrom_name`), followed by the
+/// totals over all chromosomes. The total of dropped intervals also counts the chromosomes
+/// without a row, e.g. left without any download once their intervals were all dropped.
+pub fn write_download_summary<W: Write>(
+    mut writer: W,
+    stats: &[(String, DownloadStats, Duration)],
+    dropped: &DroppedIntervals,
+) -> std::io::Result<()> {
+    writeln!(
+        writer,
+        "chromosome\ttotal_records\tkept_records\tbytes_downloaded\telapsed_seconds\tdropped_intervals"
+    )?;
+    let mut total = DownloadStats::default();
+    let mut total_elapsed = Duration::ZERO;
+    for (chrom_name, chrom_stats, elapsed) in stats {
+        let chrom_dropped = dropped.get(chrom_name).copied().unwrap_or(0);
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{:.3}\t{}",
+            chrom_name,
+            chrom_stats.total_records,
+            chrom_stats.kept_records,
+            chrom_stats.bytes_downloaded,
+            elapsed.as_secs_f64(),
+            chrom_dropped
+        )?;
+        total.total_records += chrom_stats.total_records;
+        total.kept_records += chrom_stats.kept_records;
+        total.bytes_downloaded += chrom_stats.bytes_downloaded;
+        total_elapsed += *elapsed;
+    }
+    writeln!(
+        writer,
+        "total\t{}\t{}\t{}\t{:.3}\t{}",
+        total.total_records,
+        total.kept_records,
+        total.bytes_downloaded,
+        total_elapsed.as_secs_f64(),
+        dropped.values().sum::<u64>()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::records::{index_sorted_bed, read_bed_regions_with, BedReadOptions};
+    use crate::test_utils::*;
+    use sha2::Digest;
+    use std::collections::HashMap;
+    use std::io::BufRead;
+
+    #[test]
+    fn test_resume_from_truncated_part_file() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, requests) = serve(body.clone());
+
+        let dir = test_dir("resume");
+        let output = dir.join("chr1.vcf.gz");
+        std::fs::write(dir.join("chr1.vcf.gz.part"), &body[..body.len() / 2]).unwrap();
+
+        assert!(smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                resume: true,
+                ..Default::default()
+            },
+        )
+        .is_ok());
+        assert_eq!(*requests.lock().unwrap(), vec![body.len() / 2]);
+        assert!(!dir.join("chr1.vcf.gz.part").exists());
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_resume_with_md5_checkpoint() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let dir = test_dir("resume_md5state");
+        let output = dir.join("chr1.vcf.gz");
+        let part_path = dir.join("chr1.vcf.gz.part");
+        let md5state_path = dir.join("chr1.vcf.gz.md5state");
+        let half = body.len() / 2;
+        // Checkpoint of the MD5 of `bytes`, as saved by an interrupted download
+        let save_checkpoint = |bytes: &[u8]| {
+            let mut writer =
+                ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), ProgressBar::hidden(), None);
+            writer.save_checkpoints(md5state_path.clone(), bytes.len() as u64);
+            writer.write_all(bytes).unwrap();
+        };
+        let download = || {
+            let (url, requests) = serve(body.clone());
+            let result = smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    resume: true,
+                    overwrite: true,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(*requests.lock().unwrap(), vec![half]);
+            result
+        };
+
+        std::fs::write(&part_path, &body[..half]).unwrap();
+        save_checkpoint(&body[..half / 2]);
+        let stats = download().unwrap();
+        assert_eq!(stats.actual_checksum, Some(expected_md5.clone()));
+        assert_eq!(stats.bytes_downloaded, body.len() as u64);
+        assert!(!md5state_path.exists());
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+
+        // The part file is not hashed again: a checkpoint of other bytes gives another hash
+        std::fs::write(&part_path, &body[..half]).unwrap();
+        save_checkpoint(&vec![0; half]);
+        assert!(matches!(
+            download(),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+        assert!(!md5state_path.exists());
+
+        // A checkpoint beyond the part file cannot be of it
+        std::fs::write(&part_path, &body[..half]).unwrap();
+        save_checkpoint(&vec![0; half + 1]);
+        assert!(download().is_ok());
+    }
+
+    #[test]
+    fn test_retry_on_server_errors() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, requests) = serve_with_failures(body, 2);
+
+        let dir = test_dir("retry");
+        let output = dir.join("chr1.vcf.gz");
+
+        assert!(smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                retries: 2,
+                retry_base_delay: Duration::from_millis(1),
+                ..Default::default()
+            },
+        )
+        .is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_give_up_after_max_retries() {
+        let (url, requests) = serve_with_failures(Vec::new(), 10);
+        let options = DownloadOptions {
+            retries: 2,
+            retry_base_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            get_blocking_reader_from_url(&url, 0, &options),
+            Err(DownloadError::Http { .. })
+        ));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let body = bgzf_bytes(TEST_VCF);
+        let (url, _) = serve(body);
+        let dir = test_dir("checksum_mismatch");
+
+        let result = smart_save_vcf_from_url(
+            &url,
+            "0123456789abcdef0123456789abcdef",
+            [(100, 200)].into_iter(),
+            &dir.join("chr1.vcf.gz"),
+            ProgressBar::hidden(),
+            &DownloadOptions::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(result.unwrap_err().exit_code(), 1);
+    }
+
+    #[test]
+    fn test_not_bgzf_download() {
+        let dir = test_dir("not_bgzf");
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(TEST_VCF.join("\n").as_bytes()).unwrap();
+        let html = b"<!DOCTYPE html>\n<html><body>Not Found</body></html>\n".to_vec();
+
+        for (body, hint) in [(gzip.finish().unwrap(), "plain gzip"), (html, "HTML page")] {
+            let expected_md5 = md5_hex(&body);
+            let (url, _) = serve(body);
+            let output = dir.join("chr1.vcf.gz");
+            let result = smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions::default(),
+            );
+            assert!(matches!(
+                result,
+                Err(DownloadError::Parse(message))
+                    if message.contains("is not a bgzf file") && message.contains(hint)
+            ));
+            assert!(!output.exists());
+        }
+        assert!(is_bgzf_header(&bgzf_bytes(TEST_VCF)));
+    }
+
+    #[test]
+    fn test_prepare_output_dir() {
+        let dir = test_dir("output_dir");
+        let output_dir = dir.join("nested").join("output");
+        prepare_output_dir(&output_dir).unwrap();
+        assert!(output_dir.is_dir());
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 0);
+
+        let not_a_dir = dir.join("file");
+        std::fs::write(&not_a_dir, "").unwrap();
+        assert!(matches!(
+            prepare_output_dir(&not_a_dir),
+            Err(DownloadError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tabix_index() {
+        use noodles::csi::BinningIndex;
+
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("tabix_index");
+        let output = dir.join("chr1.vcf.gz");
+        smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(1, 1000)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                index: Some(IndexFormat::Auto),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let index = noodles::tabix::fs::read(dir.join("chr1.vcf.gz.tbi")).unwrap();
+        let chromosome_id = index
+            .header()
+            .unwrap()
+            .reference_sequence_names()
+            .get_index_of(&b"chr1"[..])
+            .unwrap();
+        let position = noodles::core::Position::try_from(150).unwrap();
+        let chunks = index
+            .query(chromosome_id, (position..=position).into())
+            .unwrap();
+
+        let mut reader = noodles::bgzf::Reader::new(File::open(&output).unwrap());
+        reader.seek(chunks[0].start()).unwrap();
+        let lines: Vec<String> = reader.lines().map(Result::unwrap).collect();
+        assert!(lines.contains(&TEST_VCF[3].to_string()));
+        assert!(lines.iter().all(|line| !line.starts_with('#')));
+    }
+
+    #[test]
+    fn test_csi_index_for_large_positions() {
+        use noodles::csi::BinningIndex;
+
+        let large_vcf = [
+            TEST_VCF[0],
+            TEST_VCF[1],
+            "chrUn\t100\t.\tA\tG\t.\tPASS\t.",
+            "chrUn\t600000000\t.\tC\tT\t.\tPASS\t.",
+        ];
+        let body = bgzf_bytes(&large_vcf);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("csi_index");
+        let output = dir.join("chrUn.vcf.gz");
+        let download = |format| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(1, 1_000_000_000)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    index: Some(format),
+                    overwrite: true,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(matches!(
+            download(IndexFormat::Tbi),
+            Err(DownloadError::Parse(_))
+        ));
+
+        download(IndexFormat::Auto).unwrap();
+        assert!(!dir.join("chrUn.vcf.gz.tbi").exists());
+        let index = noodles::csi::fs::read(dir.join("chrUn.vcf.gz.csi")).unwrap();
+        assert!(index.depth() > 5);
+
+        let position = noodles::core::Position::try_from(600_000_000).unwrap();
+        let chunks = index.query(0, (position..=position).into()).unwrap();
+        let mut reader = noodles::bgzf::Reader::new(File::open(&output).unwrap());
+        reader.seek(chunks[0].start()).unwrap();
+        let lines: Vec<String> = reader.lines().map(Result::unwrap).collect();
+        assert!(lines.contains(&large_vcf[3].to_string()));
+    }
+
+    #[test]
+    fn test_compression_levels() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("compression_levels");
+        let outputs: Vec<PathBuf> = [0, 9]
+            .into_iter()
+            .map(|level| {
+                let output = dir.join(format!("chr1.level{}.vcf.gz", level));
+                smart_save_vcf_from_url(
+                    &url,
+                    &expected_md5,
+                    [(1, 1000)].into_iter(),
+                    &output,
+                    ProgressBar::hidden(),
+                    &DownloadOptions {
+                        compression_level: CompressionLevel::new(level).unwrap(),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+                output
+            })
+            .collect();
+
+        assert_eq!(read_bgzf_lines(&outputs[0]), TEST_VCF);
+        assert_eq!(read_bgzf_lines(&outputs[0]), read_bgzf_lines(&outputs[1]));
+        assert!(
+            std::fs::metadata(&outputs[0]).unwrap().len()
+                > std::fs::metadata(&outputs[1]).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_bgzf_eof_block() {
+        const BGZF_EOF: [u8; 28] = [
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        // Enough lines to fill several bgzf blocks and the output buffer
+        let mut vcf = TEST_VCF[..2].to_vec();
+        let records: Vec<String> = (1..=100_000)
+            .map(|pos| format!("chr1\t{}\t.\tA\tG\t.\tPASS\tAC=1;AF=0.5", pos))
+            .collect();
+        vcf.extend(records.iter().map(String::as_str));
+        let body = bgzf_bytes(&vcf);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let output = test_dir("bgzf_eof_block").join("chr1.vcf.gz");
+        smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(1, 200_000)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions::default(),
+        )
+        .unwrap();
+
+        assert!(std::fs::read(&output).unwrap().ends_with(&BGZF_EOF));
+        assert_eq!(read_bgzf_lines(&output), vcf);
+    }
+
+    #[test]
+    fn test_multithreaded_compression() {
+        let mut vcf = TEST_VCF[..2].to_vec();
+        let records: Vec<String> = (1..=100_000)
+            .map(|pos| format!("chr1\t{}\t.\tA\tG\t.\tPASS\tAC=1;AF=0.5", pos))
+            .collect();
+        vcf.extend(records.iter().map(String::as_str));
+        let body = bgzf_bytes(&vcf);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("multithreaded_compression");
+        for write_threads in [1, 4] {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(1, 200_000)].into_iter(),
+                &dir.join(format!("chr1.{}.vcf.gz", write_threads)),
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    write_threads,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let multithreaded = dir.join("chr1.4.vcf.gz");
+        assert_eq!(read_bgzf_lines(&multithreaded), vcf);
+        assert_eq!(
+            read_bgzf_lines(&multithreaded),
+            read_bgzf_lines(&dir.join("chr1.1.vcf.gz"))
+        );
+    }
+
+    #[test]
+    fn test_uncompressed_output() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("uncompressed_output");
+        let download = |output: &Path, compress| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    compress,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        };
+        download(&dir.join("chr1.vcf.gz"), true);
+        download(&dir.join("chr1.vcf"), false);
+
+        let plain_lines: Vec<String> = std::fs::read_to_string(dir.join("chr1.vcf"))
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(plain_lines, read_bgzf_lines(&dir.join("chr1.vcf.gz")));
+        assert_eq!(plain_lines, vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]);
+    }
+
+    #[test]
+    fn test_bcf_output() {
+        use noodles::vcf::variant::RecordBuf;
+
+        let vcf = [
+            "##fileformat=VCFv4.3",
+            "##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">",
+            "##contig=<ID=chr1,length=1000>",
+            "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO",
+            "chr1\t50\t.\tA\tG\t.\tPASS\tAF=0.5",
+            "chr1\t150\trs1\tC\tT,G\t30\tPASS\tAF=0.1,0.2",
+            "chr1\t180\t.\tG\tA\t.\t.\t.",
+            "chr1\t250\t.\tG\tA\t.\tPASS\tAF=0.01",
+        ];
+        let body = bgzf_bytes(&vcf);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("bcf_output");
+        let download = |output: &Path, output_format| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    output_format,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let vcf_stats = download(&dir.join("chr1.vcf.gz"), OutputFormat::Vcf);
+        let bcf_stats = download(&dir.join("chr1.bcf"), OutputFormat::Bcf);
+        assert_eq!(vcf_stats, bcf_stats);
+
+        let mut vcf_reader = noodles::vcf::io::Reader::new(noodles::bgzf::Reader::new(
+            File::open(dir.join("chr1.vcf.gz")).unwrap(),
+        ));
+        let vcf_header = vcf_reader.read_header().unwrap();
+        let vcf_records: Vec<RecordBuf> = vcf_reader
+            .record_bufs(&vcf_header)
+            .map(Result::unwrap)
+            .collect();
+
+        let mut bcf_reader =
+            noodles::bcf::io::Reader::new(File::open(dir.join("chr1.bcf")).unwrap());
+        let bcf_header = bcf_reader.read_header().unwrap();
+        let bcf_records: Vec<RecordBuf> = bcf_reader
+            .records()
+            .map(|record| {
+                RecordBuf::try_from_variant_record(&bcf_header, &record.unwrap()).unwrap()
+            })
+            .collect();
+
+        assert_eq!(bcf_records.len(), 2);
+        assert_eq!(bcf_records, vcf_records);
+        assert_eq!(bcf_header.contigs(), vcf_header.contigs());
+    }
+
+    #[test]
+    fn test_download_plan() {
+        let jobs: Vec<DownloadJob> = [
+            ("chr1", vec![(101, 201), (501, 1001)]),
+            ("chrX", vec![(1, 11)]),
+        ]
+        .into_iter()
+        .map(|(chrom_name, regions)| DownloadJob {
+            chrom_name: chrom_name.to_string(),
+            url: format!("http://example.com/{}.vcf.bgz", chrom_name),
+            expected_checksum: "abcdef".to_string(),
+            regions: regions.into(),
+            output_path: PathBuf::from(format!("{}.vcf.gz", chrom_name)),
+            progress_bar: ProgressBar::hidden(),
+        })
+        .collect();
+
+        let mut plan = Vec::new();
+        write_download_plan(&mut plan, &jobs).unwrap();
+        assert_eq!(
+            String::from_utf8(plan).unwrap(),
+            "chromosome\tintervals\tspan_bp\turl\n\
+             chr1\t2\t600\thttp://example.com/chr1.vcf.bgz\n\
+             chrX\t1\t10\thttp://example.com/chrX.vcf.bgz\n"
+        );
+    }
+
+    #[test]
+    fn test_skip_existing_download() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, requests) = serve(body);
+
+        let dir = test_dir("skip_existing");
+        let output = dir.join("chr1.vcf.gz");
+        let download_with = |regions: [(u64, u64); 1], line_filter: LineFilter| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                regions.into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    skip_existing: true,
+                    overwrite: true,
+                    line_filter,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        };
+        let download = |regions| download_with(regions, LineFilter::default());
+
+        download([(100, 200)]);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Same source and regions: nothing is downloaded
+        download([(100, 200)]);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Other regions: the output is outdated
+        download([(1, 1000)]);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // Modified output: downloaded again
+        std::fs::write(&output, "corrupted").unwrap();
+        download([(1, 1000)]);
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert_eq!(read_bgzf_lines(&output), TEST_VCF);
+
+        // Other filters: the output is outdated as well
+        let min_af = |min_af| LineFilter {
+            min_af: Some(min_af),
+            ..Default::default()
+        };
+        download_with([(1, 1000)], min_af(0.1));
+        assert_eq!(requests.lock().unwrap().len(), 4);
+        download_with([(1, 1000)], min_af(0.1));
+        assert_eq!(requests.lock().unwrap().len(), 4);
+        download_with([(1, 1000)], min_af(0.2));
+        assert_eq!(requests.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_md5_sidecar() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+
+        let dir = test_dir("md5_sidecar");
+        let output = dir.join("chr1.vcf.gz");
+        smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                write_md5: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let output_md5 = md5_hex(std::fs::read(&output).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("chr1.vcf.gz.md5")).unwrap(),
+            format!("{}  chr1.vcf.gz\n", output_md5)
+        );
+    }
+
+    #[test]
+    fn test_sha256_checksum() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_sha256: String = sha2::Sha256::digest(&body)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let (url, _) = serve(body);
+        let dir = test_dir("sha256_checksum");
+
+        let download = |checksum: &str, checksum_algorithm| {
+            smart_save_vcf_from_url(
+                &url,
+                checksum,
+                [(100, 200)].into_iter(),
+                &dir.join("chr1.vcf.gz"),
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    checksum_algorithm,
+                    overwrite: true,
+                    ..Default::default()
+                },
+            )
+        };
+        // Detected from the length of the checksum
+        download(&expected_sha256, None).unwrap();
+        download(&expected_sha256, Some(ChecksumAlgorithm::Sha256)).unwrap();
+        assert!(matches!(
+            download(&expected_sha256, Some(ChecksumAlgorithm::Md5)),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_no_verify() {
+        let body = bgzf_bytes(TEST_VCF);
+        let (url, _) = serve(body);
+        let dir = test_dir("no_verify");
+        let output = dir.join("chr1.vcf.gz");
+
+        smart_save_vcf_from_url(
+            &url,
+            "stale",
+            [(100, 200)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                verify: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+        assert!(!dir.join("chr1.vcf.gz.verified").exists());
+    }
+
+    #[test]
+    fn test_stream_chromosomes_to_single_writer() {
+        let chr2_vcf = [TEST_VCF[0], TEST_VCF[1], "chr2\t120\t.\tT\tC\t.\tPASS\t."];
+        // Same setup as --stdout, with a buffer in place of stdout
+        let mut output = noodles::bgzf::io::Writer::new(Vec::new());
+        for (lines, with_header) in [(TEST_VCF, true), (&chr2_vcf[..], false)] {
+            let body = bgzf_bytes(lines);
+            let expected_md5 = md5_hex(&body);
+            let (url, _) = serve(body);
+            stream_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &mut output,
+                with_header,
+                ProgressBar::hidden(),
+                &DownloadOptions::default(),
+            )
+            .unwrap();
+        }
+
+        let mut decompressed = String::new();
+        noodles::bgzf::Reader::new(&output.finish().unwrap()[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(
+            decompressed.lines().collect::<Vec<_>>(),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3], chr2_vcf[2]]
+        );
+    }
+
+    #[test]
+    fn test_download_without_content_length() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let body_length = body.len() as u64;
+        let url = serve_without_length(body);
+        let dir = test_dir("no_content_length");
+        let output = dir.join("chr1.vcf.gz");
+
+        let progress_bar = ProgressBar::hidden();
+        smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &output,
+            progress_bar.clone(),
+            &DownloadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(progress_bar.length(), None);
+        assert_eq!(progress_bar.position(), body_length);
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Accepts connections but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/test.vcf.bgz", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+
+        let start = Instant::now();
+        let result = get_blocking_reader_from_url(
+            &url,
+            0,
+            &DownloadOptions {
+                retries: 0,
+                timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(DownloadError::Http { .. })));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_redirect() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (target, _) = serve(body);
+        let url = serve_redirect(target.clone());
+        let dir = test_dir("redirect");
+
+        let download = |max_redirects| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &dir.join("chr1.vcf.gz"),
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    max_redirects,
+                    retries: 0,
+                    overwrite: true,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(download(1).unwrap().kept_records, 1);
+        assert_eq!(
+            get_blocking_reader_from_url(&url, 0, &DownloadOptions::default())
+                .unwrap()
+                .url()
+                .as_str(),
+            target
+        );
+        assert!(matches!(
+            download(0),
+            Err(DownloadError::Http { reason, .. }) if reason.contains("more than 0 redirects")
+        ));
+    }
+
+    #[test]
+    fn test_proxy() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        // The test server answers any request, including the ones forwarded by a proxy
+        let proxy = serve_without_length(body);
+        let proxy = proxy.trim_end_matches("/test.vcf.bgz").to_string();
+        let dir = test_dir("proxy");
+        let output = dir.join("chr1.vcf.gz");
+
+        // Unresolvable without the proxy
+        smart_save_vcf_from_url(
+            "http://gnomad.invalid/chr1.vcf.bgz",
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                retries: 0,
+                proxy: Some(proxy),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_shared_client() {
+        let client = Arc::new(build_client(&DownloadOptions::default()).unwrap());
+        let options = DownloadOptions {
+            client: Some(client.clone()),
+            ..Default::default()
+        };
+        // Every worker of `run_downloads` gets its own copy of the options
+        let worker_options = [options.clone(), options.clone()];
+        for options in &worker_options {
+            assert!(Arc::ptr_eq(&http_client(options).unwrap(), &client));
+        }
+        assert!(!Arc::ptr_eq(
+            &http_client(&DownloadOptions::default()).unwrap(),
+            &client
+        ));
+    }
+
+    #[test]
+    fn test_cancelled_download() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, requests) = serve(body);
+        let dir = test_dir("cancelled");
+        let options = DownloadOptions {
+            cancel: Some(Arc::new(AtomicBool::new(true))),
+            ..Default::default()
+        };
+
+        // Interrupted while writing its output
+        let output = dir.join("chr1.vcf.gz");
+        let result = smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &output,
+            ProgressBar::hidden(),
+            &options,
+        );
+        assert!(matches!(result, Err(DownloadError::Cancelled)));
+        assert!(!output.exists());
+        assert!(dir.join("chr1.vcf.gz.partial").exists());
+
+        // Not started at all
+        let outcomes = run_downloads(
+            vec![DownloadJob {
+                chrom_name: "chr2".to_string(),
+                url: url.clone(),
+                expected_checksum: expected_md5,
+                regions: vec![(100, 200)].into(),
+                output_path: dir.join("chr2.vcf.gz"),
+                progress_bar: ProgressBar::hidden(),
+            }],
+            1,
+            &options,
+        );
+        assert!(matches!(outcomes[0].result, Err(DownloadError::Cancelled)));
+        assert!(!dir.join("chr2.vcf.gz").exists());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_max_runtime() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        // About 5 seconds to send the whole body
+        let url = serve_slowly(
+            body.clone(),
+            Duration::from_millis(5000 / body.len() as u64),
+        );
+        let dir = test_dir("max_runtime");
+        let cancel = Arc::new(AtomicBool::new(false));
+        let timed_out = start_watchdog(Duration::from_millis(300), cancel.clone());
+
+        let start = Instant::now();
+        let outcomes = run_downloads(
+            vec![DownloadJob {
+                chrom_name: "chr1".to_string(),
+                url,
+                expected_checksum: expected_md5,
+                regions: vec![(100, 200)].into(),
+                output_path: dir.join("chr1.vcf.gz"),
+                progress_bar: ProgressBar::hidden(),
+            }],
+            1,
+            &DownloadOptions {
+                cancel: Some(cancel),
+                ..Default::default()
+            },
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert!(timed_out.load(Ordering::Relaxed));
+        assert!(matches!(outcomes[0].result, Err(DownloadError::Cancelled)));
+        assert!(!dir.join("chr1.vcf.gz").exists());
+
+        // Cancelled before the deadline, e.g. by Ctrl-C
+        let cancel = Arc::new(AtomicBool::new(true));
+        let timed_out = start_watchdog(Duration::from_millis(10), cancel);
+        thread::sleep(Duration::from_millis(100));
+        assert!(!timed_out.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (failing_url, _) = serve(body.clone());
+        // About 5 seconds to send the whole body
+        let slow_url = serve_slowly(
+            body.clone(),
+            Duration::from_millis(5000 / body.len() as u64),
+        );
+        let dir = test_dir("fail_fast");
+        let jobs = vec![
+            DownloadJob {
+                chrom_name: "chr1".to_string(),
+                url: failing_url,
+                expected_checksum: "0123456789abcdef0123456789abcdef".to_string(),
+                regions: vec![(100, 200)].into(),
+                output_path: dir.join("chr1.vcf.gz"),
+                progress_bar: ProgressBar::hidden(),
+            },
+            DownloadJob {
+                chrom_name: "chr2".to_string(),
+                url: slow_url,
+                expected_checksum: expected_md5,
+                regions: vec![(100, 200)].into(),
+                output_path: dir.join("chr2.vcf.gz"),
+                progress_bar: ProgressBar::hidden(),
+            },
+        ];
+
+        let start = Instant::now();
+        let outcomes = run_downloads(
+            jobs,
+            2,
+            &DownloadOptions {
+                fail_fast: true,
+                ..Default::default()
+            },
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+        let result = |chrom_name: &str| {
+            &outcomes
+                .iter()
+                .find(|outcome| outcome.chrom_name == chrom_name)
+                .unwrap()
+                .result
+        };
+        assert!(matches!(
+            result("chr1"),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+        assert!(matches!(result("chr2"), Err(DownloadError::Cancelled)));
+        assert!(!dir.join("chr2.vcf.gz").exists());
+    }
+
+    #[test]
+    fn test_existing_output() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, requests) = serve(body);
+        let dir = test_dir("existing_output");
+        let output = dir.join("chr1.vcf.gz");
+        std::fs::write(&output, "previous download").unwrap();
+        let download = |overwrite| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    overwrite,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(matches!(
+            download(false),
+            Err(DownloadError::Io { source, .. })
+                if source.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "previous download"
+        );
+        assert!(requests.lock().unwrap().is_empty());
+
+        download(true).unwrap();
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_connections_per_file() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let body_length = body.len();
+        let (url, requests) = serve(body);
+        let dir = test_dir("connections_per_file");
+        let download = |connections_per_file, output: &Path| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    connections_per_file,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let single = download(1, &dir.join("single.vcf.gz"));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        let split = download(3, &dir.join("split.vcf.gz"));
+        let mut offsets = requests.lock().unwrap()[1..].to_vec();
+        offsets.sort();
+        let chunk_length = body_length.div_ceil(3);
+        assert_eq!(offsets, [0, chunk_length, 2 * chunk_length]);
+
+        assert_eq!(split, single);
+        assert_eq!(
+            std::fs::read(dir.join("split.vcf.gz")).unwrap(),
+            std::fs::read(dir.join("single.vcf.gz")).unwrap()
+        );
+
+        // The chunks go to the temporary directory
+        let chunked_options = |tmp_dir: PathBuf| DownloadOptions {
+            connections_per_file: 3,
+            tmp_dir: Some(tmp_dir),
+            ..Default::default()
+        };
+        let output = dir.join("tmp_dir.vcf.gz");
+        let regions = [(100, 200)].into_iter();
+        let options = chunked_options(dir.join("missing"));
+        let download = smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            regions.clone(),
+            &dir.join("missing_tmp_dir.vcf.gz"),
+            ProgressBar::hidden(),
+            &options,
+        );
+        assert!(
+            download
+                .as_ref()
+                .is_err_and(|e| e.to_string().contains("chunk file")),
+            "{:?}",
+            download
+        );
+        std::fs::create_dir(dir.join("tmp")).unwrap();
+        let options = chunked_options(dir.join("tmp"));
+        let download = smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            regions,
+            &output,
+            ProgressBar::hidden(),
+            &options,
+        );
+        assert_eq!(download.unwrap(), single);
+        assert_eq!(chunk_dir(Some(&output), &DownloadOptions::default()), dir);
+        assert_eq!(chunk_dir(None, &options), dir.join("tmp"));
+
+        let (url, _) = serve(Vec::new());
+        assert!(matches!(
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &dir.join("empty.vcf.gz"),
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    connections_per_file: 3,
+                    ..Default::default()
+                },
+            ),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_verify_before_write() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("verify_before_write");
+        let output = dir.join("chr1.vcf.gz");
+        let download = |checksum: &str| {
+            smart_save_vcf_from_url(
+                &url,
+                checksum,
+                [(100, 200)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    verify_before_write: true,
+                    index: Some(IndexFormat::Tbi),
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(matches!(
+            download("0123456789abcdef0123456789abcdef"),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+        assert!(!output.exists());
+        assert!(!dir.join("chr1.vcf.gz.tbi").exists());
+        assert!(dir.join("chr1.vcf.gz.tmp").exists());
+
+        download(&expected_md5).unwrap();
+        assert!(!dir.join("chr1.vcf.gz.tmp").exists());
+        assert!(dir.join("chr1.vcf.gz.tbi").exists());
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_tmp_dir() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("tmp_dir");
+        let tmp_dir = dir.join("tmp");
+        std::fs::create_dir(&tmp_dir).unwrap();
+        let output = dir.join("chr1.vcf.gz");
+        let download = |checksum: &str| {
+            smart_save_vcf_from_url(
+                &url,
+                checksum,
+                [(100, 200)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                &DownloadOptions {
+                    verify_before_write: true,
+                    tmp_dir: Some(tmp_dir.clone()),
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(download("0123456789abcdef0123456789abcdef").is_err());
+        assert!(tmp_dir.join("chr1.vcf.gz.tmp").exists());
+        assert!(!dir.join("chr1.vcf.gz.tmp").exists());
+
+        download(&expected_md5).unwrap();
+        assert!(!tmp_dir.join("chr1.vcf.gz.tmp").exists());
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_move_file_across_devices() {
+        let dir = test_dir("move_file_across_devices");
+        let from = dir.join("chr1.vcf.gz.tmp");
+        let to = dir.join("chr1.vcf.gz");
+        std::fs::write(&from, "content").unwrap();
+
+        // What rename reports when both paths are not on the same filesystem
+        let cross_device = |_: &Path, _: &Path| Err(std::io::ErrorKind::CrossesDevices.into());
+        move_file_with(&from, &to, cross_device).unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "content");
+
+        // Other errors are not worked around
+        std::fs::write(&from, "other").unwrap();
+        let denied = |_: &Path, _: &Path| Err(std::io::ErrorKind::PermissionDenied.into());
+        assert!(move_file_with(&from, &to, denied).is_err());
+        assert!(from.exists());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "content");
+
+        move_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "other");
+    }
+
+    #[test]
+    fn test_local_file_url() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let dir = test_dir("local_file_url");
+        let source = dir.join("source.vcf.bgz");
+        std::fs::write(&source, &body).unwrap();
+        let url = reqwest::Url::from_file_path(&source).unwrap().to_string();
+        assert!(url.starts_with("file://"));
+
+        let download = |url: &str, checksum: &str, output: &str| {
+            smart_save_vcf_from_url(
+                url,
+                checksum,
+                [(100, 200)].into_iter(),
+                &dir.join(output),
+                ProgressBar::hidden(),
+                &DownloadOptions::default(),
+            )
+        };
+        let stats = download(&url, &expected_md5, "chr1.vcf.gz").unwrap();
+        assert_eq!(stats.bytes_downloaded, body.len() as u64);
+        assert_eq!(
+            read_bgzf_lines(&dir.join("chr1.vcf.gz")),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+        // Bare absolute paths work the same way
+        download(source.to_str().unwrap(), &expected_md5, "chr1_path.vcf.gz").unwrap();
+
+        // The checksum is still verified
+        assert!(matches!(
+            download(&url, "0123456789abcdef0123456789abcdef", "mismatch.vcf.gz"),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_custom_headers() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let url = serve_requiring_header(body, "Authorization: Bearer secret");
+        let dir = test_dir("custom_headers");
+        let output = dir.join("chr1.vcf.gz");
+        let download = |options: &DownloadOptions| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &output,
+                ProgressBar::hidden(),
+                options,
+            )
+        };
+
+        assert!(matches!(
+            download(&DownloadOptions::default()),
+            Err(DownloadError::Http { .. })
+        ));
+        download(&DownloadOptions {
+            headers: vec![("Authorization".to_string(), "Bearer secret".to_string())],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            read_bgzf_lines(&output),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+    }
+
+    #[test]
+    fn test_total_progress() {
+        let dir = test_dir("total_progress");
+        let mut jobs = Vec::new();
+        let mut total_length = 0;
+        for chrom_name in ["chr1", "chr2"] {
+            let body = bgzf_bytes(TEST_VCF);
+            total_length += body.len() as u64;
+            let expected_checksum = md5_hex(&body);
+            let (url, _) = serve(body);
+            jobs.push(DownloadJob {
+                chrom_name: chrom_name.to_string(),
+                url,
+                expected_checksum,
+                regions: vec![(100, 200)].into(),
+                output_path: dir.join(format!("{}.vcf.gz", chrom_name)),
+                progress_bar: ProgressBar::hidden(),
+            });
+        }
+
+        let total_progress = TotalProgress::new(ProgressBar::hidden(), jobs.len());
+        let options = DownloadOptions {
+            total_progress: Some(total_progress.clone()),
+            ..Default::default()
+        };
+        for outcome in run_downloads(jobs, 2, &options) {
+            outcome.result.unwrap();
+        }
+        assert_eq!(total_progress.progress_bar().length(), Some(total_length));
+        assert_eq!(total_progress.progress_bar().position(), total_length);
+    }
+
+    #[test]
+    fn test_outcome_status() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("outcome_status");
+        let job = |chrom_name: &str, url: &str, expected_checksum: &str| DownloadJob {
+            chrom_name: chrom_name.to_string(),
+            url: url.to_string(),
+            expected_checksum: expected_checksum.to_string(),
+            regions: vec![(100, 200)].into(),
+            output_path: dir.join(format!("{}.vcf.gz", chrom_name)),
+            progress_bar: ProgressBar::hidden(),
+        };
+        let jobs = vec![
+            job("chr1", &url, &expected_md5),
+            job("chr2", &url, "0123456789abcdef0123456789abcdef"),
+            // Nothing listens on port 9
+            job("chr3", "http://127.0.0.1:9/chr3.vcf.bgz", &expected_md5),
+            // The output cannot be created
+            DownloadJob {
+                output_path: dir.join("missing").join("chr4.vcf.gz"),
+                ..job("chr4", &url, &expected_md5)
+            },
+        ];
+
+        let mut outcomes = run_downloads(
+            jobs,
+            2,
+            &DownloadOptions {
+                retries: 0,
+                overwrite: true,
+                ..Default::default()
+            },
+        );
+        outcomes.sort_by(|a, b| a.chrom_name.cmp(&b.chrom_name));
+        let statuses: Vec<(&str, OutcomeStatus)> = outcomes
+            .iter()
+            .map(|outcome| (outcome.chrom_name.as_str(), outcome.status()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("chr1", OutcomeStatus::Success),
+                ("chr2", OutcomeStatus::ChecksumMismatch),
+                ("chr3", OutcomeStatus::NetworkError),
+                ("chr4", OutcomeStatus::Failed),
+            ]
+        );
+        assert_eq!(
+            OutcomeStatus::ChecksumMismatch.to_string(),
+            "checksum mismatch"
+        );
+    }
+
+    #[test]
+    fn test_panicking_download() {
+        let result = catch_panic(|| {
+            let positions: Vec<u64> = Vec::new();
+            Ok(DownloadStats {
+                total_records: positions[3],
+                ..Default::default()
+            })
+        });
+        assert!(matches!(
+            result,
+            Err(DownloadError::Panicked(message)) if message.contains("out of bounds")
+        ));
+        assert!(matches!(
+            catch_panic(|| panic!("failed on {}", "chr2")),
+            Err(DownloadError::Panicked(message)) if message == "failed on chr2"
+        ));
+        assert_eq!(
+            catch_panic(|| Ok(DownloadStats::default())).unwrap(),
+            DownloadStats::default()
+        );
+    }
+
+    #[test]
+    fn test_keep_raw() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("keep_raw");
+
+        smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &dir.join("chr1.vcf.gz"),
+            ProgressBar::hidden(),
+            &DownloadOptions {
+                keep_raw: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            read_bgzf_lines(&dir.join("chr1.vcf.gz")),
+            vec![TEST_VCF[0], TEST_VCF[1], TEST_VCF[3]]
+        );
+        assert_eq!(
+            file_md5(&dir.join("chr1.raw.vcf.gz")).unwrap(),
+            expected_md5
+        );
+
+        assert_eq!(
+            raw_output_path(Path::new("out/gnomad_chr1.bcf")),
+            Path::new("out/gnomad_chr1.raw.vcf.gz")
+        );
+        assert_eq!(
+            raw_output_path(Path::new("chr1.exomes.vcf")),
+            Path::new("chr1.exomes.raw.vcf.gz")
+        );
+    }
+
+    #[test]
+    fn test_empty_intervals() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("empty_intervals");
+        let options = DownloadOptions {
+            report_empty_intervals: true,
+            ..Default::default()
+        };
+        let regions = [(1, 10), (100, 200), (210, 240), (250, 251)];
+
+        let stats = smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            regions.into_iter(),
+            &dir.join("chr1.vcf.gz"),
+            ProgressBar::hidden(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(stats.kept_records, 2);
+        assert_eq!(stats.empty_intervals, Some(vec![(1, 10), (210, 240)]));
+
+        let counted = count_vcf_records_from_url(
+            &url,
+            &expected_md5,
+            regions.into_iter(),
+            ProgressBar::hidden(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(counted.empty_intervals, stats.empty_intervals);
+        let untracked = count_vcf_records_from_url(
+            &url,
+            &expected_md5,
+            regions.into_iter(),
+            ProgressBar::hidden(),
+            &DownloadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(untracked.empty_intervals, None);
+    }
+
+    #[test]
+    fn test_streamed_bed_regions() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("streamed_bed_regions");
+        let bed = "chr1\t99\t120\nchr1\t110\t150\nchr1\t249\t250\nchr2\t0\t10\n";
+        std::fs::write(dir.join("regions.bed"), bed).unwrap();
+        let options = BedReadOptions {
+            sorted: true,
+            ..Default::default()
+        };
+
+        // Only where the lines of each chromosome are is kept in memory
+        let sections = index_sorted_bed(&dir.join("regions.bed"), &options)
+            .unwrap()
+            .0;
+        let streamed = JobRegions::Streamed(sections["chr1"].clone());
+        let loaded = JobRegions::from(
+            read_bed_regions_with(bed.as_bytes(), &options).unwrap()["chr1"].clone(),
+        );
+        for (regions, output) in [(&streamed, "streamed.vcf.gz"), (&loaded, "loaded.vcf.gz")] {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                regions.iter(),
+                &dir.join(output),
+                ProgressBar::hidden(),
+                &DownloadOptions::default(),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            read_bgzf_lines(&dir.join("streamed.vcf.gz")),
+            read_bgzf_lines(&dir.join("loaded.vcf.gz"))
+        );
+        assert_eq!(
+            read_bgzf_lines(&dir.join("streamed.vcf.gz")),
+            [TEST_VCF[0], TEST_VCF[1], TEST_VCF[3], TEST_VCF[4]]
+        );
+    }
+
+    #[test]
+    fn test_count_only() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("count_only");
+
+        let download = |output: &Path, options: &DownloadOptions| {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200), (250, 251)].into_iter(),
+                output,
+                ProgressBar::hidden(),
+                options,
+            )
+        };
+        let counted = download(
+            &dir.join("counted").join("chr1.vcf.gz"),
+            &DownloadOptions {
+                count_only: true,
+                write_md5: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let filtered = download(&dir.join("chr1.vcf.gz"), &DownloadOptions::default()).unwrap();
+        assert_eq!(counted, filtered);
+        assert_eq!(counted.kept_records, 2);
+        assert!(!dir.join("counted").exists());
+
+        // The checksum is still verified
+        assert!(matches!(
+            count_vcf_records_from_url(
+                &url,
+                "0123456789abcdef0123456789abcdef",
+                [(100, 200)].into_iter(),
+                ProgressBar::hidden(),
+                &DownloadOptions::default(),
+            ),
+            Err(DownloadError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_download_stats() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let body_length = body.len() as u64;
+        let (url, _) = serve(body);
+        let dir = test_dir("download_stats");
+
+        let stats = smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200), (250, 251)].into_iter(),
+            &dir.join("chr1.vcf.gz"),
+            ProgressBar::hidden(),
+            &DownloadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            stats,
+            DownloadStats {
+                total_records: 3,
+                bad_lines: 0,
+                kept_records: 2,
+                bytes_downloaded: body_length,
+                actual_checksum: Some(expected_md5.clone()),
+                empty_intervals: None,
+            }
+        );
+
+        // Timed around the download
+        let outcomes = run_downloads(
+            vec![DownloadJob {
+                chrom_name: "chr1".to_string(),
+                url,
+                expected_checksum: expected_md5,
+                regions: vec![(100, 200), (250, 251)].into(),
+                output_path: dir.join("chr1.vcf.gz"),
+                progress_bar: ProgressBar::hidden(),
+            }],
+            1,
+            &DownloadOptions {
+                overwrite: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(outcomes[0].result.as_ref().unwrap(), &stats);
+        assert!(outcomes[0].elapsed > Duration::ZERO);
+
+        let mut summary = Vec::new();
+        write_download_summary(
+            &mut summary,
+            &[
+                (
+                    "chr1".to_string(),
+                    stats.clone(),
+                    Duration::from_millis(1250),
+                ),
+                ("chr2".to_string(), stats, Duration::from_millis(500)),
+            ],
+            &HashMap::from([("chr2".to_string(), 4), ("chr3".to_string(), 1)]),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(summary).unwrap(),
+            format!(
+                "chromosome\ttotal_records\tkept_records\tbytes_downloaded\telapsed_seconds\t\
+                 dropped_intervals\n\
+                 chr1\t3\t2\t{0}\t1.250\t0\nchr2\t3\t2\t{0}\t0.500\t4\n\
+                 total\t6\t4\t{1}\t1.750\t5\n",
+                body_length,
+                2 * body_length
+            )
+        );
+    }
+}
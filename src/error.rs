@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// Everything that can go wrong while downloading and filtering a VCF.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// Reading or writing a local file failed
+    Io {
+        context: String,
+        source: std::io::Error,
+    },
+    /// The remote file could not be fetched
+    Http { url: String, reason: String },
+    /// An input (BED, URL list or downloaded VCF) is malformed
+    Parse(String),
+    /// The downloaded stream does not match its expected checksum
+    ChecksumMismatch { expected: String, actual: String },
+    /// A URL list row has no URL for this chromosome
+    MissingUrl(String),
+    /// The run was interrupted (Ctrl-C) before the download could complete
+    Cancelled,
+    /// The download panicked, with this message. Only this download is lost, not the run
+    Panicked(String),
+    /// The run was stopped for lasting longer than allowed (`--max-runtime`)
+    TimedOut(std::time::Duration),
+}
+
+impl DownloadError {
+    /// Wraps an I/O error with a description of what was being done, for use with `map_err`.
+    pub fn io(context: impl Into<String>) -> impl FnOnce(std::io::Error) -> DownloadError {
+        let context = context.into();
+        move |source| DownloadError::Io { context, source }
+    }
+
+    /// Process exit code reported for this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            DownloadError::ChecksumMismatch { .. } => 1,
+            DownloadError::Parse(_) | DownloadError::MissingUrl(_) => 2,
+            DownloadError::Io { .. } | DownloadError::Http { .. } => 3,
+            // As if killed by SIGINT
+            DownloadError::Cancelled => 130,
+            // As an uncaught panic
+            DownloadError::Panicked(_) => 101,
+            // As with timeout(1)
+            DownloadError::TimedOut(_) => 124,
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Io { context, source } => write!(f, "{}: {}", context, source),
+            DownloadError::Http { url, reason } => {
+                write!(f, "Cannot download file at {}: {}", url, reason)
+            }
+            DownloadError::Parse(message) => write!(f, "{}", message),
+            DownloadError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksums do not match (expected {}, got {})",
+                expected, actual
+            ),
+            DownloadError::MissingUrl(chromosome) => {
+                write!(f, "No URL given for chromosome {}", chromosome)
+            }
+            DownloadError::Cancelled => write!(f, "Download cancelled"),
+            DownloadError::Panicked(message) => write!(f, "Internal error: {}", message),
+            DownloadError::TimedOut(max_runtime) => write!(
+                f,
+                "Stopped after the maximum runtime of {}s",
+                max_runtime.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,841 @@
+use std::borrow::Cow;
+use std::io::{BufRead, Write};
+
+use crate::error::DownloadError;
+use crate::intervals::{IntervalTree, SortedIntervalIntersect};
+use crate::vcf;
+
+/// Extracts the POS column of a VCF data line (see `vcf::parse_pos`).
+pub fn parse_vcf_pos(line: &str) -> Result<u64, DownloadError> {
+    vcf::parse_pos(line)
+        .map_err(|e| DownloadError::Parse(format!("Invalid vcf line {}: {}", line, e)))
+}
+
+/// What to do with a VCF data line whose POS cannot be read.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BadLinePolicy {
+    /// Skip the line, the number of skipped lines being reported at the end
+    Skip,
+    /// Fail the whole file
+    #[default]
+    Error,
+}
+
+/// Which allele frequency of a multi-allelic record is compared to the AF thresholds.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultiAllelicAf {
+    /// The highest frequency among the alternate alleles
+    #[default]
+    Max,
+    /// The lowest frequency among the alternate alleles
+    Min,
+}
+
+/// How the data lines falling in the regions are filtered further by `filter_vcf_lines`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineFilter {
+    pub on_bad_line: BadLinePolicy,
+    /// Records whose `AF` (INFO field) is below this are dropped
+    pub min_af: Option<f64>,
+    /// Records whose `AF` (INFO field) is above this are dropped
+    pub max_af: Option<f64>,
+    pub multi_allelic_af: MultiAllelicAf,
+    /// Only keep records whose FILTER is `PASS` or missing (`.`)
+    pub pass_only: bool,
+    /// Only keep these INFO keys, in this order, in the kept records
+    pub keep_info: Option<Vec<String>>,
+    /// With `keep_info`, also drop the `##INFO` header lines of the other keys
+    pub prune_info_header: bool,
+    /// Match records on their whole span, from `POS` to their end (see `parse_vcf_end`),
+    /// rather than on `POS` alone
+    pub sv_aware: bool,
+    /// Only keep the `##contig` header lines of the chromosome of the first record. These lines
+    /// are held back until then, and thus end up right before `#CHROM`. All of them are kept
+    /// for a VCF without any record
+    pub trim_header: bool,
+    /// The records may not be sorted by position: each one is looked up in an `IntervalTree`
+    /// of the regions, instead of the regions being walked along with the records. The kept
+    /// records are then held in memory to be passed on sorted by POS, at the end
+    pub assume_unsorted: bool,
+    /// With `assume_unsorted`, pass the kept records on as they come, in the order of the VCF
+    pub keep_input_order: bool,
+    /// Only keep these sample columns, in this order, after the FORMAT column of the kept
+    /// records. They are located by the `#CHROM` header line, which is rewritten the same way
+    pub samples: Option<Vec<String>>,
+}
+
+impl LineFilter {
+    /// Whether a data line passes the filters, other than the region check.
+    /// With an AF threshold, records without a readable `AF` are dropped.
+    pub fn keeps(&self, line: &str) -> bool {
+        if self.pass_only && !matches!(vcf::filter(line), Some("PASS" | ".")) {
+            return false;
+        }
+        if self.min_af.is_none() && self.max_af.is_none() {
+            return true;
+        }
+        let Some(af) = parse_vcf_af(line, self.multi_allelic_af) else {
+            return false;
+        };
+        self.min_af.is_none_or(|min_af| af >= min_af)
+            && self.max_af.is_none_or(|max_af| af <= max_af)
+    }
+
+    /// Whether a header line is kept, i.e. is not the `##INFO` line of a pruned key.
+    pub fn keeps_header(&self, line: &str) -> bool {
+        let Some(keep_info) = self.keep_info.as_ref().filter(|_| self.prune_info_header) else {
+            return true;
+        };
+        match line.strip_prefix("##INFO=<ID=") {
+            Some(definition) => {
+                let id = definition.split([',', '>']).next().unwrap_or_default();
+                keep_info.iter().any(|key| key == id)
+            }
+            None => true,
+        }
+    }
+
+    /// Fields of the lines kept with `samples`, from the `#CHROM` header line `chrom_line`:
+    /// those up to FORMAT then the selected samples. `None` without `samples`, an error if a
+    /// sample is not in the VCF.
+    pub fn sample_fields(&self, chrom_line: &str) -> Result<Option<Vec<usize>>, DownloadError> {
+        let Some(samples) = &self.samples else {
+            return Ok(None);
+        };
+        let columns: Vec<&str> = chrom_line.split('\t').collect();
+        let mut fields: Vec<usize> = (0..=vcf::FORMAT).collect();
+        for sample in samples {
+            let field = columns
+                .iter()
+                .skip(vcf::FORMAT + 1)
+                .position(|column| column == sample)
+                .ok_or_else(|| {
+                    DownloadError::Parse(format!("Sample {} is not in the VCF", sample))
+                })?;
+            fields.push(vcf::FORMAT + 1 + field);
+        }
+        Ok(Some(fields))
+    }
+
+    /// Chromosome of a `##contig` header line, `None` for any other line.
+    pub fn header_contig(line: &str) -> Option<&str> {
+        let definition = line.strip_prefix("##contig=<ID=")?;
+        definition.split([',', '>']).next()
+    }
+
+    /// Rewrites the INFO column of a data line to only hold the keys of `keep_info`,
+    /// in the order of `keep_info` (`.` if none is left).
+    pub fn prune_info<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let Some(keep_info) = &self.keep_info else {
+            return Cow::Borrowed(line);
+        };
+        let mut fields: Vec<&str> = line.split('\t').collect();
+        let Some(info) = fields.get(vcf::INFO) else {
+            return Cow::Borrowed(line);
+        };
+        let entries: Vec<&str> = info.split(';').collect();
+        let kept: Vec<&str> = keep_info
+            .iter()
+            .filter_map(|key| {
+                entries
+                    .iter()
+                    .copied()
+                    .find(|entry| vcf::info_value(entry, key).is_some())
+            })
+            .collect();
+        let pruned = if kept.is_empty() {
+            ".".to_string()
+        } else {
+            kept.join(";")
+        };
+        fields[vcf::INFO] = &pruned;
+        Cow::Owned(fields.join("\t"))
+    }
+}
+
+/// Last position covered by a VCF data line starting at `pos`: its `END` INFO field, or `pos`
+/// plus the length of its `SVLEN` INFO field, or `pos` if it has neither.
+pub fn parse_vcf_end(line: &str, pos: u64) -> u64 {
+    let Some(info) = vcf::info(line) else {
+        return pos;
+    };
+    if let Some(end) = vcf::info_value(info, "END").and_then(|end| end.parse::<u64>().ok()) {
+        return end.max(pos);
+    }
+    // Multi-allelic SVs list one length per alternate allele
+    vcf::info_value(info, "SVLEN")
+        .and_then(|lengths| {
+            lengths
+                .split(',')
+                .filter_map(|length| length.parse::<i64>().ok())
+                .map(i64::unsigned_abs)
+                .max()
+        })
+        .map_or(pos, |length| pos.saturating_add(length))
+}
+
+/// Extracts the `AF` value of the INFO column of a VCF data line, reduced according to
+/// `multi_allelic_af` when there are several alternate alleles.
+/// Missing (`.`) frequencies are ignored.
+pub fn parse_vcf_af(line: &str, multi_allelic_af: MultiAllelicAf) -> Option<f64> {
+    let values = vcf::info_value(vcf::info(line)?, "AF")?;
+    let frequencies = values
+        .split(',')
+        .filter_map(|value| value.parse::<f64>().ok());
+    match multi_allelic_af {
+        MultiAllelicAf::Max => frequencies.reduce(f64::max),
+        MultiAllelicAf::Min => frequencies.reduce(f64::min),
+    }
+}
+
+/// Region check of `filter_vcf_lines`, see `LineFilter::assume_unsorted`.
+enum RegionCheck<I: Iterator<Item = (u64, u64)>> {
+    Sorted(SortedIntervalIntersect<I, u64>),
+    Unsorted(IntervalTree<u64>),
+}
+
+impl<I: Iterator<Item = (u64, u64)>> RegionCheck<I> {
+    fn new(regions: I, line_filter: &LineFilter) -> Self {
+        match line_filter.assume_unsorted {
+            true => RegionCheck::Unsorted(IntervalTree::new(regions.collect())),
+            false => RegionCheck::Sorted(SortedIntervalIntersect::new(regions)),
+        }
+    }
+
+    /// Whether `[start, end)` intersects the regions, `None` if no later record can.
+    fn overlaps(&mut self, start: u64, end: u64) -> Option<bool> {
+        match self {
+            RegionCheck::Sorted(intersection_check) => intersection_check.overlaps(start, end),
+            RegionCheck::Unsorted(tree) => Some(tree.overlaps(start, end)),
+        }
+    }
+
+    /// Whether `pos` falls in the regions, `None` if no later record can.
+    fn in_interval(&mut self, pos: u64) -> Option<bool> {
+        match self {
+            RegionCheck::Sorted(intersection_check) => intersection_check.in_interval(pos),
+            RegionCheck::Unsorted(tree) => Some(tree.contains(pos)),
+        }
+    }
+}
+
+/// Lines read by `filter_vcf_lines`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LineCounts {
+    /// Data lines, kept or not
+    pub records: u64,
+    /// Malformed data lines skipped
+    pub bad_lines: u64,
+    /// Data lines in the regions dropped by the `LineFilter`
+    pub filtered_out: u64,
+}
+
+/// Streams a VCF, passing to `keep` every header line, and every data line whose POS falls in
+/// `regions` along with that POS. `regions` must be sorted, disjoint, half-open intervals of
+/// 1-based positions (see `BEDRecord::vcf_interval`).
+/// Data lines in the regions are only kept if they pass `line_filter`. With `line_filter.sv_aware`,
+/// a data line is in the regions when its span overlaps one of them. With
+/// `line_filter.assume_unsorted`, the data lines do not need to be sorted.
+pub(crate) fn filter_vcf_lines<R, I, F>(
+    mut reader: R,
+    regions: I,
+    line_filter: &LineFilter,
+    mut keep: F,
+) -> Result<LineCounts, DownloadError>
+where
+    R: BufRead,
+    I: Iterator<Item = (u64, u64)>,
+    F: FnMut(&str, Option<u64>) -> Result<(), DownloadError>,
+{
+    let mut intersection_check = RegionCheck::new(regions, line_filter);
+    let mut counts = LineCounts::default();
+
+    // With `trim_header`, the `##contig` and `#CHROM` lines waiting for the first record
+    let mut held_header = line_filter.trim_header.then(Vec::new);
+    // With `line_filter.samples`, the fields kept, once the `#CHROM` line is read
+    let mut sample_fields = None;
+    // Kept records waiting to be sorted, see `LineFilter::assume_unsorted`
+    let mut held_records =
+        (line_filter.assume_unsorted && !line_filter.keep_input_order).then(Vec::new);
+
+    // A single buffer is reused for every line, instead of allocating a `String` per line
+    let mut buffer = String::new();
+    for line_number in 0.. {
+        buffer.clear();
+        let read = reader
+            .read_line(&mut buffer)
+            .map_err(|source| DownloadError::Io {
+                context: "Cannot read VCF stream".to_string(),
+                source,
+            })?;
+        if read == 0 {
+            break;
+        }
+        // CRLF line endings would otherwise leave a `\r` in the last column of every line
+        let line = buffer.strip_suffix('\n').unwrap_or(&buffer);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if vcf::is_header(line) {
+            if !line_filter.keeps_header(line) {
+                continue;
+            }
+            let mut line = Cow::Borrowed(line);
+            if line.starts_with("#CHROM") {
+                sample_fields = line_filter.sample_fields(&line)?;
+                if let Some(fields) = &sample_fields {
+                    // Every field was found in this very line
+                    line = Cow::Owned(vcf::select_fields(&line, fields).unwrap_or_default());
+                }
+            }
+            let line = line.as_ref();
+            match &mut held_header {
+                Some(held_header)
+                    if line.starts_with("#CHROM") || LineFilter::header_contig(line).is_some() =>
+                {
+                    held_header.push(line.to_string())
+                }
+                _ => keep(line, None)?,
+            }
+        } else {
+            if let Some(held_header) = held_header.take() {
+                let chromosome = vcf::field(line, vcf::CHROM).unwrap_or_default();
+                release_header(held_header, Some(chromosome), &mut keep)?;
+            }
+            if line_filter.samples.is_some() && sample_fields.is_none() {
+                return Err(DownloadError::Parse(
+                    "No #CHROM header line before the records, cannot locate the samples"
+                        .to_string(),
+                ));
+            }
+            let pos = match parse_vcf_pos(line) {
+                Ok(pos) => pos,
+                Err(DownloadError::Parse(message))
+                    if line_filter.on_bad_line == BadLinePolicy::Error =>
+                {
+                    return Err(DownloadError::Parse(format!(
+                        "line {}: {}",
+                        line_number + 1,
+                        message
+                    )))
+                }
+                Err(_) => {
+                    counts.bad_lines += 1;
+                    continue;
+                }
+            };
+            counts.records += 1;
+            let intersects = if line_filter.sv_aware {
+                intersection_check.overlaps(pos, parse_vcf_end(line, pos) + 1)
+            } else {
+                intersection_check.in_interval(pos)
+            };
+            if let Some(intersects) = intersects {
+                if intersects && line_filter.keeps(line) {
+                    let mut line = line_filter.prune_info(line);
+                    if let Some(fields) = &sample_fields {
+                        match vcf::select_fields(&line, fields) {
+                            Some(selected) => line = Cow::Owned(selected),
+                            None if line_filter.on_bad_line == BadLinePolicy::Error => {
+                                return Err(DownloadError::Parse(format!(
+                                    "line {}: missing sample columns",
+                                    line_number + 1
+                                )))
+                            }
+                            None => {
+                                counts.bad_lines += 1;
+                                continue;
+                            }
+                        }
+                    }
+                    match &mut held_records {
+                        Some(held_records) => held_records.push((pos, line.into_owned())),
+                        None => keep(&line, Some(pos))?,
+                    }
+                } else if intersects {
+                    counts.filtered_out += 1;
+                }
+            }
+        }
+    }
+    if let Some(held_header) = held_header {
+        release_header(held_header, None, &mut keep)?;
+    }
+    if let Some(mut held_records) = held_records {
+        // Stable, so that records at the same position stay in the order of the VCF
+        held_records.sort_by_key(|(pos, _)| *pos);
+        for (pos, line) in held_records {
+            keep(&line, Some(pos))?;
+        }
+    }
+    Ok(counts)
+}
+
+/// Passes the held back header lines to `keep`, leaving out the `##contig` lines of other
+/// chromosomes than `chromosome` (if known).
+fn release_header<F>(
+    held_header: Vec<String>,
+    chromosome: Option<&str>,
+    keep: &mut F,
+) -> Result<(), DownloadError>
+where
+    F: FnMut(&str, Option<u64>) -> Result<(), DownloadError>,
+{
+    held_header
+        .iter()
+        .filter(|line| {
+            LineFilter::header_contig(line)
+                .is_none_or(|contig| chromosome.is_none_or(|chromosome| contig == chromosome))
+        })
+        .try_for_each(|line| keep(line, None))
+}
+
+/// Copies the header of an uncompressed VCF stream to `writer`, followed by the data lines
+/// falling in `regions` (see `filter_vcf_lines` for the expected coordinates).
+pub fn filter_vcf_stream<R, W, I>(reader: R, mut writer: W, regions: I) -> Result<(), DownloadError>
+where
+    R: BufRead,
+    W: Write,
+    I: Iterator<Item = (u64, u64)>,
+{
+    filter_vcf_lines(reader, regions, &LineFilter::default(), |line, _| {
+        writeln!(writer, "{}", line).map_err(|source| DownloadError::Io {
+            context: "Cannot write VCF stream".to_string(),
+            source,
+        })
+    })?;
+    writer
+        .flush()
+        .map_err(DownloadError::io("Cannot write VCF stream"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_large_vcf_pos() {
+        let line = "chrUn\t4300000001\t.\tA\tG\t.\tPASS\t.";
+        assert_eq!(parse_vcf_pos(line).unwrap(), 4_300_000_001);
+
+        let mut output = Vec::new();
+        filter_vcf_stream(
+            format!("{}\n", line).as_bytes(),
+            &mut output,
+            [(4_300_000_001, 4_300_000_011)].into_iter(),
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), format!("{}\n", line));
+    }
+
+    #[test]
+    fn test_truncated_vcf_line() {
+        let vcf = "#CHROM\tPOS\nchr1\t150\t.\tA\tG\nchr1\nchr1\t160\t.\tC\tT\n";
+        let keep_all = [(1, 1000)];
+
+        match filter_vcf_lines(
+            vcf.as_bytes(),
+            keep_all.into_iter(),
+            &LineFilter::default(),
+            |_, _| Ok(()),
+        ) {
+            Err(DownloadError::Parse(message)) => {
+                assert!(message.starts_with("line 3: "), "{}", message)
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let mut kept = Vec::new();
+        let counts = filter_vcf_lines(
+            vcf.as_bytes(),
+            keep_all.into_iter(),
+            &LineFilter {
+                on_bad_line: BadLinePolicy::Skip,
+                ..Default::default()
+            },
+            |_, pos| {
+                kept.extend(pos);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(kept, vec![150, 160]);
+        assert_eq!(
+            counts,
+            LineCounts {
+                records: 2,
+                bad_lines: 1,
+                filtered_out: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_af_filter() {
+        let line = |info: &str| format!("chr1\t150\t.\tA\tG,T\t.\tPASS\t{}", info);
+        assert_eq!(
+            parse_vcf_af(&line("AC=3;AF=0.25;AN=12"), MultiAllelicAf::Max),
+            Some(0.25)
+        );
+        assert_eq!(
+            parse_vcf_af(&line("AF=0.1,0.4"), MultiAllelicAf::Max),
+            Some(0.4)
+        );
+        assert_eq!(
+            parse_vcf_af(&line("AF=0.1,0.4"), MultiAllelicAf::Min),
+            Some(0.1)
+        );
+        assert_eq!(
+            parse_vcf_af(&line("AF=.,0.4"), MultiAllelicAf::Min),
+            Some(0.4)
+        );
+        // Other keys ending with AF are not the allele frequency
+        assert_eq!(parse_vcf_af(&line("MAF=0.1"), MultiAllelicAf::Max), None);
+
+        let common = LineFilter {
+            min_af: Some(0.2),
+            ..Default::default()
+        };
+        assert!(common.keeps(&line("AF=0.25")));
+        assert!(!common.keeps(&line("AF=0.1")));
+        assert!(common.keeps(&line("AF=0.1,0.4")));
+        assert!(!common.keeps(&line(".")));
+
+        let rare = LineFilter {
+            max_af: Some(0.2),
+            multi_allelic_af: MultiAllelicAf::Min,
+            ..Default::default()
+        };
+        assert!(rare.keeps(&line("AF=0.1,0.4")));
+        assert!(!rare.keeps(&line("AF=0.25")));
+        assert!(LineFilter::default().keeps(&line(".")));
+    }
+
+    #[test]
+    fn test_pass_only() {
+        let vcf = "\
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t150\t.\tA\tG\t.\tPASS\t.
+chr1\t160\t.\tA\tG\t.\tAC0;RF\t.
+chr1\t170\t.\tA\tG\t.\t.\t.
+chr1\t180\t.\tA\tG\t.\tInbreedingCoeff\t.
+chr1\t250\t.\tA\tG\t.\tPASS\t.
+";
+        let mut kept = Vec::new();
+        let counts = filter_vcf_lines(
+            vcf.as_bytes(),
+            [(100, 200)].into_iter(),
+            &LineFilter {
+                pass_only: true,
+                ..Default::default()
+            },
+            |_, pos| {
+                kept.extend(pos);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(kept, vec![150, 170]);
+        assert_eq!(counts.filtered_out, 2);
+    }
+
+    #[test]
+    fn test_keep_info() {
+        let vcf = "\
+##INFO=<ID=AC,Number=A,Type=Integer,Description=\"Allele count\">
+##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">
+##INFO=<ID=AF_nfe,Number=A,Type=Float,Description=\"NFE allele frequency\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t150\trs1\tA\tG\t12.5\tPASS\tAC=3;AF_nfe=0.1;AF=0.25;lcr
+";
+        let filter = |prune_info_header| LineFilter {
+            keep_info: Some(vec!["lcr".to_string(), "AF".to_string(), "DP".to_string()]),
+            prune_info_header,
+            ..Default::default()
+        };
+        let filtered = |line_filter: &LineFilter| {
+            let mut lines = Vec::new();
+            filter_vcf_lines(
+                vcf.as_bytes(),
+                [(100, 200)].into_iter(),
+                line_filter,
+                |line, _| {
+                    lines.push(line.to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+            lines
+        };
+
+        let lines = filtered(&filter(false));
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[4], "chr1\t150\trs1\tA\tG\t12.5\tPASS\tlcr;AF=0.25");
+
+        let lines = filtered(&filter(true));
+        assert_eq!(
+            lines[..2],
+            [vcf.lines().nth(1).unwrap(), vcf.lines().nth(3).unwrap()]
+        );
+        assert_eq!(lines[2], "chr1\t150\trs1\tA\tG\t12.5\tPASS\tlcr;AF=0.25");
+
+        let nothing_left = LineFilter {
+            keep_info: Some(vec!["DP".to_string()]),
+            ..Default::default()
+        };
+        assert!(filtered(&nothing_left)[4].ends_with("\tPASS\t."));
+    }
+
+    #[test]
+    fn test_trim_header() {
+        let vcf = "\
+##fileformat=VCFv4.3
+##contig=<ID=chr1,length=248956422>
+##contig=<ID=chr10,length=133797422>
+##INFO=<ID=AF,Number=A,Type=Float,Description=\"Allele frequency\">
+##contig=<ID=chr2,length=242193529>
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr2\t50\t.\tA\tG\t.\tPASS\tAF=0.5
+chr2\t150\t.\tC\tT\t.\tPASS\tAF=0.1
+";
+        let filtered = |vcf: &str, trim_header| {
+            let mut lines = Vec::new();
+            filter_vcf_lines(
+                vcf.as_bytes(),
+                [(100, 200)].into_iter(),
+                &LineFilter {
+                    trim_header,
+                    ..Default::default()
+                },
+                |line, _| {
+                    lines.push(line.to_string());
+                    Ok(())
+                },
+            )
+            .unwrap();
+            lines
+        };
+
+        let lines: Vec<&str> = vcf.lines().collect();
+        assert_eq!(
+            filtered(vcf, true),
+            [lines[0], lines[3], lines[4], lines[5], lines[7]]
+        );
+        assert_eq!(filtered(vcf, false), [&lines[..6], &lines[7..]].concat());
+
+        // No record to tell the chromosome from
+        let header_only = lines[..6].join("\n");
+        assert_eq!(
+            filtered(&header_only, true),
+            [lines[0], lines[3], lines[1], lines[2], lines[4], lines[5]]
+        );
+    }
+
+    #[test]
+    fn test_sv_straddling_region() {
+        let vcf = "\
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t900\t.\tN\t<DEL>\t.\tPASS\tSVTYPE=DEL;END=1000
+chr1\t950\t.\tN\t<DEL>\t.\tPASS\tSVTYPE=DEL;SVLEN=-40
+chr1\t960\t.\tN\t<DEL>\t.\tPASS\tSVTYPE=DEL;SVLEN=-45
+chr1\t1500\t.\tA\tG\t.\tPASS\t.
+chr1\t1900\t.\tN\t<DEL>\t.\tPASS\tSVTYPE=DEL;END=2500
+chr1\t2100\t.\tN\t<DEL>\t.\tPASS\tSVTYPE=DEL;END=2200
+";
+        let kept_positions = |sv_aware| {
+            let mut kept = Vec::new();
+            filter_vcf_lines(
+                vcf.as_bytes(),
+                [(1000, 2000)].into_iter(),
+                &LineFilter {
+                    sv_aware,
+                    ..Default::default()
+                },
+                |_, pos| {
+                    kept.extend(pos);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            kept
+        };
+        assert_eq!(kept_positions(false), [1500, 1900]);
+        assert_eq!(kept_positions(true), [900, 960, 1500, 1900]);
+
+        assert_eq!(
+            parse_vcf_end("chr1\t900\t.\tN\t<DEL>\t.\tPASS\tEND=1000", 900),
+            1000
+        );
+        assert_eq!(
+            parse_vcf_end("chr1\t900\t.\tN\t<INS>\t.\tPASS\tSVLEN=30,-50", 900),
+            950
+        );
+        assert_eq!(parse_vcf_end("chr1\t900\t.\tA\tG\t.\tPASS\t.", 900), 900);
+    }
+
+    #[test]
+    fn test_filtering_matches_line_by_line_reference() {
+        let mut vcf =
+            String::from("##fileformat=VCFv4.3\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n");
+        for pos in (1..50_000u64).step_by(7) {
+            vcf.push_str(&format!(
+                "chr1\t{}\t.\tA\tG\t.\tPASS\tAF=0.{}\n",
+                pos,
+                pos % 10
+            ));
+        }
+        // The last line may lack its line feed
+        vcf.push_str("chr1\t49999\t.\tA\tG\t.\tPASS\t.");
+        let regions = [(100, 2000), (30_000, 30_500), (49_990, 50_000)];
+
+        let mut kept = Vec::new();
+        filter_vcf_lines(
+            vcf.as_bytes(),
+            regions.into_iter(),
+            &LineFilter::default(),
+            |line, _| {
+                kept.push(line.to_string());
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let expected: Vec<String> = vcf
+            .lines()
+            .filter(|line| {
+                line.starts_with('#') || {
+                    let pos: u64 = line.split('\t').nth(1).unwrap().parse().unwrap();
+                    regions
+                        .iter()
+                        .any(|&(start, end)| start <= pos && pos < end)
+                }
+            })
+            .map(str::to_string)
+            .collect();
+        assert_eq!(kept, expected);
+        assert_eq!(kept.last().unwrap(), "chr1\t49999\t.\tA\tG\t.\tPASS\t.");
+    }
+
+    #[test]
+    fn test_crlf_vcf() {
+        let vcf = "##fileformat=VCFv4.3\r\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\r\n\
+                   chr1\t150\t.\tA\tG\t.\tPASS\tAF=0.5\r\nchr1\t250\t.\tC\tT\t.\tPASS\t.\r\n\
+                   chr1\t260\r\n";
+        let mut output = Vec::new();
+        filter_vcf_stream(
+            vcf.as_bytes(),
+            &mut output,
+            [(100, 200), (260, 261)].into_iter(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "##fileformat=VCFv4.3\n#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+             chr1\t150\t.\tA\tG\t.\tPASS\tAF=0.5\nchr1\t260\n"
+        );
+
+        let common = LineFilter {
+            min_af: Some(0.4),
+            ..Default::default()
+        };
+        let mut kept = Vec::new();
+        filter_vcf_lines(
+            vcf.as_bytes(),
+            [(1, 1000)].into_iter(),
+            &common,
+            |_, pos| {
+                kept.extend(pos);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(kept, [150]);
+    }
+
+    #[test]
+    fn test_unsorted_vcf() {
+        let vcf = "\
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t1500\ta\tA\tG\t.\tPASS\t.
+chr1\t150\tb\tA\tG\t.\tPASS\t.
+chr1\t50\tc\tA\tG\t.\tPASS\t.
+chr1\t1100\td\tA\tG\t.\tPASS\t.
+chr1\t150\te\tA\tG\t.\tPASS\t.
+";
+        let kept_ids = |line_filter: LineFilter| {
+            let mut kept = Vec::new();
+            filter_vcf_lines(
+                vcf.as_bytes(),
+                [(100, 200), (1000, 2000)].into_iter(),
+                &line_filter,
+                |line, pos| {
+                    if pos.is_some() {
+                        kept.push(vcf::field(line, 2).unwrap().to_string());
+                    }
+                    Ok(())
+                },
+            )
+            .unwrap();
+            kept
+        };
+
+        // Walking the regions along with the records moves past (100, 200) at the first record
+        // (debug builds panic on the decreasing position instead)
+        let streamed = std::panic::catch_unwind(|| kept_ids(LineFilter::default()));
+        assert!(streamed.is_err() || streamed.unwrap() == ["a", "d"]);
+
+        let unsorted = LineFilter {
+            assume_unsorted: true,
+            ..Default::default()
+        };
+        assert_eq!(kept_ids(unsorted.clone()), ["b", "e", "d", "a"]);
+        assert_eq!(
+            kept_ids(LineFilter {
+                keep_input_order: true,
+                ..unsorted
+            }),
+            ["a", "b", "d", "e"]
+        );
+    }
+
+    #[test]
+    fn test_samples() {
+        let vcf = "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tNA1\tNA2\tNA3
+chr1\t150\t.\tA\tG\t.\tPASS\t.\tGT:DP\t0/1:10\t1/1:20\t0/0:30
+chr1\t250\t.\tC\tT\t.\tPASS\t.\tGT:DP\t0/0:11\t0/1:21\t1/1:31
+";
+        let filter = |samples: &[&str]| {
+            let mut kept = Vec::new();
+            filter_vcf_lines(
+                vcf.as_bytes(),
+                [(100, 200)].into_iter(),
+                &LineFilter {
+                    samples: Some(samples.iter().map(|s| s.to_string()).collect()),
+                    ..Default::default()
+                },
+                |line, _| {
+                    kept.push(line.to_string());
+                    Ok(())
+                },
+            )
+            .map(|_| kept)
+        };
+
+        assert_eq!(
+            filter(&["NA2"]).unwrap(),
+            [
+                "##fileformat=VCFv4.3",
+                "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tNA2",
+                "chr1\t150\t.\tA\tG\t.\tPASS\t.\tGT:DP\t1/1:20",
+            ]
+        );
+        assert_eq!(
+            filter(&["NA3", "NA1"]).unwrap()[2],
+            "chr1\t150\t.\tA\tG\t.\tPASS\t.\tGT:DP\t0/0:30\t0/1:10"
+        );
+        match filter(&["NA4"]) {
+            Err(DownloadError::Parse(message)) => assert!(message.contains("NA4"), "{}", message),
+            other => panic!("Expected an unknown sample error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use noodles::bgzf::VirtualPosition;
+use noodles::core::Position;
+use noodles::csi;
+use noodles::csi::binning_index::index::header;
+use noodles::csi::binning_index::index::reference_sequence::bin::Chunk;
+use noodles::csi::binning_index::index::reference_sequence::index::BinnedIndex;
+use noodles::tabix;
+
+use crate::error::DownloadError;
+use crate::vcf;
+
+/// Size of the smallest bin, shared by tabix and the CSI indexes written here.
+const MIN_SHIFT: u8 = 14;
+/// Depth of a tabix index, which cannot index positions beyond 2^29.
+const TBI_DEPTH: u8 = 5;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexFormat {
+    /// Tabix, unless a position is too large for it, in which case CSI is used
+    #[default]
+    Auto,
+    /// Tabix (`.tbi`), limited to positions below 2^29
+    Tbi,
+    /// CSI (`.csi`), with a depth chosen to fit the largest position
+    Csi,
+}
+
+/// Collects the data lines of a bgzf VCF while they are being written, to index it once complete.
+pub struct VcfIndexer {
+    format: IndexFormat,
+    chromosomes: Vec<String>,
+    records: Vec<(usize, Position, Position, Chunk)>,
+}
+
+impl VcfIndexer {
+    pub fn new(format: IndexFormat) -> Self {
+        VcfIndexer {
+            format,
+            chromosomes: Vec::new(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Records a data line starting at `pos` that was written between the `start` and `end` virtual positions.
+    /// The indexed span goes from `pos` to the last base of the REF allele.
+    pub fn add_line(
+        &mut self,
+        line: &str,
+        pos: u64,
+        start: VirtualPosition,
+        end: VirtualPosition,
+    ) -> Result<(), DownloadError> {
+        let chromosome = vcf::field(line, vcf::CHROM).unwrap_or_default();
+        let ref_length = vcf::field(line, vcf::REF).map_or(1, |r| r.len().max(1)) as u64;
+
+        let to_position = |value: u64| {
+            usize::try_from(value.max(1))
+                .ok()
+                .and_then(|value| Position::try_from(value).ok())
+                .ok_or_else(|| {
+                    DownloadError::Parse(format!("{}: position {} cannot be indexed", line, value))
+                })
+        };
+        let first = to_position(pos)?;
+        let last = to_position(pos + ref_length - 1)?;
+
+        if self.chromosomes.last().map(String::as_str) != Some(chromosome) {
+            if self.chromosomes.iter().any(|c| c == chromosome) {
+                return Err(DownloadError::Parse(format!(
+                    "{}: records of chromosome {} are not contiguous, cannot index",
+                    line, chromosome
+                )));
+            }
+            self.chromosomes.push(chromosome.to_string());
+        }
+        self.records.push((
+            self.chromosomes.len() - 1,
+            first,
+            last,
+            Chunk::new(start, end),
+        ));
+        Ok(())
+    }
+
+    /// Smallest depth whose bins cover every recorded position, given `MIN_SHIFT`.
+    fn required_depth(&self) -> u8 {
+        let max_position = self
+            .records
+            .iter()
+            .map(|(_, _, end, _)| usize::from(*end) as u64)
+            .max()
+            .unwrap_or(0);
+        let bits = (u64::BITS - max_position.leading_zeros()) as u8;
+        bits.saturating_sub(MIN_SHIFT).div_ceil(3).max(TBI_DEPTH)
+    }
+
+    /// Writes the index next to `vcf_path`, returning the path of the written index.
+    pub fn write(self, vcf_path: &Path) -> Result<PathBuf, DownloadError> {
+        let depth = self.required_depth();
+        let format = match self.format {
+            IndexFormat::Auto if depth > TBI_DEPTH => IndexFormat::Csi,
+            IndexFormat::Auto => IndexFormat::Tbi,
+            IndexFormat::Tbi if depth > TBI_DEPTH => {
+                return Err(DownloadError::Parse(format!(
+                    "{}: positions are too large for a tabix index, use a CSI index instead",
+                    vcf_path.display()
+                )))
+            }
+            format => format,
+        };
+
+        let mut index_path = vcf_path.as_os_str().to_owned();
+        let result = if format == IndexFormat::Csi {
+            index_path.push(".csi");
+            csi::fs::write(&index_path, &self.build_csi(depth))
+        } else {
+            index_path.push(".tbi");
+            tabix::fs::write(&index_path, &self.build_tabix())
+        };
+        let index_path = PathBuf::from(index_path);
+        result.map_err(DownloadError::io(format!(
+            "Cannot write index {}",
+            index_path.display()
+        )))?;
+        Ok(index_path)
+    }
+
+    fn build_tabix(self) -> tabix::Index {
+        let mut indexer = tabix::index::Indexer::default();
+        indexer.set_header(header::Builder::vcf().build());
+        for (id, start, end, chunk) in self.records {
+            indexer
+                .add_record(&self.chromosomes[id], start, end, chunk)
+                .expect("Chromosomes are contiguous");
+        }
+        indexer.build()
+    }
+
+    fn build_csi(self, depth: u8) -> csi::Index {
+        let header = header::Builder::vcf()
+            .set_reference_sequence_names(
+                self.chromosomes
+                    .iter()
+                    .map(|name| name.as_str().into())
+                    .collect(),
+            )
+            .build();
+        let mut indexer =
+            csi::binning_index::Indexer::<BinnedIndex>::new(MIN_SHIFT, depth).set_header(header);
+        for (id, start, end, chunk) in self.records {
+            indexer
+                .add_record(Some((id, start, end, true)), chunk)
+                .expect("Chromosomes are contiguous");
+        }
+        indexer.build(self.chromosomes.len())
+    }
+}
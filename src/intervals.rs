@@ -0,0 +1,703 @@
+use std::collections::VecDeque;
+
+/// Tells whether increasing positions fall in a sorted list of disjoint half-open intervals.
+///
+/// Queries consume the intervals they have moved past: an interval ending before a query is
+/// never looked at again. Positions given to `in_interval`, as well as the starts given to
+/// `overlaps`, must therefore never decrease from one query to the next, or intervals that
+/// should match are missed. Ends of `overlaps` queries do not need to be ordered, so spans
+/// sorted by start only (like VCF structural variants) can be queried. Debug builds panic on
+/// a query before the previous one, rather than silently answering wrong.
+///
+/// Any coordinate type with `PartialOrd` works, e.g. `u64` positions beyond 2^32:
+///
+/// ```
+/// use smart_gnomad_downloader::SortedIntervalIntersect;
+///
+/// let regions = vec![(100u64, 200), (5_000_000_000, 5_000_000_100)];
+/// let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+/// assert_eq!(intersection_check.in_interval(50), Some(false));
+/// assert_eq!(intersection_check.in_interval(150), Some(true));
+/// assert_eq!(intersection_check.overlaps(4_999_999_990, 5_000_000_001), Some(true));
+/// // Past the last interval, nothing can match anymore
+/// assert_eq!(intersection_check.in_interval(5_000_000_100), None);
+/// ```
+pub struct SortedIntervalIntersect<I, T>
+where
+    I: Iterator<Item = (T, T)>,
+{
+    intervals: I,
+    current_interval: Option<(T, T)>,
+    /// Position (or start) of the previous query
+    last_query: Option<T>,
+}
+
+impl<I, T> SortedIntervalIntersect<I, T>
+where
+    I: Iterator<Item = (T, T)>,
+    T: PartialOrd + Copy,
+{
+    /// Checks positions against `intervals`, which must be sorted, disjoint and half-open.
+    pub fn new(intervals: I) -> Self {
+        Self {
+            intervals,
+            current_interval: None,
+            last_query: None,
+        }
+    }
+
+    /// Records `value` as the latest query, checking in debug builds that queries never go back.
+    fn check_query(&mut self, value: T) {
+        debug_assert!(
+            self.last_query.is_none_or(|last_query| last_query <= value),
+            "SortedIntervalIntersect queried before its previous query: queries must not decrease"
+        );
+        self.last_query = Some(value);
+    }
+
+    /// Whether `value` falls in one of the intervals, `None` once they are exhausted (no
+    /// later position can match either).
+    pub fn in_interval(&mut self, value: T) -> Option<bool> {
+        self.check_query(value);
+        if self.current_interval.is_none() {
+            self.current_interval = self.intervals.next();
+        }
+        loop {
+            if let Some(ref current_interval) = self.current_interval {
+                if value < current_interval.0 {
+                    return Some(false);
+                }
+                if value < current_interval.1 {
+                    return Some(true);
+                }
+                self.current_interval = self.intervals.next();
+            } else {
+                return None;
+            }
+        }
+    }
+
+    /// Whether the half-open interval `[start, end)` intersects one of the intervals, `None`
+    /// once they are exhausted. Only `start` needs to be non-decreasing from one query to the
+    /// next (and may repeat), whatever the `end`: a long query does not consume the intervals
+    /// it covers, so the following queries still see them.
+    pub fn overlaps(&mut self, start: T, end: T) -> Option<bool> {
+        self.check_query(start);
+        if self.current_interval.is_none() {
+            self.current_interval = self.intervals.next();
+        }
+        loop {
+            let current_interval = self.current_interval.as_ref()?;
+            if start < current_interval.1 {
+                return Some(current_interval.0 < end);
+            }
+            self.current_interval = self.intervals.next();
+        }
+    }
+}
+
+/// Collects the intervals of a sorted list of disjoint half-open intervals that no recorded
+/// span intersects. As with `SortedIntervalIntersect`, the starts of the recorded spans must
+/// never decrease, while their ends may be in any order.
+pub struct EmptyIntervals<I, T>
+where
+    I: Iterator<Item = (T, T)>,
+{
+    intervals: I,
+    /// Intervals read from `intervals` that spans may still intersect, and whether one did
+    pending: VecDeque<((T, T), bool)>,
+    empty: Vec<(T, T)>,
+}
+
+impl<I, T> EmptyIntervals<I, T>
+where
+    I: Iterator<Item = (T, T)>,
+    T: PartialOrd + Copy,
+{
+    pub fn new(intervals: I) -> Self {
+        Self {
+            intervals,
+            pending: VecDeque::new(),
+            empty: Vec::new(),
+        }
+    }
+
+    /// Marks the intervals intersecting `[start, end)` as not empty.
+    pub fn record(&mut self, start: T, end: T) {
+        // Intervals ending before `start` cannot be intersected anymore
+        loop {
+            if self.pending.is_empty() {
+                match self.intervals.next() {
+                    Some(interval) => self.pending.push_back((interval, false)),
+                    None => return,
+                }
+            }
+            let (interval, intersected) = self.pending[0];
+            if start < interval.1 {
+                break;
+            }
+            self.pending.pop_front();
+            if !intersected {
+                self.empty.push(interval);
+            }
+        }
+        let mut index = 0;
+        loop {
+            if index == self.pending.len() {
+                match self.intervals.next() {
+                    Some(interval) => self.pending.push_back((interval, false)),
+                    None => return,
+                }
+            }
+            let (interval, intersected) = &mut self.pending[index];
+            if end <= interval.0 {
+                return;
+            }
+            *intersected = true;
+            index += 1;
+        }
+    }
+
+    /// The intervals no span intersected, in order.
+    pub fn finish(mut self) -> Vec<(T, T)> {
+        self.empty.extend(
+            self.pending
+                .into_iter()
+                .filter(|(_, intersected)| !intersected)
+                .map(|(interval, _)| interval),
+        );
+        self.empty.extend(self.intervals);
+        self.empty
+    }
+}
+
+/// Tells whether positions, in any order, fall in a set of half-open intervals, which may be
+/// unsorted or overlap. Unlike `SortedIntervalIntersect`, nothing is consumed by a query, so
+/// records of an unsorted VCF can be checked one by one, for `O(log n)` each, and the
+/// intervals matching a query can be listed.
+///
+/// ```
+/// use smart_gnomad_downloader::IntervalTree;
+///
+/// let tree = IntervalTree::new(vec![(400u64, 1000), (100, 200)]);
+/// assert!(tree.contains(450));
+/// assert!(tree.contains(150));
+/// assert!(!tree.contains(200));
+/// assert!(tree.overlaps(150, 450));
+/// assert!(!tree.overlaps(200, 400));
+/// assert_eq!(tree.overlapping(150, 450), vec![(100, 200), (400, 1000)]);
+/// ```
+pub struct IntervalTree<T> {
+    /// Intervals sorted by start, the nodes of a balanced binary tree: the node of a range of
+    /// indices is the middle one, whose subtrees are the halves on each side
+    intervals: Vec<(T, T)>,
+    /// Greatest end in the subtree of each node
+    max_ends: Vec<T>,
+}
+
+impl<T> IntervalTree<T>
+where
+    T: PartialOrd + Copy,
+{
+    pub fn new(mut intervals: Vec<(T, T)>) -> Self {
+        intervals.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut max_ends: Vec<T> = intervals.iter().map(|interval| interval.1).collect();
+        Self::compute_max_ends(&mut max_ends, 0, intervals.len());
+        Self {
+            intervals,
+            max_ends,
+        }
+    }
+
+    /// Sets the max end of the subtree of `lo..hi` on its node, returning it.
+    fn compute_max_ends(max_ends: &mut [T], lo: usize, hi: usize) -> Option<T> {
+        if lo >= hi {
+            return None;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let subtrees = [
+            Self::compute_max_ends(max_ends, lo, mid),
+            Self::compute_max_ends(max_ends, mid + 1, hi),
+        ];
+        for end in subtrees.into_iter().flatten() {
+            if end > max_ends[mid] {
+                max_ends[mid] = end;
+            }
+        }
+        Some(max_ends[mid])
+    }
+
+    /// Whether `value` falls in one of the intervals.
+    pub fn contains(&self, value: T) -> bool {
+        self.find_in(
+            0,
+            self.intervals.len(),
+            value,
+            &|start| start <= value,
+            &mut |_| true,
+        )
+    }
+
+    /// Whether the half-open interval `[start, end)` intersects one of the intervals.
+    pub fn overlaps(&self, start: T, end: T) -> bool {
+        self.find_in(0, self.intervals.len(), start, &|s| s < end, &mut |_| true)
+    }
+
+    /// The intervals `value` falls in, sorted by start, in `O(log n + k)` for `k` of them.
+    pub fn containing(&self, value: T) -> Vec<(T, T)> {
+        let mut found = Vec::new();
+        self.find_in(
+            0,
+            self.intervals.len(),
+            value,
+            &|start| start <= value,
+            &mut |interval| {
+                found.push(interval);
+                false
+            },
+        );
+        found
+    }
+
+    /// The intervals intersecting the half-open interval `[start, end)`, sorted by start, in
+    /// `O(log n + k)` for `k` of them.
+    pub fn overlapping(&self, start: T, end: T) -> Vec<(T, T)> {
+        let mut found = Vec::new();
+        self.find_in(
+            0,
+            self.intervals.len(),
+            start,
+            &|s| s < end,
+            &mut |interval| {
+                found.push(interval);
+                false
+            },
+        );
+        found
+    }
+
+    /// Passes to `found`, by increasing start, the intervals of the subtree of `lo..hi` ending
+    /// after `after` whose start passes `starts_before`, which holds for the starts up to some
+    /// point. Stops as soon as `found` returns `true`, returning `true` as well.
+    fn find_in<S, F>(
+        &self,
+        lo: usize,
+        hi: usize,
+        after: T,
+        starts_before: &S,
+        found: &mut F,
+    ) -> bool
+    where
+        S: Fn(T) -> bool,
+        F: FnMut((T, T)) -> bool,
+    {
+        if lo >= hi {
+            return false;
+        }
+        let mid = lo + (hi - lo) / 2;
+        // Nothing in this subtree ends late enough
+        if self.max_ends[mid] <= after {
+            return false;
+        }
+        if self.find_in(lo, mid, after, starts_before, found) {
+            return true;
+        }
+        let (start, end) = self.intervals[mid];
+        // The intervals on the right start even later
+        if !starts_before(start) {
+            return false;
+        }
+        (end > after && found((start, end)))
+            || self.find_in(mid + 1, hi, after, starts_before, found)
+    }
+}
+
+/// Coalesces sorted (by start) half-open intervals so that overlapping or touching ones,
+/// like `(100, 200)` and `(200, 300)`, become a single interval.
+pub fn merge_intervals<T>(sorted_intervals: Vec<(T, T)>) -> Vec<(T, T)>
+where
+    T: PartialOrd + Copy,
+{
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(sorted_intervals.len());
+    for interval in sorted_intervals {
+        match merged.last_mut() {
+            Some(last) if interval.0 <= last.1 => {
+                if interval.1 > last.1 {
+                    last.1 = interval.1;
+                }
+            }
+            _ => merged.push(interval),
+        }
+    }
+    merged
+}
+
+/// Set difference of sorted and merged half-open intervals (see `merge_intervals`): the parts
+/// of `intervals` outside of every interval of `excluded`, sorted. An excluded interval inside
+/// an interval splits it in two, while one only touching it, like `(200, 300)` for
+/// `(100, 200)`, leaves it whole.
+pub fn subtract_intervals<T>(intervals: Vec<(T, T)>, excluded: &[(T, T)]) -> Vec<(T, T)>
+where
+    T: PartialOrd + Copy,
+{
+    let mut remaining = Vec::with_capacity(intervals.len());
+    let mut excluded = excluded.iter().peekable();
+    for (mut start, end) in intervals {
+        // Excluded intervals ending before this one cannot overlap the next ones either,
+        // while one going past its end may overlap the next one too
+        while excluded
+            .next_if(|(_, excluded_end)| *excluded_end <= start)
+            .is_some()
+        {}
+        for &(excluded_start, excluded_end) in excluded.clone() {
+            if excluded_start >= end {
+                break;
+            }
+            if excluded_start > start {
+                remaining.push((start, excluded_start));
+            }
+            if excluded_end > start {
+                start = excluded_end;
+            }
+        }
+        if start < end {
+            remaining.push((start, end));
+        }
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intersection_check() {
+        let regions = [(100u64, 200u64), (400u64, 1000u64)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+
+        assert_eq!(intersection_check.in_interval(10), Some(false));
+        assert_eq!(intersection_check.in_interval(150), Some(true));
+        assert_eq!(intersection_check.in_interval(151), Some(true));
+        assert_eq!(intersection_check.in_interval(220), Some(false));
+        assert_eq!(intersection_check.in_interval(450), Some(true));
+        assert_eq!(intersection_check.in_interval(460), Some(true));
+        assert_eq!(intersection_check.in_interval(1100), None);
+        assert_eq!(intersection_check.in_interval(2000), None);
+        assert_eq!(intersection_check.in_interval(3000), None);
+    }
+
+    #[test]
+    fn test_interval_tree() {
+        // Unsorted and overlapping
+        let tree = IntervalTree::new(vec![(400u64, 1000u64), (100, 200), (150, 300), (900, 901)]);
+        for value in [100, 199, 250, 299, 400, 900, 999] {
+            assert!(tree.contains(value), "{}", value);
+        }
+        for value in [0, 99, 300, 399, 1000, 5000] {
+            assert!(!tree.contains(value), "{}", value);
+        }
+        // Queried in any order
+        assert!(tree.contains(150));
+        assert!(tree.overlaps(50, 101));
+        assert!(tree.overlaps(299, 5000));
+        assert!(tree.overlaps(0, 5000));
+        assert!(!tree.overlaps(300, 400));
+        assert!(!tree.overlaps(50, 100));
+        assert!(!tree.overlaps(1000, 2000));
+
+        let empty = IntervalTree::<u64>::new(Vec::new());
+        assert!(!empty.contains(1));
+        assert!(!empty.overlaps(0, u64::MAX));
+
+        let tree = IntervalTree::new(vec![(-100i64, -10i64), (0, 10)]);
+        assert!(tree.contains(-50));
+        assert!(!tree.contains(-5));
+    }
+
+    /// Xorshift generator, so that the random interval sets are the same on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    #[test]
+    fn test_interval_tree_matches_brute_force() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for _ in 0..500 {
+            let size = random.below(40);
+            let intervals: Vec<(u64, u64)> = (0..size)
+                .map(|_| {
+                    let start = random.below(1000);
+                    (start, start + random.below(100))
+                })
+                .collect();
+            let tree = IntervalTree::new(intervals.clone());
+            let mut sorted = intervals.clone();
+            sorted.sort();
+
+            for _ in 0..50 {
+                let value = random.below(1100);
+                let expected: Vec<_> = sorted
+                    .iter()
+                    .copied()
+                    .filter(|&(start, end)| start <= value && value < end)
+                    .collect();
+                assert_eq!(
+                    tree.contains(value),
+                    !expected.is_empty(),
+                    "{:?}",
+                    intervals
+                );
+                let mut containing = tree.containing(value);
+                containing.sort();
+                assert_eq!(containing, expected, "{} in {:?}", value, intervals);
+
+                let start = random.below(1100);
+                let end = start + random.below(200);
+                let expected: Vec<_> = sorted
+                    .iter()
+                    .copied()
+                    .filter(|&(s, e)| s < end && start < e)
+                    .collect();
+                assert_eq!(tree.overlaps(start, end), !expected.is_empty());
+                let mut overlapping = tree.overlapping(start, end);
+                overlapping.sort();
+                assert_eq!(
+                    overlapping,
+                    expected,
+                    "{:?} in {:?}",
+                    (start, end),
+                    intervals
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_coordinate_types() {
+        let regions = [(4_294_967_296u64, 4_294_967_396u64)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+        assert_eq!(intersection_check.in_interval(4_294_967_295), Some(false));
+        assert_eq!(intersection_check.in_interval(4_294_967_296), Some(true));
+        assert_eq!(intersection_check.in_interval(4_294_967_396), None);
+
+        let regions = [(-100i64, -10i64), (0, 10)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+        assert_eq!(intersection_check.in_interval(-200), Some(false));
+        assert_eq!(intersection_check.in_interval(-50), Some(true));
+        assert_eq!(intersection_check.in_interval(-5), Some(false));
+        assert_eq!(intersection_check.in_interval(5), Some(true));
+
+        let regions = [(10usize, 20usize)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+        assert_eq!(intersection_check.overlaps(5, 11), Some(true));
+        assert_eq!(intersection_check.in_interval(20), None);
+
+        assert_eq!(
+            merge_intervals(vec![(-5i64, 0), (0, 5), (10, 20)]),
+            [(-5, 5), (10, 20)]
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "queries must not decrease")]
+    fn test_decreasing_queries() {
+        let regions = [(100u64, 200u64), (400u64, 1000u64)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+        assert_eq!(intersection_check.in_interval(450), Some(true));
+        assert_eq!(intersection_check.in_interval(450), Some(true));
+        // Would wrongly be outside of the regions, (100, 200) having been consumed
+        intersection_check.in_interval(150);
+    }
+
+    #[test]
+    fn test_empty_intervals() {
+        let regions = [
+            (100u64, 200u64),
+            (300, 400),
+            (500, 600),
+            (700, 800),
+            (900, 1000),
+        ];
+        let mut empty_intervals = EmptyIntervals::new(regions.into_iter());
+        empty_intervals.record(150, 151);
+        empty_intervals.record(160, 161);
+        // A span starting before an interval, and another covering several ones
+        empty_intervals.record(450, 520);
+        empty_intervals.record(550, 950);
+        assert_eq!(empty_intervals.finish(), [(300, 400)]);
+
+        let mut empty_intervals = EmptyIntervals::new(regions.into_iter());
+        empty_intervals.record(250, 260);
+        empty_intervals.record(600, 601);
+        assert_eq!(empty_intervals.finish(), regions);
+
+        assert_eq!(EmptyIntervals::new(regions.into_iter()).finish(), regions);
+    }
+
+    #[test]
+    fn test_overlapping_queries() {
+        let regions = [(100u64, 200u64), (400u64, 1000u64)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+
+        assert_eq!(intersection_check.overlaps(50, 150), Some(true));
+        // A query spanning several intervals does not consume them
+        assert_eq!(intersection_check.overlaps(150, 2000), Some(true));
+        assert_eq!(intersection_check.overlaps(150, 160), Some(true));
+        assert_eq!(intersection_check.overlaps(150, 160), Some(true));
+        assert_eq!(intersection_check.overlaps(199, 450), Some(true));
+        assert_eq!(intersection_check.overlaps(500, 501), Some(true));
+        assert_eq!(intersection_check.overlaps(999, 1200), Some(true));
+        assert_eq!(intersection_check.overlaps(1000, 1200), None);
+    }
+
+    #[test]
+    fn test_adjacent_queries() {
+        let regions = [(100u64, 200u64), (400u64, 1000u64)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+
+        // Intervals are half-open: touching an interval is not overlapping it
+        assert_eq!(intersection_check.overlaps(50, 100), Some(false));
+        assert_eq!(intersection_check.overlaps(50, 101), Some(true));
+        assert_eq!(intersection_check.overlaps(200, 400), Some(false));
+        assert_eq!(intersection_check.overlaps(200, 401), Some(true));
+        assert_eq!(intersection_check.overlaps(1000, 1001), None);
+    }
+
+    #[test]
+    fn test_disjoint_queries() {
+        let regions = [(100u64, 200u64), (400u64, 1000u64)];
+        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
+
+        assert_eq!(intersection_check.overlaps(10, 20), Some(false));
+        assert_eq!(intersection_check.overlaps(250, 300), Some(false));
+        assert_eq!(intersection_check.overlaps(300, 350), Some(false));
+        assert_eq!(intersection_check.overlaps(2000, 3000), None);
+        assert_eq!(
+            SortedIntervalIntersect::new(std::iter::empty::<(u64, u64)>()).overlaps(1, 2),
+            None
+        );
+    }
+
+    #[test]
+    fn test_merge_nested_intervals() {
+        assert_eq!(
+            merge_intervals(vec![(100u64, 500u64), (150, 200), (300, 400)]),
+            vec![(100, 500)]
+        );
+    }
+
+    #[test]
+    fn test_merge_overlapping_intervals() {
+        assert_eq!(
+            merge_intervals(vec![(100u64, 200u64), (150, 300), (400, 500)]),
+            vec![(100, 300), (400, 500)]
+        );
+    }
+
+    #[test]
+    fn test_merge_touching_intervals() {
+        assert_eq!(
+            merge_intervals(vec![(100u64, 200u64), (200, 300), (301, 400)]),
+            vec![(100, 300), (301, 400)]
+        );
+    }
+
+    #[test]
+    fn test_overlap_after_merge() {
+        let mut intersection_check = SortedIntervalIntersect::new(
+            merge_intervals(vec![(100u64, 200u64), (150, 300)]).into_iter(),
+        );
+        assert_eq!(intersection_check.in_interval(120), Some(true));
+        assert_eq!(intersection_check.in_interval(180), Some(true));
+        assert_eq!(intersection_check.in_interval(250), Some(true));
+        assert_eq!(intersection_check.in_interval(300), None);
+    }
+
+    #[test]
+    fn test_subtract_inner_interval() {
+        // Split in two, the excluded bounds being left out of the pieces
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 500u64)], &[(200, 300)]),
+            vec![(100, 200), (300, 500)]
+        );
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 500u64)], &[(150, 200), (300, 400)]),
+            vec![(100, 150), (200, 300), (400, 500)]
+        );
+    }
+
+    #[test]
+    fn test_subtract_partial_overlaps() {
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64)], &[(50, 150)]),
+            vec![(150, 200)]
+        );
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64)], &[(150, 250)]),
+            vec![(100, 150)]
+        );
+        // Sharing a bound
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64)], &[(100, 150)]),
+            vec![(150, 200)]
+        );
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64)], &[(150, 200)]),
+            vec![(100, 150)]
+        );
+    }
+
+    #[test]
+    fn test_subtract_covering_interval() {
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64)], &[(100, 200)]),
+            vec![]
+        );
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64), (300, 400), (500, 600)], &[(50, 450)]),
+            vec![(500, 600)]
+        );
+        // One excluded interval cutting the end of an interval and the start of the next
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64), (300, 400)], &[(150, 350)]),
+            vec![(100, 150), (350, 400)]
+        );
+    }
+
+    #[test]
+    fn test_subtract_touching_intervals() {
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64)], &[(50, 100), (200, 300)]),
+            vec![(100, 200)]
+        );
+        assert_eq!(
+            subtract_intervals(vec![(100u64, 200u64), (200u64, 300u64)], &[(200, 201)]),
+            vec![(100, 200), (201, 300)]
+        );
+    }
+
+    #[test]
+    fn test_subtract_disjoint_intervals() {
+        let intervals = vec![(100u64, 200u64), (400, 500)];
+        assert_eq!(
+            subtract_intervals(intervals.clone(), &[(10, 20), (250, 350), (600, 700)]),
+            intervals
+        );
+        assert_eq!(subtract_intervals(intervals.clone(), &[]), intervals);
+        assert_eq!(subtract_intervals(Vec::new(), &[(100u64, 200u64)]), vec![]);
+        // Other coordinate types
+        assert_eq!(
+            subtract_intervals(vec![(-10i64, 10i64)], &[(-5, 5)]),
+            vec![(-10, -5), (5, 10)]
+        );
+    }
+}
@@ -0,0 +1,30 @@
+//! Downloads gnomAD-like bgzf VCFs while only keeping the records that intersect a BED file.
+//!
+//! The filtering itself does not depend on the network: `filter_vcf_stream` works on any
+//! uncompressed VCF stream, while `smart_save_vcf_from_url` adds downloading, checksum
+//! verification and bgzf output on top of it.
+
+pub mod checksum;
+mod chunked;
+pub mod download;
+pub mod error;
+pub mod filter;
+pub mod index;
+pub mod intervals;
+pub mod merge;
+pub mod progress;
+mod rate;
+pub mod records;
+pub mod releases;
+pub mod report;
+pub mod throttle;
+pub mod vcf;
+pub mod verify;
+
+#[cfg(test)]
+mod test_utils;
+
+pub use download::{smart_save_vcf_from_url, DownloadOptions, DownloadStats};
+pub use error::DownloadError;
+pub use filter::filter_vcf_stream;
+pub use intervals::{IntervalTree, SortedIntervalIntersect};
@@ -1,18 +1,47 @@
 use clap::clap_derive;
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
 
 use indicatif::MultiProgress;
 use indicatif::ProgressBar;
+use indicatif::ProgressDrawTarget;
 use indicatif::ProgressStyle;
+use noodles::bgzf::io::writer::CompressionLevel;
 use std::collections::HashMap;
-use std::io::BufRead;
-use std::io::Write;
-use std::path::PathBuf;
-
-use md5;
-use serde::{Deserialize, Serialize};
 use std::fs::File;
+use std::io::{BufWriter, IsTerminal, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+use smart_gnomad_downloader::checksum::ChecksumAlgorithm;
+use smart_gnomad_downloader::download::{
+    build_client, path_with_suffix, prepare_output_dir, run_downloads, start_watchdog,
+    stream_vcf_from_url, write_download_plan, write_download_summary, DownloadJob, DownloadOutcome,
+    JobRegions, OutcomeStatus, OutputFormat, TotalProgress,
+};
+use smart_gnomad_downloader::filter::{BadLinePolicy, LineFilter, MultiAllelicAf};
+use smart_gnomad_downloader::index::IndexFormat;
+use smart_gnomad_downloader::merge::merge_vcfs;
+use smart_gnomad_downloader::progress::{ProgressJson, ProgressLines};
+use smart_gnomad_downloader::records::{
+    chromosomes_without_url, drop_short_regions, index_sorted_bed, output_name, pad_regions,
+    read_bed_regions_with, read_bed_regions_with_dropped, read_dataset_url_lists,
+    read_dataset_url_lists_with, read_regions, subtract_regions, today, union_regions,
+    write_bed_regions, write_contig_list, write_url_list, BedReadOptions, ChrNaming, Column,
+    DatasetUrlLists, DroppedIntervals, DuplicateUrlPolicy, InvalidIntervalPolicy, OutputTemplate,
+    RegionFormat, UrlListColumns, UrlListOptions,
+};
+use smart_gnomad_downloader::releases::Release;
+use smart_gnomad_downloader::report::{read_failed_chromosomes, write_report};
+use smart_gnomad_downloader::throttle::RateLimit;
+use smart_gnomad_downloader::verify::{verify_output, write_verify_table};
+use smart_gnomad_downloader::{DownloadError, DownloadOptions};
 
 #[derive(clap_derive::Parser, Debug)]
 #[clap(
@@ -21,295 +50,1528 @@ use std::thread;
     author = "SteampunkIslande"
 )]
 #[clap(about = "Download bed-restricted gnomad genome vcf")]
+// Both groups may be filled in by --config, see `Cli::parse_with_config`
+#[clap(group(clap::ArgGroup::new("source").args(["urls", "url"])))]
+#[clap(group(clap::ArgGroup::new("regions").args(["bed", "region"])))]
 struct Cli {
-    /// Path to the BED file to restrict the VCF to
+    /// TOML file giving default values for the options below (e.g. `threads = 4`), the ones
+    /// given on the command line taking precedence
+    #[clap(long = "config")]
+    config: Option<PathBuf>,
+
+    /// Path to the BED file to restrict the VCF to, `-` to read it from stdin. Repeated, the
+    /// union of the BED files is kept
     #[clap(short = 'b', long = "bed")]
-    bed: PathBuf,
+    bed: Vec<PathBuf>,
+
+    /// BED file of regions to leave out (e.g. a blacklist), subtracted from the regions of the
+    /// BED files or --region once padded (see --pad). Repeated, the union of the BED files is
+    /// left out, and the pieces left shorter than --min-interval-size are dropped
+    #[clap(long = "exclude-bed", conflicts_with = "stream_bed")]
+    exclude_bed: Vec<PathBuf>,
+
+    /// Region to restrict the VCF to instead of a BED file, as `chr:start-end` (1-based, both
+    /// ends included, repeatable)
+    #[clap(long = "region")]
+    region: Vec<String>,
+
+    /// Format of the files given to --bed, read into the same regions whatever their format
+    #[clap(long = "region-format", value_enum, default_value_t)]
+    region_format: RegionFormat,
+
+    /// The BED file is sorted by start within each chromosome (e.g. by `bedtools sort`),
+    /// which saves sorting it again
+    #[clap(long = "sorted-bed")]
+    sorted_bed: bool,
+
+    /// Read the intervals of each chromosome from the BED file as its download goes, instead of
+    /// holding all of them in memory. The BED file must be uncompressed and grouped by chromosome
+    #[clap(long = "stream-bed", requires_all = ["sorted_bed", "bed"])]
+    stream_bed: bool,
 
-    /// Path to the BED file to URL CSV list
+    /// Succeed without downloading anything when the BED file has no region, or none left once
+    /// filtered, instead of failing
+    #[clap(long = "allow-empty-bed")]
+    allow_empty_bed: bool,
+
+    /// What to do with BED records whose start is not before their end
+    #[clap(long = "on-invalid-interval", value_enum, default_value_t)]
+    on_invalid_interval: InvalidIntervalPolicy,
+
+    /// Ignore the BED intervals shorter than this many bases (e.g. single-base artifacts)
+    #[clap(long = "min-interval-size", default_value_t = 0)]
+    min_interval_size: u64,
+
+    /// The first line of the BED file names its columns (`chromosome`, `start`, `end`, in any order)
+    #[clap(long = "bed-has-header")]
+    bed_has_header: bool,
+
+    /// Path to the BED file to URL CSV list, which may be gzipped (e.g. `urls.csv.gz`)
     #[clap(short = 'u', long = "url-list")]
-    urls: PathBuf,
+    urls: Option<PathBuf>,
+
+    /// The first line of the URL list names its columns (`chromosome`, `md5sum`, `url` and
+    /// optionally `dataset`, in any order)
+    #[clap(long = "url-has-header")]
+    url_has_header: bool,
+
+    /// Column of the URL list holding the chromosome, as a 1-based position or, with
+    /// --url-has-header, a name
+    #[clap(long = "chrom-column")]
+    chrom_column: Option<Column>,
+
+    /// Column of the URL list holding the checksum, as for --chrom-column
+    #[clap(long = "checksum-column")]
+    checksum_column: Option<Column>,
+
+    /// Column of the URL list holding the URL, as for --chrom-column
+    #[clap(long = "url-column")]
+    url_column: Option<Column>,
+
+    /// Column of the URL list holding the dataset, as for --chrom-column. With a column given
+    /// by the other options, the dataset is otherwise only read from a `dataset` named column
+    #[clap(long = "dataset-column")]
+    dataset_column: Option<Column>,
+
+    /// Download this single VCF instead of the ones of a URL list
+    #[clap(long = "url", requires_all = ["md5", "chrom"])]
+    url: Option<String>,
+
+    /// With --url, expected checksum (MD5 or SHA-256) of the VCF
+    #[clap(long = "md5", requires = "url")]
+    md5: Option<String>,
+
+    /// With --url, chromosome of the VCF, whose BED regions are kept
+    #[clap(long = "chrom", requires = "url")]
+    chrom: Option<String>,
+
+    /// Replace the output VCFs that already exist
+    #[clap(long = "overwrite")]
+    overwrite: bool,
+
+    /// Fail the downloads whose output VCF already exists (the default)
+    #[clap(long = "no-clobber", conflicts_with = "overwrite")]
+    no_clobber: bool,
+
+    /// Split each download over this many connections, when the server accepts range requests
+    #[clap(long = "connections-per-file", default_value_t = NonZeroUsize::MIN)]
+    connections_per_file: NonZeroUsize,
+
+    /// Write each output to a `.tmp` file, only renamed to its final name once its download is
+    /// verified, so that a corrupt download never leaves a final output behind
+    #[clap(long = "verify-before-write", conflicts_with = "no_verify")]
+    verify_before_write: bool,
+
+    /// Directory of the `.part`, `.md5state`, `.tmp` and `.chunk` (see --connections-per-file)
+    /// files, instead of the output directory. Files are copied to the output directory if it
+    /// is on another filesystem
+    #[clap(long = "tmp-dir")]
+    tmp_dir: Option<PathBuf>,
+
+    /// Keep the raw download in a `.part` file and resume from it if it already exists. The MD5
+    /// of the part file is saved along in a `.md5state` file, so that it is not hashed again
+    #[clap(long = "resume")]
+    resume: bool,
+
+    /// Number of times a request is retried after a timeout or a server error
+    #[clap(long = "retries", default_value_t = 3)]
+    retries: u32,
+
+    /// Delay in seconds before the first retry, doubled after each failed attempt
+    #[clap(long = "retry-base-delay", default_value_t = 1.0)]
+    retry_base_delay: f64,
+
+    /// Timeout in seconds of connecting and of every read of a download, 0 to wait forever.
+    /// A download that keeps receiving data is never interrupted
+    #[clap(long = "timeout", default_value_t = 10.0)]
+    timeout: f64,
+
+    /// Redirects followed by each request before failing
+    #[clap(long = "max-redirects", default_value_t = 10)]
+    max_redirects: usize,
+
+    /// Stop every download as soon as one fails with a checksum mismatch or a network error
+    #[clap(long = "fail-fast", conflicts_with = "stdout")]
+    fail_fast: bool,
+
+    /// Stop the whole run after this many seconds, removing incomplete outputs
+    #[clap(long = "max-runtime")]
+    max_runtime: Option<f64>,
+
+    /// Proxy URL every request goes through (defaults to the HTTP_PROXY/HTTPS_PROXY
+    /// environment variables)
+    #[clap(long = "proxy")]
+    proxy: Option<String>,
+
+    /// Maximum download rate in bytes per second, shared by all the downloads of the run: the
+    /// downloads running at a given time split it between them
+    #[clap(long = "max-rate")]
+    max_rate: Option<u64>,
+
+    /// Extra HTTP header sent with every request, as `"Key: Value"` (repeatable)
+    #[clap(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+
+    /// Token sent as an `Authorization: Bearer` header with every request
+    #[clap(long = "auth-token")]
+    auth_token: Option<String>,
+
+    /// Maximum number of chromosomes downloaded at the same time (defaults to the number of CPUs)
+    #[clap(short = 't', long = "threads")]
+    threads: Option<NonZeroUsize>,
+
+    /// Directory the filtered VCFs are written to (created if missing)
+    #[clap(short = 'o', long = "output-dir", default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Write a tabix index (`.tbi`) next to each output VCF
+    #[clap(long = "index")]
+    index: bool,
+
+    /// Index format used with --index
+    #[clap(long = "index-format", value_enum, default_value_t, requires = "index")]
+    index_format: IndexFormat,
+
+    /// Compression level of the output VCFs, from 0 (no compression) to 9 (smallest files)
+    #[clap(long = "compression-level", value_parser = clap::value_parser!(u8).range(0..=9))]
+    compression_level: Option<u8>,
+
+    /// Threads compressing each output VCF. Up to --threads downloads run at the same time, so
+    /// this many threads per download are used on top of them
+    #[clap(
+        long = "write-threads",
+        default_value_t = NonZeroUsize::MIN,
+        conflicts_with_all = ["index", "no_compress"]
+    )]
+    write_threads: NonZeroUsize,
+
+    /// Write plain `.vcf` files instead of bgzf-compressed `.vcf.gz`
+    #[clap(long = "no-compress", conflicts_with_all = ["index", "compression_level"])]
+    no_compress: bool,
+
+    /// Format of the output files, `<chrom>.bcf` for BCF
+    #[clap(
+        long = "output-format",
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["no_compress", "index", "write_threads", "stdout", "merge"]
+    )]
+    output_format: OutputFormat,
+
+    /// Name of the output files inside --output-dir, instead of `<chrom>.vcf.gz`, e.g.
+    /// `gnomad_{chrom}_filtered.vcf.gz`. Placeholders are {chrom}, {dataset} and {date}
+    /// (today, as YYYY-MM-DD), and {chrom} is required
+    #[clap(long = "output-template")]
+    output_template: Option<String>,
+
+    /// Number of bases added on both sides of every BED interval
+    #[clap(long = "pad", default_value_t = 0)]
+    pad: u64,
+
+    /// What to do with VCF data lines whose position cannot be read
+    #[clap(long = "on-bad-line", value_enum, default_value_t)]
+    on_bad_line: BadLinePolicy,
+
+    /// Only keep records whose allele frequency (`AF` INFO field) is at least this
+    #[clap(long = "min-af")]
+    min_af: Option<f64>,
+
+    /// Only keep records whose allele frequency (`AF` INFO field) is at most this
+    #[clap(long = "max-af")]
+    max_af: Option<f64>,
+
+    /// Allele frequency of multi-allelic records compared to --min-af and --max-af
+    #[clap(long = "multi-allelic-af", value_enum, default_value_t)]
+    multi_allelic_af: MultiAllelicAf,
+
+    /// Only keep records whose FILTER is `PASS` or `.`
+    #[clap(long = "pass-only")]
+    pass_only: bool,
+
+    /// Only keep these INFO keys (comma-separated, in this order) in the output records
+    #[clap(long = "keep-info", value_delimiter = ',')]
+    keep_info: Option<Vec<String>>,
+
+    /// Only keep this sample column (repeatable, in the order given) after the FORMAT column of
+    /// multi-sample VCFs
+    #[clap(long = "sample")]
+    sample: Vec<String>,
+
+    /// With --keep-info, also remove the `##INFO` header lines of the other keys
+    #[clap(long = "prune-info-header", requires = "keep_info")]
+    prune_info_header: bool,
+
+    /// Keep structural variants whose span (`POS` to their `END` or `SVLEN` INFO field) overlaps
+    /// a region, even when their `POS` is before it
+    #[clap(long = "sv-aware")]
+    sv_aware: bool,
+
+    /// The VCFs may not be sorted by position: check every record against the regions on its
+    /// own, instead of walking the regions along with the records, which misses records once
+    /// positions go back. The kept records are held in memory, to be written sorted by position
+    #[clap(long = "assume-unsorted")]
+    assume_unsorted: bool,
+
+    /// With --assume-unsorted, write the kept records in the order of the VCF, without sorting
+    /// them. The outputs may then not be indexed
+    #[clap(
+        long = "keep-input-order",
+        requires = "assume_unsorted",
+        conflicts_with_all = ["index", "report_empty_intervals"]
+    )]
+    keep_input_order: bool,
+
+    /// Only keep the `##contig` header line of the chromosome of each output
+    #[clap(long = "trim-header", conflicts_with_all = ["stdout", "merge"])]
+    trim_header: bool,
+
+    /// Rewrite the chromosome names of the BED file and of the URL list, e.g. to match `chr1` with `1`
+    #[clap(long = "chr-naming", value_enum, default_value_t)]
+    chr_naming: ChrNaming,
+
+    /// What to do when a chromosome has several different entries in the URL list, including
+    /// chromosomes given the same name by --chr-naming (e.g. `chr1` and `1`)
+    #[clap(long = "on-duplicate-url", value_enum, default_value_t)]
+    on_duplicate_url: DuplicateUrlPolicy,
+
+    /// Print which chromosomes would be downloaded, and from where, without downloading anything
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+
+    /// Print the chromosomes of the URL list and their URLs, then exit. No BED is needed
+    #[clap(long = "list-contigs", conflicts_with_all = ["bed", "region"])]
+    list_contigs: bool,
+
+    /// Do not download chromosomes whose output already exists and was verified by a previous run
+    #[clap(long = "skip-existing")]
+    skip_existing: bool,
+
+    /// Write the checksum of each output VCF to a `.md5` file, in the format of `md5sum`
+    #[clap(long = "write-md5")]
+    write_md5: bool,
+
+    /// Algorithm of the checksums of the URL list (guessed from their length by default)
+    #[clap(long = "checksum-algo", value_enum)]
+    checksum_algo: Option<ChecksumAlgorithm>,
+
+    /// Do not check downloads against the checksums of the URL list.
+    /// The integrity of the outputs is then not guaranteed
+    #[clap(long = "no-verify", conflicts_with = "checksum_algo")]
+    no_verify: bool,
+
+    /// Write the filtered VCFs to stdout, one chromosome after the other in a single bgzf stream,
+    /// instead of one file per chromosome
+    #[clap(
+        long = "stdout",
+        conflicts_with_all = ["output_dir", "resume", "index", "skip_existing", "write_md5", "dry_run"]
+    )]
+    stdout: bool,
+
+    /// Only count the records of each chromosome falling in the regions, printing the counts to
+    /// stdout instead of writing any VCF
+    #[clap(
+        long = "count-only",
+        conflicts_with_all = ["stdout", "merge", "dry_run", "resume", "index", "skip_existing", "write_md5", "output_format"]
+    )]
+    count_only: bool,
+
+    /// Also keep each download as is, in `<chrom>.raw.vcf.gz` next to its filtered output
+    #[clap(long = "keep-raw", conflicts_with_all = ["stdout", "count_only"])]
+    keep_raw: bool,
+
+    /// Also concatenate the filtered VCFs, in chromosome order, into this single bgzf VCF
+    #[clap(long = "merge", conflicts_with = "stdout")]
+    merge: Option<PathBuf>,
+
+    /// Write a JSON report of every download (checksums, record counts, timings) to this file
+    #[clap(long = "report")]
+    report: Option<PathBuf>,
+
+    /// Write the progress of the downloads as JSON lines (`{"chrom", "downloaded", "total",
+    /// "percent"}`) to this file, or to this file descriptor if it is a number
+    #[clap(long = "progress-json")]
+    progress_json: Option<String>,
+
+    /// Write to this BED file the regions of each chromosome without any kept record
+    #[clap(long = "report-empty-intervals", value_name = "BED")]
+    report_empty_intervals: Option<PathBuf>,
+
+    /// Only download the chromosomes that failed according to this report of a previous run
+    /// (see --report)
+    #[clap(long = "only-failed", value_name = "REPORT")]
+    only_failed: Option<PathBuf>,
+
+    /// Color the progress bars and logs: `auto` when stderr is a terminal. Without colors, the
+    /// progress bars are replaced by plain progress lines logged every few seconds
+    #[clap(long = "color", value_enum, default_value_t)]
+    color: ColorChoice,
+
+    /// Print more details (repeat for even more), RUST_LOG taking precedence if set
+    #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only print warnings and errors (repeat to only print errors)
+    #[clap(short = 'q', long = "quiet", action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct URLDownloadRecord {
-    chromosome: String,
-    md5sum: String,
-    url: String,
+/// When the output on stderr is colored, see `--color`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ColorChoice {
+    /// When stderr is a terminal
+    #[default]
+    Auto,
+    Always,
+    /// Neither colors nor progress bars, the progress being logged as plain lines
+    Never,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct BEDRecord {
-    chromosome: String,
-    start: u32,
-    end: u32,
+impl ColorChoice {
+    /// Whether colors (and progress bars) are used, `Auto` being resolved against stderr.
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => std::io::stderr().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-struct VCFRecord {
-    chromosome: String,
-    pos: u32,
+/// Logs to stderr at the level set by `-v`/`-q`, warnings and errors being prefixed as such.
+fn init_logger(verbose: u8, quiet: u8, colored: bool) {
+    let level = match (verbose, quiet) {
+        (0, 0) => log::LevelFilter::Info,
+        (1, _) => log::LevelFilter::Debug,
+        (_, 0) => log::LevelFilter::Trace,
+        (_, 1) => log::LevelFilter::Warn,
+        _ => log::LevelFilter::Error,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .write_style(match colored {
+            true => env_logger::WriteStyle::Always,
+            false => env_logger::WriteStyle::Never,
+        })
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "Error: {}", record.args()),
+            log::Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
 }
 
-struct Md5ConsumerWriter {
-    md5_context: md5::Context,
-    progress_bar: ProgressBar,
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print the completion script of a shell to stdout
+    #[clap(hide = true)]
+    Completions { shell: clap_complete::Shell },
+    /// Write the URL list of a published gnomAD release. With several versions or datasets, rows
+    /// are tagged so that each gets its own `<chrom>.<tag>.vcf.gz` output
+    GenUrls {
+        /// gnomAD versions, e.g. `v4` or `4.1`, separated by commas
+        #[clap(long = "version", value_delimiter = ',', required = true)]
+        version: Vec<String>,
+
+        /// Datasets (`genomes`, `exomes`), separated by commas
+        #[clap(long = "dataset", value_delimiter = ',', default_value = "genomes")]
+        dataset: Vec<String>,
+
+        #[clap(long = "reference", default_value = "grch38")]
+        reference: String,
+
+        /// File the URL list is written to, stdout if not given
+        #[clap(long = "out")]
+        out: Option<PathBuf>,
+    },
+    /// Check the outputs of previous downloads against their `.verified` markers, without
+    /// downloading anything, and print a table of the results
+    Verify {
+        /// URL list the outputs were downloaded from
+        #[clap(short = 'u', long = "url-list")]
+        urls: PathBuf,
+
+        /// Directory containing the outputs
+        #[clap(short = 'o', long = "output-dir", default_value = ".")]
+        output_dir: PathBuf,
+    },
 }
 
-impl Write for Md5ConsumerWriter {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.md5_context.consume(buf);
-        let chunk_length = buf.len();
-        self.progress_bar.inc(chunk_length as u64);
-        Ok(buf.len())
+/// Writes the URL list of the given gnomAD releases to `out`, or stdout. Rows are tagged with
+/// what tells the releases apart: their version, their dataset, or both.
+fn run_gen_urls(
+    versions: &[String],
+    datasets: &[String],
+    reference: &str,
+    out: Option<&Path>,
+) -> Result<(), DownloadError> {
+    let mut urls = Vec::new();
+    for version in versions {
+        for dataset in datasets {
+            let release = Release::find(version, dataset, reference)?;
+            let mut tag = Vec::new();
+            if versions.len() > 1 {
+                tag.push(release.version);
+            }
+            if datasets.len() > 1 {
+                tag.push(release.dataset);
+            }
+            urls.extend(release.urls().into_iter().map(|mut record| {
+                record.dataset = (!tag.is_empty()).then(|| tag.join("."));
+                record
+            }));
+        }
     }
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.md5_context.flush()
+    match out {
+        Some(out) => write_url_list(
+            File::create(out).map_err(DownloadError::io(format!(
+                "Cannot create {}",
+                out.display()
+            )))?,
+            &urls,
+        )
+        .map_err(DownloadError::io(format!("Cannot write {}", out.display()))),
+        None => write_url_list(std::io::stdout().lock(), &urls)
+            .map_err(DownloadError::io("Cannot write URL list")),
     }
 }
 
-impl Md5ConsumerWriter {
-    pub fn new(progress_bar: ProgressBar) -> Self {
-        Md5ConsumerWriter {
-            md5_context: md5::Context::new(),
-            progress_bar,
+/// Checks the existing output of each chromosome of the URL list at `urls_path`, returning
+/// whether they all passed.
+fn run_verify(urls_path: &Path, output_dir: &Path) -> Result<bool, DownloadError> {
+    let url_lists = read_dataset_url_lists(
+        File::open(urls_path).map_err(DownloadError::io(format!(
+            "Cannot open URL list {}",
+            urls_path.display()
+        )))?,
+        DuplicateUrlPolicy::Error,
+    )?;
+    let mut statuses = Vec::new();
+    for (dataset, urls) in url_lists {
+        for (chrom_name, (expected_checksum, _)) in urls {
+            let name = output_name(&chrom_name, dataset.as_deref());
+            let output_path = ["vcf.gz", "vcf", "bcf"]
+                .iter()
+                .map(|extension| output_dir.join(format!("{}.{}", name, extension)))
+                .find(|output_path| output_path.exists());
+            if let Some(output_path) = output_path {
+                statuses.push((name, verify_output(&output_path, &expected_checksum)?));
+            }
         }
     }
+    statuses.sort_by(|a, b| a.0.cmp(&b.0));
+    write_verify_table(std::io::stdout().lock(), &statuses)
+        .map_err(DownloadError::io("Cannot write verification table"))?;
+    Ok(statuses.iter().all(|(_, status)| status.is_ok()))
+}
 
-    pub fn digest(self, expected_md5sum: &str) -> bool {
-        let md5sum = format!("{:x}", self.md5_context.compute());
-        if &md5sum == expected_md5sum {
-            self.progress_bar
-                .set_message("Successfully downloaded file");
-            self.progress_bar.finish();
-            true
-        } else {
-            self.progress_bar
-                .set_message("Error: checksums do not match!");
-            self.progress_bar.finish();
-            false
-        }
+/// Writes the completion script of `shell` for every flag of `Cli`.
+fn write_completions<W: Write>(shell: clap_complete::Shell, writer: &mut W) {
+    clap_complete::generate(
+        shell,
+        &mut Cli::command(),
+        "smart_gnomad_downloader",
+        writer,
+    );
+}
+
+/// Parses a `"Key: Value"` HTTP header.
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Key: Value\", got \"{}\"", header))?;
+    let (name, value) = (name.trim(), value.trim());
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name \"{}\"", name))?;
+    reqwest::header::HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value for header \"{}\"", name))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// How often the progress is logged in place of the progress bars, with `--color never`.
+const PROGRESS_LINES_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often `--progress-json` tells how far the downloads are.
+const PROGRESS_JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Opens the `--progress-json` target: a file descriptor given by its number, or else a file.
+fn open_progress_json(target: &str) -> Result<File, DownloadError> {
+    let open_error = DownloadError::io(format!("Cannot open {} for progress events", target));
+    match target.parse::<u32>() {
+        // Opening it again keeps the descriptor usable by the rest of the process
+        Ok(fd) if cfg!(unix) => std::fs::OpenOptions::new()
+            .append(true)
+            .open(format!("/dev/fd/{}", fd))
+            .map_err(open_error),
+        _ => File::create(target).map_err(open_error),
     }
 }
 
-struct SortedIntervalIntersect<I, T>
-where
-    I: Iterator<Item = (T, T)>,
-{
-    intervals: I,
-    current_interval: Option<(T, T)>,
+/// Settings of a `--config` file, named after the long flags of `Cli` (`url-list`, `min-af`...).
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    bed: Option<PathBuf>,
+    #[serde(default)]
+    region: Vec<String>,
+    url_list: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+    threads: Option<NonZeroUsize>,
+    retries: Option<u32>,
+    resume: Option<bool>,
+    index: Option<bool>,
+    skip_existing: Option<bool>,
+    chr_naming: Option<String>,
+    pad: Option<u64>,
+    on_bad_line: Option<String>,
+    min_af: Option<f64>,
+    max_af: Option<f64>,
+    pass_only: Option<bool>,
+    keep_info: Option<Vec<String>>,
 }
 
-impl<I, T> SortedIntervalIntersect<I, T>
-where
-    I: Iterator<Item = (T, T)>,
-    T: PartialOrd,
-{
-    pub fn new(intervals: I) -> Self {
-        Self {
-            intervals,
-            current_interval: None,
+impl Cli {
+    /// Parses `args`, taking the settings they do not give from the `--config` file, if any.
+    fn parse_with_config<I, T>(args: I) -> Result<Cli, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = Cli::command().try_get_matches_from(args)?;
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        if cli.command.is_some() {
+            return Ok(cli);
+        }
+        let config_error = |message: String| Cli::command().error(ErrorKind::InvalidValue, message);
+        if let Some(config_path) = &cli.config {
+            let config = std::fs::read_to_string(config_path).map_err(|e| {
+                config_error(format!("Cannot read {}: {}", config_path.display(), e))
+            })?;
+            let config: Config = toml::from_str(&config).map_err(|e| {
+                config_error(format!("Invalid config {}: {}", config_path.display(), e))
+            })?;
+            cli.apply_config(config, &matches).map_err(config_error)?;
+        }
+
+        if cli.bed.is_empty() && cli.region.is_empty() && !cli.list_contigs {
+            return Err(Cli::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "one of --bed or --region is required",
+            ));
+        }
+        if cli.urls.is_none() && cli.url.is_none() {
+            return Err(Cli::command().error(
+                ErrorKind::MissingRequiredArgument,
+                "one of --url-list or --url is required",
+            ));
         }
+        Ok(cli)
     }
 
-    pub fn in_interval(&mut self, value: T) -> Option<bool> {
-        if self.current_interval.is_none() {
-            self.current_interval = self.intervals.next();
+    /// Fills in the settings of `config` that were not given on the command line.
+    fn apply_config(&mut self, config: Config, matches: &ArgMatches) -> Result<(), String> {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        fn value_enum<E: ValueEnum>(name: &str, value: &str) -> Result<E, String> {
+            E::from_str(value, false).map_err(|_| format!("Invalid {} in config: {}", name, value))
         }
-        loop {
-            if let Some(ref current_interval) = self.current_interval {
-                if value < current_interval.0 {
-                    return Some(false);
-                }
-                if value < current_interval.1 {
-                    return Some(true);
-                }
-                self.current_interval = self.intervals.next();
-            } else {
-                return None;
+
+        // The regions and the URLs come either from the command line or the config, not a mix
+        if !given("bed") && !given("region") {
+            self.bed = config.bed.into_iter().collect();
+            self.region = config.region;
+        }
+        if !given("urls") && !given("url") {
+            self.urls = config.url_list;
+        }
+        macro_rules! apply {
+            ($($id:ident),*) => {
+                $(if let Some(value) = config.$id {
+                    if !given(stringify!($id)) {
+                        self.$id = value.into();
+                    }
+                })*
+            };
+        }
+        apply!(
+            output_dir,
+            retries,
+            resume,
+            index,
+            skip_existing,
+            pad,
+            pass_only
+        );
+        apply!(threads, min_af, max_af, keep_info);
+        if let Some(chr_naming) = config.chr_naming.filter(|_| !given("chr_naming")) {
+            self.chr_naming = value_enum("chr-naming", &chr_naming)?;
+        }
+        if let Some(on_bad_line) = config.on_bad_line.filter(|_| !given("on_bad_line")) {
+            self.on_bad_line = value_enum("on-bad-line", &on_bad_line)?;
+        }
+        Ok(())
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Cli::parse_with_config(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    if let Some(Command::Completions { shell }) = args.command {
+        write_completions(shell, &mut std::io::stdout());
+        return ExitCode::SUCCESS;
+    }
+    let colored = args.color.enabled();
+    console::set_colors_enabled_stderr(colored);
+    init_logger(args.verbose, args.quiet, colored);
+    if let Some(Command::GenUrls {
+        version,
+        dataset,
+        reference,
+        out,
+    }) = &args.command
+    {
+        return match run_gen_urls(version, dataset, reference, out.as_deref()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                ExitCode::from(e.exit_code())
             }
+        };
+    }
+    if let Some(Command::Verify { urls, output_dir }) = &args.command {
+        return match run_verify(urls, output_dir) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::from(1),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                ExitCode::from(e.exit_code())
+            }
+        };
+    }
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
         }
     }
 }
 
-fn get_blocking_reader_from_url(url: &str) -> Option<reqwest::blocking::Response> {
-    let client = reqwest::blocking::Client::builder()
-        .timeout(Some(core::time::Duration::from_secs(10)))
-        .build()
-        .expect("Cannot build client");
-    client.get(url).send().ok()
+/// One job per chromosome of the BED and dataset of the URL lists, sorted by output name, along
+/// with the chromosomes missing from a dataset's URL list (tagged with the dataset).
+/// The regions are moved into the jobs, and only copied for chromosomes of several datasets.
+/// The `dropped` intervals of each chromosome, under the name of its output for every dataset
+/// (`chr1.genomes`, see `output_name`), as `DownloadOutcome::chrom_name`.
+fn dropped_per_output(dropped: DroppedIntervals, url_lists: &DatasetUrlLists) -> DroppedIntervals {
+    url_lists
+        .keys()
+        .flat_map(|dataset| {
+            dropped.iter().map(move |(chromosome, &count)| {
+                (output_name(chromosome, dataset.as_deref()), count)
+            })
+        })
+        .collect()
 }
 
-fn smart_save_vcf_from_url<I>(
-    url: &str,
-    expected_md5: &str,
-    regions: I,
-    output_file_name: &str,
-    progress_bar: ProgressBar,
-) -> bool
-where
-    I: Iterator<Item = (u32, u32)>,
-{
-    eprintln!("Downloading file from {}", url);
-
-    let raw_reader =
-        get_blocking_reader_from_url(url).expect(&format!("Cannot download file at {}", url));
-
-    let pb = progress_bar;
-    if let Some(len) = raw_reader.content_length() {
-        pb.set_length(len);
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7}({percent}%) - [Remaining (estimate): {eta_precise}] {msg}",
-            )
-            .unwrap()
-            .progress_chars("=>-"),
+/// Outputs are named after `template` if any, or else `<chrom>[.<dataset>].<extension>`.
+fn build_jobs(
+    url_lists: &DatasetUrlLists,
+    regions_per_chr: HashMap<String, JobRegions>,
+    output_dir: &Path,
+    extension: &str,
+    template: Option<&OutputTemplate>,
+) -> (Vec<DownloadJob>, Vec<String>) {
+    let mut skipped_chromosomes = Vec::new();
+    for (dataset, urls) in url_lists {
+        skipped_chromosomes.extend(
+            chromosomes_without_url(&regions_per_chr, urls)
+                .into_iter()
+                .map(|chrom_name| output_name(&chrom_name, dataset.as_deref())),
         );
     }
 
-    let mut md5_writer = Md5ConsumerWriter::new(pb);
-
-    let actual_reader = tee::TeeReader::new(raw_reader, &mut md5_writer);
-    let bg_reader = noodles::bgzf::Reader::new(actual_reader);
-
-    let vcf_file =
-        std::fs::File::create(&format!("{}", output_file_name)).expect("Cannot create output file");
+    let mut jobs = Vec::new();
+    for (chrom_name, regions) in regions_per_chr {
+        let sources: Vec<_> = url_lists
+            .iter()
+            .filter_map(|(dataset, urls)| Some((dataset, urls.get(&chrom_name)?)))
+            .collect();
+        // The last source gets the regions themselves
+        let regions = std::iter::repeat_n(regions, sources.len());
+        for ((dataset, (expected_checksum, url)), regions) in sources.into_iter().zip(regions) {
+            let name = output_name(&chrom_name, dataset.as_deref());
+            let file_name = match template {
+                Some(template) => template.file_name(&chrom_name, dataset.as_deref()),
+                None => format!("{}.{}", &name, extension),
+            };
+            jobs.push(DownloadJob {
+                output_path: output_dir.join(file_name),
+                chrom_name: name,
+                url: url.clone(),
+                expected_checksum: expected_checksum.clone(),
+                regions,
+                progress_bar: ProgressBar::hidden(),
+            });
+        }
+    }
+    jobs.sort_by(|a, b| a.chrom_name.cmp(&b.chrom_name));
+    skipped_chromosomes.sort();
+    (jobs, skipped_chromosomes)
+}
 
-    let mut vcf_file_writer = noodles::bgzf::io::Writer::new(vcf_file);
+fn run(args: Cli) -> Result<(), DownloadError> {
+    let bed_paths = args.bed;
+    let urls_path = args.urls;
+    let output_template = args
+        .output_template
+        .as_deref()
+        .map(|template| OutputTemplate::new(template, &today()))
+        .transpose()?;
+    let mut options = DownloadOptions {
+        resume: args.resume,
+        overwrite: args.overwrite,
+        verify_before_write: args.verify_before_write,
+        tmp_dir: args.tmp_dir.clone(),
+        connections_per_file: args.connections_per_file.get(),
+        write_threads: args.write_threads.get(),
+        retries: args.retries,
+        retry_base_delay: Duration::from_secs_f64(args.retry_base_delay),
+        index: args.index.then_some(args.index_format),
+        compression_level: args
+            .compression_level
+            .and_then(CompressionLevel::new)
+            .unwrap_or_default(),
+        compress: !args.no_compress,
+        output_format: args.output_format,
+        count_only: args.count_only,
+        keep_raw: args.keep_raw,
+        report_empty_intervals: args.report_empty_intervals.is_some(),
+        fail_fast: args.fail_fast,
+        skip_existing: args.skip_existing,
+        write_md5: args.write_md5,
+        checksum_algorithm: args.checksum_algo,
+        verify: !args.no_verify,
+        rate_limit: args
+            .max_rate
+            .map(|max_rate| Arc::new(RateLimit::new(max_rate))),
+        proxy: args.proxy,
+        max_redirects: args.max_redirects,
+        timeout: (args.timeout > 0.0).then(|| Duration::from_secs_f64(args.timeout)),
+        headers: args
+            .headers
+            .into_iter()
+            .chain(
+                args.auth_token
+                    .map(|token| ("Authorization".to_string(), format!("Bearer {}", token))),
+            )
+            .collect(),
+        line_filter: LineFilter {
+            on_bad_line: args.on_bad_line,
+            min_af: args.min_af,
+            max_af: args.max_af,
+            multi_allelic_af: args.multi_allelic_af,
+            pass_only: args.pass_only,
+            keep_info: args.keep_info,
+            prune_info_header: args.prune_info_header,
+            sv_aware: args.sv_aware,
+            trim_header: args.trim_header,
+            assume_unsorted: args.assume_unsorted,
+            keep_input_order: args.keep_input_order,
+            samples: (!args.sample.is_empty()).then_some(args.sample),
+        },
+        ..Default::default()
+    };
+    options.client = Some(Arc::new(build_client(&options)?));
 
-    let mut intersection_check = SortedIntervalIntersect::new(regions);
+    let single_url = args.url.is_some();
+    let url_lists = match (urls_path, args.url, args.md5, args.chrom) {
+        (_, Some(url), Some(md5), Some(chrom)) => {
+            DatasetUrlLists::from([(None, HashMap::from([(chrom, (md5, url))]))])
+        }
+        (Some(urls_path), ..) => read_dataset_url_lists_with(
+            File::open(urls_path.as_path()).map_err(DownloadError::io(format!(
+                "Cannot open URL list {}",
+                urls_path.display()
+            )))?,
+            &UrlListOptions {
+                on_duplicate: args.on_duplicate_url,
+                has_header: args.url_has_header,
+                columns: UrlListColumns {
+                    chromosome: args.chrom_column,
+                    checksum: args.checksum_column,
+                    url: args.url_column,
+                    dataset: args.dataset_column,
+                },
+            },
+        )?,
+        _ => unreachable!("clap requires either --url-list or --url, --md5 and --chrom"),
+    };
+    if args.list_contigs {
+        return write_contig_list(std::io::stdout().lock(), &url_lists)
+            .map_err(DownloadError::io("Cannot write contig list"));
+    }
 
-    for line in bg_reader
-        .lines()
-        .map(Result::ok)
-        .map(|o| o.expect("Cannot read line!"))
-    {
-        if line.starts_with("#") {
-            writeln!(vcf_file_writer, "{}", line)
-                .expect("Cannot write to output! Received EOF or whatever...");
+    let bed_options = BedReadOptions {
+        format: args.region_format,
+        sorted: args.sorted_bed,
+        on_invalid_interval: args.on_invalid_interval,
+        min_interval_size: args.min_interval_size,
+        has_header: args.bed_has_header,
+    };
+    // BED intervals dropped as too short, under the chromosome names of the downloads
+    let mut dropped = DroppedIntervals::new();
+    let mut count_dropped = |counts: DroppedIntervals| {
+        for (chromosome, count) in counts {
+            *dropped
+                .entry(args.chr_naming.apply(&chromosome))
+                .or_default() += count;
+        }
+    };
+    let mut regions_per_chr: HashMap<String, JobRegions> = if args.stream_bed {
+        let [bed_path] = &bed_paths[..] else {
+            return Err(DownloadError::Parse(
+                "--stream-bed reads a single BED file, it cannot merge several".to_string(),
+            ));
+        };
+        if bed_path.as_os_str() == "-" {
+            return Err(DownloadError::Parse(
+                "--stream-bed needs a BED file, not the standard input".to_string(),
+            ));
+        }
+        let (sections, section_dropped) = index_sorted_bed(bed_path, &bed_options)?;
+        count_dropped(section_dropped);
+        args.chr_naming
+            .rename_sections(sections)?
+            .into_iter()
+            .map(|(chromosome, section)| (chromosome, JobRegions::Streamed(section)))
+            .collect()
+    } else {
+        let regions_per_chr = if bed_paths.is_empty() {
+            read_regions(&args.region)?
         } else {
-            let pos: u32 = line
-                .split("\t")
-                .nth(1)
-                .expect(&format!("Invalid vcf line: {}", line))
-                .parse()
-                .expect(&format!(
-                    "{}: second field is not a number, or is larger than 4 billion!",
-                    line
-                ));
-            if let Some(intersects) = intersection_check.in_interval(pos) {
-                if intersects {
-                    writeln!(vcf_file_writer, "{}", line)
-                        .expect("Cannot write to output! Received EOF or whatever...");
-                }
+            let mut bed_regions = Vec::new();
+            for bed_path in &bed_paths {
+                let bed_reader: Box<dyn Read> =
+                    if bed_path.as_os_str() == "-" {
+                        Box::new(std::io::stdin().lock())
+                    } else {
+                        Box::new(File::open(bed_path.as_path()).map_err(DownloadError::io(
+                            format!("Cannot open BED file {}", bed_path.display()),
+                        ))?)
+                    };
+                let (regions, bed_dropped) =
+                    read_bed_regions_with_dropped(bed_reader, &bed_options)?;
+                bed_regions.push(regions);
+                count_dropped(bed_dropped);
             }
+            union_regions(bed_regions)
+        };
+        let regions_per_chr = if args.exclude_bed.is_empty() {
+            regions_per_chr
+        } else {
+            let exclude_options = BedReadOptions {
+                on_invalid_interval: args.on_invalid_interval,
+                has_header: args.bed_has_header,
+                ..Default::default()
+            };
+            let mut excluded = Vec::new();
+            for bed_path in &args.exclude_bed {
+                excluded.push(read_bed_regions_with(
+                    File::open(bed_path).map_err(DownloadError::io(format!(
+                        "Cannot open BED file {}",
+                        bed_path.display()
+                    )))?,
+                    &exclude_options,
+                )?);
+            }
+            // Padded afterwards, the regions would grow back into the excluded ones, and
+            // pieces shorter than --min-interval-size could be left around them
+            let padded = regions_per_chr
+                .into_iter()
+                .map(|(chromosome, regions)| (chromosome, pad_regions(regions, args.pad)))
+                .collect();
+            let (regions_per_chr, pieces_dropped) = drop_short_regions(
+                subtract_regions(padded, &union_regions(excluded)),
+                args.min_interval_size,
+            );
+            count_dropped(pieces_dropped);
+            regions_per_chr
+        };
+        args.chr_naming
+            .rename_regions(regions_per_chr)
+            .into_iter()
+            .map(|(chromosome, regions)| (chromosome, regions.into()))
+            .collect()
+    };
+
+    let url_lists: DatasetUrlLists = url_lists
+        .into_iter()
+        .map(|(dataset, urls)| {
+            Ok((
+                dataset,
+                args.chr_naming.rename_urls(urls, args.on_duplicate_url)?,
+            ))
+        })
+        .collect::<Result<_, DownloadError>>()?;
+    let dropped = dropped_per_output(dropped, &url_lists);
+    if let Some(template) = &output_template {
+        if url_lists.len() > 1 && !template.has_dataset() {
+            return Err(DownloadError::Parse(
+                "The URL list has several datasets, so --output-template needs {dataset}"
+                    .to_string(),
+            ));
         }
     }
-    md5_writer.digest(expected_md5)
-}
-
-fn main() {
-    let args = Cli::parse();
-
-    let bed_path = args.bed;
-    let urls_path = args.urls;
+    if single_url {
+        let urls = &url_lists[&None];
+        // The other chromosomes of the BED are not wanted, rather than missing a URL
+        regions_per_chr.retain(|chrom_name, _| urls.contains_key(chrom_name));
+        for chrom_name in urls.keys() {
+            if !regions_per_chr.contains_key(chrom_name) {
+                log::warn!(
+                    "The BED has no region on {}, nothing to download",
+                    chrom_name
+                );
+            }
+        }
+    }
+    if regions_per_chr.is_empty() && !args.allow_empty_bed {
+        return Err(DownloadError::Parse(
+            "No region to download: the BED file is empty, or all of its intervals were filtered \
+             out (use --allow-empty-bed to succeed anyway)"
+                .to_string(),
+        ));
+    }
+    // The regions were padded before subtracting the excluded ones
+    if args.pad > 0 && args.exclude_bed.is_empty() {
+        regions_per_chr = regions_per_chr
+            .into_iter()
+            .map(|(chrom_name, regions)| (chrom_name, regions.padded(args.pad)))
+            .collect();
+    }
 
-    let mut bed_reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b'\t')
-        .flexible(true)
-        .from_reader(File::open(bed_path.as_path()).unwrap());
+    let threads = args
+        .threads
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get);
 
-    let mut urls_reader = csv::ReaderBuilder::new()
-        .has_headers(false)
-        .delimiter(b',')
-        .flexible(true)
-        .from_reader(File::open(urls_path.as_path()).unwrap());
+    let (mut jobs, skipped_chromosomes) = build_jobs(
+        &url_lists,
+        regions_per_chr,
+        &args.output_dir,
+        options.output_extension(),
+        output_template.as_ref(),
+    );
 
-    let urls: HashMap<String, (String, String)> = urls_reader
-        .deserialize::<URLDownloadRecord>()
-        .into_iter()
-        .map(|result| result.expect("Failed to deserialize URL record"))
-        .fold(HashMap::new(), |mut acc, record| {
-            acc.entry(record.chromosome)
-                .or_insert((record.md5sum, record.url));
-            acc
-        });
+    if let Some(report_path) = &args.only_failed {
+        let failed = read_failed_chromosomes(File::open(report_path).map_err(
+            DownloadError::io(format!("Cannot open report {}", report_path.display())),
+        )?)?;
+        let planned = jobs.len();
+        jobs.retain(|job| failed.contains(&job.chrom_name));
+        log::info!(
+            "Only downloading the {} chromosomes that failed in {}, skipping {} others",
+            jobs.len(),
+            report_path.display(),
+            planned - jobs.len()
+        );
+    }
 
-    // Assumes the bed is not sorted. Could be optimized if the user could guarantee the BED is sorted...
-    let regions_per_chr: HashMap<String, Vec<(u32, u32)>> = {
-        let mut regions = bed_reader
-            .deserialize::<BEDRecord>()
-            .into_iter()
-            .map(|result| result.expect("Failed to deserialize BED record"))
-            .fold(HashMap::new(), |mut acc, record| {
-                acc.entry(record.chromosome)
-                    .or_insert_with(Vec::new)
-                    .push((record.start, record.end));
-                acc
-            });
+    if args.dry_run {
+        warn_skipped_chromosomes(&skipped_chromosomes);
+        return write_download_plan(std::io::stdout().lock(), &jobs)
+            .map_err(DownloadError::io("Cannot write download plan"));
+    }
 
-        for (_, regions) in regions.iter_mut() {
-            regions.sort_by_key(|r| r.0);
+    if !args.stdout && !args.count_only {
+        prepare_output_dir(&args.output_dir)?;
+        if let Some(tmp_dir) = &args.tmp_dir {
+            prepare_output_dir(tmp_dir)?;
         }
-        regions
-    };
+    }
 
-    let mut thread_handles: Vec<(thread::JoinHandle<_>, String)> = Vec::new();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let handler_cancel = cancel.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        log::warn!(
+            "Interrupted, stopping the downloads and moving incomplete outputs to .partial files"
+        );
+        handler_cancel.store(true, Ordering::Relaxed);
+    }) {
+        log::warn!("Ctrl-C will not clean up incomplete outputs: {}", e);
+    }
+    let max_runtime = args.max_runtime.map(Duration::from_secs_f64);
+    let timed_out = max_runtime.map(|max_runtime| start_watchdog(max_runtime, cancel.clone()));
+    options.cancel = Some(cancel);
 
-    let multi_progress: MultiProgress = MultiProgress::new();
+    // Progress bars are drawn on stderr, which keeps stdout free for --stdout
+    let colored = args.color.enabled();
+    let multi_progress = MultiProgress::with_draw_target(match colored {
+        true => ProgressDrawTarget::stderr(),
+        false => ProgressDrawTarget::hidden(),
+    });
+    let total_progress =
+        TotalProgress::new(multi_progress.add(ProgressBar::no_length()), jobs.len());
+    options.total_progress = Some(total_progress.clone());
+    for job in jobs.iter_mut() {
+        job.progress_bar = multi_progress.add(ProgressBar::no_length());
+        job.progress_bar
+            .set_style(ProgressStyle::with_template("{prefix}: {msg}").unwrap());
+        job.progress_bar.set_prefix(job.chrom_name.clone());
+        job.progress_bar.set_message("Queued");
+    }
+    let progress_json = match &args.progress_json {
+        Some(target) => Some(ProgressJson::start(
+            open_progress_json(target)?,
+            jobs.iter()
+                .map(|job| (job.chrom_name.clone(), job.progress_bar.clone()))
+                .collect(),
+            PROGRESS_JSON_INTERVAL,
+        )),
+        None => None,
+    };
+    let progress_lines = (!colored).then(|| {
+        let mut progress_bars: Vec<(String, ProgressBar)> = jobs
+            .iter()
+            .map(|job| (job.chrom_name.clone(), job.progress_bar.clone()))
+            .collect();
+        progress_bars.push(("Total".to_string(), total_progress.progress_bar().clone()));
+        ProgressLines::start(progress_bars, PROGRESS_LINES_INTERVAL)
+    });
 
-    for (chrom_name, regions) in regions_per_chr.into_iter() {
-        if let Some((expected_md5, url)) = urls.get(&chrom_name) {
-            let url = url.clone();
-            let expected_md5 = expected_md5.clone();
-            let regions = regions.clone();
+    // Jobs are sorted by chromosome, which is the order of the merged VCF
+    let merged_paths: Vec<PathBuf> = jobs.iter().map(|job| job.output_path.clone()).collect();
+    let mut outcomes = if args.stdout {
+        stream_to_stdout(jobs, &options)?
+    } else {
+        run_downloads(jobs, threads, &options)
+    };
+    outcomes.sort_by(|a, b| a.chrom_name.cmp(&b.chrom_name));
 
-            let pb = multi_progress.add(ProgressBar::no_length());
+    let mut summary = Vec::new();
+    for outcome in &outcomes {
+        match &outcome.result {
+            Ok(stats) => {
+                log::info!("Successfully downloaded {}", &outcome.chrom_name);
+                if stats.bad_lines > 0 {
+                    log::warn!(
+                        "Skipped {} malformed VCF lines of {}",
+                        stats.bad_lines,
+                        &outcome.chrom_name
+                    );
+                }
+                summary.push((outcome.chrom_name.clone(), stats.clone(), outcome.elapsed));
+            }
+            Err(e) => log::error!(
+                "Failed to download {} ({}): {}",
+                &outcome.chrom_name,
+                outcome.status(),
+                e
+            ),
+        }
+    }
+    total_progress.finish();
+    if let Some(progress_lines) = progress_lines {
+        progress_lines.finish();
+    }
+    if let Some(progress_json) = progress_json {
+        progress_json
+            .finish()
+            .map_err(DownloadError::io("Cannot write progress events"))?;
+    }
+    // On stderr, as stdout may hold the VCF stream, unless the counts are all that is wanted
+    if args.count_only {
+        write_download_summary(std::io::stdout().lock(), &summary, &dropped)
+    } else {
+        write_download_summary(std::io::stderr().lock(), &summary, &dropped)
+    }
+    .map_err(DownloadError::io("Cannot write download summary"))?;
+    if let Some(report_path) = &args.report {
+        let report_error =
+            || DownloadError::io(format!("Cannot write report {}", report_path.display()));
+        let report_file = File::create(report_path).map_err(report_error())?;
+        write_report(BufWriter::new(report_file), &outcomes, &dropped).map_err(report_error())?;
+    }
+    if let Some(bed_path) = &args.report_empty_intervals {
+        let empty_intervals: Vec<(String, Vec<(u64, u64)>)> = summary
+            .iter()
+            .filter_map(|(chrom_name, stats, _)| {
+                Some((chrom_name.clone(), stats.empty_intervals.clone()?))
+            })
+            .collect();
+        let count: usize = empty_intervals
+            .iter()
+            .map(|(_, intervals)| intervals.len())
+            .sum();
+        let bed_error = || DownloadError::io(format!("Cannot write {}", bed_path.display()));
+        let bed_file = File::create(bed_path).map_err(bed_error())?;
+        let mut bed_writer = BufWriter::new(bed_file);
+        write_bed_regions(&mut bed_writer, &empty_intervals).map_err(bed_error())?;
+        bed_writer.flush().map_err(bed_error())?;
+        log::info!(
+            "{} regions without any kept record written to {}",
+            count,
+            bed_path.display()
+        );
+    }
+    warn_skipped_chromosomes(&skipped_chromosomes);
+    if let (Some(max_runtime), Some(timed_out)) = (max_runtime, timed_out) {
+        if timed_out.load(Ordering::Relaxed) {
+            // Unlike Ctrl-C, nobody is around to resume from the incomplete outputs
+            for path in &merged_paths {
+                let partial_path = path_with_suffix(path, ".partial");
+                if partial_path.exists() {
+                    if let Err(e) = std::fs::remove_file(&partial_path) {
+                        log::warn!("Cannot remove {}: {}", partial_path.display(), e);
+                    }
+                }
+            }
+            return Err(DownloadError::TimedOut(max_runtime));
+        }
+    }
+    let failures: Vec<String> = outcomes
+        .iter()
+        .filter(|outcome| outcome.result.is_err())
+        .map(|outcome| format!("{} ({})", outcome.chrom_name, outcome.status()))
+        .collect();
+    if !failures.is_empty() {
+        log::error!(
+            "{} of {} downloads failed: {}",
+            failures.len(),
+            outcomes.len(),
+            failures.join(", ")
+        );
+    }
+    if let Some(merge_path) = &args.merge {
+        if outcomes.iter().all(|outcome| outcome.result.is_ok()) {
+            merge_vcfs(&merged_paths, merge_path, options.compression_level)?;
+            log::info!("Merged all chromosomes into {}", merge_path.display());
+        } else {
+            log::warn!(
+                "Not merging chromosomes into {}, as some downloads failed",
+                merge_path.display()
+            );
+        }
+    }
+    // With --fail-fast, the downloads cancelled because of a failure do not hide it
+    if args.fail_fast {
+        outcomes.sort_by_key(|outcome| outcome.status() == OutcomeStatus::Cancelled);
+    }
+    outcomes
+        .into_iter()
+        .find_map(|outcome| outcome.result.err())
+        .map_or(Ok(()), Err)
+}
 
-            let chrom_name_clone = chrom_name.clone();
+/// Streams the jobs one after the other to stdout, only keeping the header of the first one.
+/// The error is that of finishing the stream, once every job went through.
+fn stream_to_stdout(
+    jobs: Vec<DownloadJob>,
+    options: &DownloadOptions,
+) -> Result<Vec<DownloadOutcome>, DownloadError> {
+    let stdout = std::io::stdout().lock();
+    let write_error = DownloadError::io("Cannot write to stdout");
+    if options.compress {
+        let mut writer = noodles::bgzf::io::writer::Builder::default()
+            .set_compression_level(options.compression_level)
+            .build_from_writer(stdout);
+        let results = stream_jobs(jobs, &mut writer, options);
+        writer.finish().map(|_| ()).map_err(write_error)?;
+        Ok(results)
+    } else {
+        let mut writer = BufWriter::new(stdout);
+        let results = stream_jobs(jobs, &mut writer, options);
+        writer.flush().map_err(write_error)?;
+        Ok(results)
+    }
+}
 
-            thread_handles.push((
-                thread::spawn(move || {
-                    smart_save_vcf_from_url(
-                        &url,
-                        &expected_md5,
-                        regions.into_iter(),
-                        &format!("{}.vcf.gz", &chrom_name),
-                        pb,
-                    )
-                }),
-                chrom_name_clone,
-            ));
+fn stream_jobs<W: Write>(
+    jobs: Vec<DownloadJob>,
+    writer: &mut W,
+    options: &DownloadOptions,
+) -> Vec<DownloadOutcome> {
+    let mut results = Vec::new();
+    for (i, job) in jobs.into_iter().enumerate() {
+        job.progress_bar.set_message("Downloading");
+        let start = Instant::now();
+        let result = stream_vcf_from_url(
+            &job.url,
+            &job.expected_checksum,
+            job.regions.iter(),
+            &mut *writer,
+            i == 0,
+            job.progress_bar,
+            options,
+        );
+        let failed = result.is_err();
+        results.push(DownloadOutcome {
+            chrom_name: job.chrom_name,
+            url: job.url,
+            expected_checksum: job.expected_checksum,
+            elapsed: start.elapsed(),
+            result,
+        });
+        // A failure leaves a truncated stream behind, the next chromosomes cannot follow it
+        if failed {
+            break;
         }
     }
-    for (threadid, (handle, chrom_name)) in thread_handles.into_iter().enumerate() {
-        if handle
-            .join()
-            .expect(&format!("Cannot join thread {}", threadid))
-        {
-            eprintln!("Successfully downloaded {}", &chrom_name);
-        }
+    results
+}
+
+fn warn_skipped_chromosomes(skipped_chromosomes: &[String]) {
+    if !skipped_chromosomes.is_empty() {
+        log::warn!(
+            "No URL found for the following BED chromosomes, they were skipped: {}",
+            skipped_chromosomes.join(", ")
+        );
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use clap::Parser;
+    use smart_gnomad_downloader::download::DownloadStats;
+
+    #[test]
+    fn test_dry_run_creates_no_files() {
+        let dir = std::env::temp_dir().join("smart_gnomad_downloader_main_dry_run");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("regions.bed"), "chr1\t100\t200\n").unwrap();
+        // Nothing listens on port 9, any download attempt would fail
+        std::fs::write(
+            dir.join("urls.csv"),
+            "chr1,abcdef,http://127.0.0.1:9/chr1.vcf.bgz\n",
+        )
+        .unwrap();
+
+        let output_dir = dir.join("output");
+        let args = Cli::parse_from([
+            "smart_gnomad_downloader",
+            "--bed",
+            dir.join("regions.bed").to_str().unwrap(),
+            "--url-list",
+            dir.join("urls.csv").to_str().unwrap(),
+            "--output-dir",
+            output_dir.to_str().unwrap(),
+            "--dry-run",
+        ]);
+        run(args).unwrap();
+        assert!(!output_dir.exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_config_with_override() {
+        let dir = std::env::temp_dir().join("smart_gnomad_downloader_main_config");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("config.toml"),
+            "bed = \"regions.bed\"\n\
+             url-list = \"urls.csv\"\n\
+             output-dir = \"vcfs\"\n\
+             threads = 4\n\
+             min-af = 0.01\n\
+             pass-only = true\n\
+             on-bad-line = \"skip\"\n",
+        )
+        .unwrap();
+        let config = dir.join("config.toml");
+        let config = config.to_str().unwrap();
+
+        let args = Cli::parse_with_config(["smart_gnomad_downloader", "--config", config]).unwrap();
+        assert_eq!(args.bed, [PathBuf::from("regions.bed")]);
+        assert_eq!(args.urls, Some(PathBuf::from("urls.csv")));
+        assert_eq!(args.output_dir, PathBuf::from("vcfs"));
+        assert_eq!(args.threads, NonZeroUsize::new(4));
+        assert_eq!(args.min_af, Some(0.01));
+        assert!(args.pass_only);
+        assert_eq!(args.on_bad_line, BadLinePolicy::Skip);
+
+        let args = Cli::parse_with_config([
+            "smart_gnomad_downloader",
+            "--config",
+            config,
+            "--threads",
+            "2",
+            "--region",
+            "chr1:100-200",
+        ])
+        .unwrap();
+        assert_eq!(args.threads, NonZeroUsize::new(2));
+        assert!(args.bed.is_empty());
+        assert_eq!(args.region, ["chr1:100-200"]);
+        assert_eq!(args.output_dir, PathBuf::from("vcfs"));
+
+        std::fs::write(dir.join("config.toml"), "thread = 4\n").unwrap();
+        assert!(Cli::parse_with_config(["smart_gnomad_downloader", "--config", config]).is_err());
+    }
+
+    #[test]
+    fn test_jobs_per_dataset() {
+        let url_lists = read_dataset_url_lists(
+            "chr1,abcdef,http://example.com/genomes.chr1.vcf.bgz,genomes\n\
+             chr1,012345,http://example.com/exomes.chr1.vcf.bgz,exomes\n\
+             chr2,abcdef,http://example.com/genomes.chr2.vcf.bgz,genomes\n"
+                .as_bytes(),
+            DuplicateUrlPolicy::Error,
+        )
+        .unwrap();
+        let regions_per_chr = HashMap::from([
+            ("chr1".to_string(), vec![(100, 200)].into()),
+            ("chr2".to_string(), vec![(100, 200)].into()),
+        ]);
+
+        let (jobs, skipped) = build_jobs(
+            &url_lists,
+            regions_per_chr.clone(),
+            Path::new("out"),
+            "vcf.gz",
+            None,
+        );
+        let planned: Vec<(&str, &str, &str)> = jobs
+            .iter()
+            .map(|job| {
+                (
+                    job.chrom_name.as_str(),
+                    job.output_path.to_str().unwrap(),
+                    job.url.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            planned,
+            [
+                (
+                    "chr1.exomes",
+                    "out/chr1.exomes.vcf.gz",
+                    "http://example.com/exomes.chr1.vcf.bgz"
+                ),
+                (
+                    "chr1.genomes",
+                    "out/chr1.genomes.vcf.gz",
+                    "http://example.com/genomes.chr1.vcf.bgz"
+                ),
+                (
+                    "chr2.genomes",
+                    "out/chr2.genomes.vcf.gz",
+                    "http://example.com/genomes.chr2.vcf.bgz"
+                ),
+            ]
+        );
+        assert_eq!(jobs[0].expected_checksum, "012345");
+        assert_eq!(skipped, ["chr2.exomes"]);
+
+        let template = OutputTemplate::new("gnomad_{dataset}_{chrom}_{date}.vcf", "2024-05-01");
+        let (jobs, _) = build_jobs(
+            &url_lists,
+            regions_per_chr,
+            Path::new("out"),
+            "vcf.gz",
+            Some(&template.unwrap()),
+        );
+        let output_paths: Vec<&str> = jobs
+            .iter()
+            .map(|job| job.output_path.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            output_paths,
+            [
+                "out/gnomad_exomes_chr1_2024-05-01.vcf",
+                "out/gnomad_genomes_chr1_2024-05-01.vcf",
+                "out/gnomad_genomes_chr2_2024-05-01.vcf",
+            ]
+        );
+        assert_eq!(jobs[0].chrom_name, "chr1.exomes");
+    }
+
+    #[test]
+    fn test_dropped_per_output() {
+        let url_lists = read_dataset_url_lists(
+            "chr1,abcdef,http://example.com/genomes.chr1.vcf.bgz,genomes\n\
+             chr1,012345,http://example.com/exomes.chr1.vcf.bgz,exomes\n"
+                .as_bytes(),
+            DuplicateUrlPolicy::Error,
+        )
+        .unwrap();
+        let dropped = DroppedIntervals::from([("chr1".to_string(), 2), ("chr3".to_string(), 1)]);
+        let dropped = dropped_per_output(dropped, &url_lists);
+        assert_eq!(
+            dropped,
+            DroppedIntervals::from([
+                ("chr1.exomes".to_string(), 2),
+                ("chr1.genomes".to_string(), 2),
+                ("chr3.exomes".to_string(), 1),
+                ("chr3.genomes".to_string(), 1),
+            ])
+        );
+
+        // chr3 has no download, but its dropped intervals are in the total
+        let stats = DownloadStats {
+            total_records: 3,
+            kept_records: 1,
+            ..Default::default()
+        };
+        let mut summary = Vec::new();
+        write_download_summary(
+            &mut summary,
+            &[("chr1.genomes".to_string(), stats, Duration::from_secs(1))],
+            &dropped,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(summary)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            [
+                "chr1.genomes\t3\t1\t0\t1.000\t2",
+                "total\t3\t1\t0\t1.000\t6"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jobs_own_regions() {
+        let url_lists = read_dataset_url_lists(
+            "chr1,abcdef,http://example.com/chr1.vcf.bgz\n".as_bytes(),
+            DuplicateUrlPolicy::Error,
+        )
+        .unwrap();
+        let regions: Vec<(u64, u64)> = (0..1_000_000).map(|i| (i * 10 + 1, i * 10 + 5)).collect();
+        let regions_buffer = regions.as_ptr();
+
+        let (jobs, _) = build_jobs(
+            &url_lists,
+            HashMap::from([("chr1".to_string(), regions.into())]),
+            Path::new("out"),
+            "vcf.gz",
+            None,
+        );
+        // The job holds the very buffer that was read, not a copy of it
+        let JobRegions::Loaded(regions) = &jobs[0].regions else {
+            panic!("regions were not loaded");
+        };
+        assert_eq!(regions.as_ptr(), regions_buffer);
+        assert_eq!(regions.len(), 1_000_000);
+    }
 
     #[test]
-    fn test_intersection_check() {
-        let regions = [(100u32, 200u32), (400u32, 1000u32)];
-        let mut intersection_check = SortedIntervalIntersect::new(regions.into_iter());
-
-        assert_eq!(intersection_check.in_interval(10), Some(false));
-        assert_eq!(intersection_check.in_interval(150), Some(true));
-        assert_eq!(intersection_check.in_interval(151), Some(true));
-        assert_eq!(intersection_check.in_interval(220), Some(false));
-        assert_eq!(intersection_check.in_interval(450), Some(true));
-        assert_eq!(intersection_check.in_interval(460), Some(true));
-        assert_eq!(intersection_check.in_interval(1100), None);
-        assert_eq!(intersection_check.in_interval(2000), None);
-        assert_eq!(intersection_check.in_interval(3000), None);
+    fn test_bash_completions() {
+        let args =
+            Cli::parse_with_config(["smart_gnomad_downloader", "completions", "bash"]).unwrap();
+        let Some(Command::Completions { shell }) = args.command else {
+            panic!("completions subcommand not parsed");
+        };
+        let mut script = Vec::new();
+        write_completions(shell, &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("--bed"));
     }
 }
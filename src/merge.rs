@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use noodles::bgzf::io::writer::CompressionLevel;
+
+use crate::error::DownloadError;
+use crate::records::decompressed;
+
+/// Concatenates the VCFs at `inputs` (bgzf or plain) into a single bgzf VCF at `output`, in the
+/// given order. Only the header of the first input is kept, the other inputs contributing their
+/// data lines only.
+pub fn merge_vcfs(
+    inputs: &[PathBuf],
+    output: &Path,
+    compression_level: CompressionLevel,
+) -> Result<(), DownloadError> {
+    let write_error = |source| DownloadError::Io {
+        context: format!("Cannot write to {}", output.display()),
+        source,
+    };
+    let output_file = File::create(output).map_err(DownloadError::io(format!(
+        "Cannot create output file {}",
+        output.display()
+    )))?;
+    let mut writer = noodles::bgzf::io::writer::Builder::default()
+        .set_compression_level(compression_level)
+        .build_from_writer(output_file);
+
+    for (i, input) in inputs.iter().enumerate() {
+        let read_error = |source| DownloadError::Io {
+            context: format!("Cannot read {}", input.display()),
+            source,
+        };
+        let input_file = File::open(input).map_err(read_error)?;
+        let reader = BufReader::new(decompressed(input_file).map_err(read_error)?);
+        for line in reader.lines() {
+            let line = line.map_err(read_error)?;
+            if i > 0 && line.starts_with('#') {
+                continue;
+            }
+            writeln!(writer, "{}", line).map_err(write_error)?;
+        }
+    }
+    writer.finish().map_err(write_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_merge_vcfs() {
+        let dir = test_dir("merge");
+        let chr2_vcf = [TEST_VCF[0], TEST_VCF[1], "chr2\t120\t.\tT\tC\t.\tPASS\t."];
+        std::fs::write(dir.join("chr1.vcf.gz"), bgzf_bytes(TEST_VCF)).unwrap();
+        // Plain and compressed inputs can be mixed
+        std::fs::write(dir.join("chr2.vcf"), chr2_vcf.join("\n") + "\n").unwrap();
+
+        let merged = dir.join("merged.vcf.gz");
+        merge_vcfs(
+            &[dir.join("chr1.vcf.gz"), dir.join("chr2.vcf")],
+            &merged,
+            CompressionLevel::default(),
+        )
+        .unwrap();
+
+        let mut expected = TEST_VCF.to_vec();
+        expected.push(chr2_vcf[2]);
+        assert_eq!(read_bgzf_lines(&merged), expected);
+    }
+}
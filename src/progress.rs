@@ -0,0 +1,246 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use indicatif::{HumanBytes, ProgressBar};
+use serde::{Deserialize, Serialize};
+
+/// Line of the newline-delimited JSON written by `ProgressJson`: how far the download of a
+/// chromosome is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProgressEvent {
+    pub chrom: String,
+    pub downloaded: u64,
+    /// Size of the download, unknown until it starts or if the server does not tell
+    pub total: Option<u64>,
+    pub percent: Option<f64>,
+}
+
+impl ProgressEvent {
+    fn new(chrom: &str, progress_bar: &ProgressBar) -> Self {
+        let downloaded = progress_bar.position();
+        let total = progress_bar.length();
+        ProgressEvent {
+            chrom: chrom.to_string(),
+            downloaded,
+            total,
+            percent: total
+                .filter(|&total| total > 0)
+                .map(|total| (downloaded as f64 * 1000.0 / total as f64).round() / 10.0),
+        }
+    }
+}
+
+impl fmt::Display for ProgressEvent {
+    /// Plain text line, e.g. `chr1: 1.50 KiB/3.00 KiB (50.0%)`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.chrom, HumanBytes(self.downloaded))?;
+        if let Some(total) = self.total {
+            write!(f, "/{}", HumanBytes(total))?;
+        }
+        if let Some(percent) = self.percent {
+            write!(f, " ({:.1}%)", percent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Thread passing to `report`, every `interval`, the events of the downloads that moved since
+/// the previous ones, then the last event of every download once stopped.
+struct Reporter {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<std::io::Result<()>>,
+}
+
+impl Reporter {
+    fn start<F>(
+        progress_bars: Vec<(String, ProgressBar)>,
+        interval: Duration,
+        mut report: F,
+    ) -> Self
+    where
+        F: FnMut(&[ProgressEvent]) -> std::io::Result<()> + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut written: Vec<Option<ProgressEvent>> = vec![None; progress_bars.len()];
+            loop {
+                let last = stopped.recv_timeout(interval) != Err(mpsc::RecvTimeoutError::Timeout);
+                let mut events = Vec::new();
+                for ((chrom, progress_bar), written) in progress_bars.iter().zip(&mut written) {
+                    let event = ProgressEvent::new(chrom, progress_bar);
+                    if last || written.as_ref() != Some(&event) {
+                        events.push(event.clone());
+                        *written = Some(event);
+                    }
+                }
+                report(&events)?;
+                if last {
+                    return Ok(());
+                }
+            }
+        });
+        Reporter { stop, thread }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        let _ = self.stop.send(());
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(std::io::Error::other("progress writer panicked")))
+    }
+}
+
+/// Writes the progress of the downloads as JSON lines (see `ProgressEvent`), for programs
+/// wrapping this one, while the progress bars go on as usual. Every `interval`, an event is
+/// written for each download that moved since the previous one, and `finish` writes a last
+/// event for every download.
+pub struct ProgressJson(Reporter);
+
+impl ProgressJson {
+    /// Starts writing to `writer` the progress of `progress_bars`, along with their chromosome.
+    pub fn start<W: Write + Send + 'static>(
+        mut writer: W,
+        progress_bars: Vec<(String, ProgressBar)>,
+        interval: Duration,
+    ) -> Self {
+        ProgressJson(Reporter::start(progress_bars, interval, move |events| {
+            for event in events {
+                serde_json::to_writer(&mut writer, event)?;
+                writeln!(writer)?;
+            }
+            writer.flush()
+        }))
+    }
+
+    /// Writes the last events and stops, failing if any event could not be written.
+    pub fn finish(self) -> std::io::Result<()> {
+        self.0.finish()
+    }
+}
+
+/// Logs the progress of the downloads as plain text lines (see `ProgressEvent`'s `Display`), in
+/// place of progress bars where they cannot be drawn (e.g. CI logs), as `ProgressJson` writes
+/// its events.
+pub struct ProgressLines(Reporter);
+
+impl ProgressLines {
+    pub fn start(progress_bars: Vec<(String, ProgressBar)>, interval: Duration) -> Self {
+        ProgressLines(Reporter::start(progress_bars, interval, |events| {
+            for event in events {
+                log::info!("{}", event);
+            }
+            Ok(())
+        }))
+    }
+
+    /// Logs the last lines and stops.
+    pub fn finish(self) {
+        let _ = self.0.finish();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::download::{smart_save_vcf_from_url, DownloadOptions};
+    use crate::test_utils::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Writer whose content stays readable once it is moved to the `ProgressJson` thread.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_progress_event() {
+        let progress_bar = ProgressBar::hidden();
+        assert_eq!(
+            ProgressEvent::new("chr1", &progress_bar).percent,
+            None,
+            "queued"
+        );
+        progress_bar.set_length(3000);
+        progress_bar.set_position(1000);
+        assert_eq!(
+            ProgressEvent::new("chr1", &progress_bar),
+            ProgressEvent {
+                chrom: "chr1".to_string(),
+                downloaded: 1000,
+                total: Some(3000),
+                percent: Some(33.3),
+            }
+        );
+        assert_eq!(
+            ProgressEvent::new("chr1", &progress_bar).to_string(),
+            "chr1: 1000 B/2.93 KiB (33.3%)"
+        );
+        progress_bar.unset_length();
+        assert_eq!(
+            ProgressEvent::new("chr1", &progress_bar).to_string(),
+            "chr1: 1000 B"
+        );
+    }
+
+    #[test]
+    fn test_progress_json() {
+        let body = bgzf_bytes(TEST_VCF);
+        let body_length = body.len() as u64;
+        let expected_md5 = md5_hex(&body);
+        // About a second to send the whole body, without telling its length
+        let url = serve_slowly(
+            body.clone(),
+            Duration::from_millis(1000 / body.len() as u64),
+        );
+        let dir = test_dir("progress_json");
+        let progress_bar = ProgressBar::hidden();
+        let buffer = SharedBuffer::default();
+        let progress_json = ProgressJson::start(
+            buffer.clone(),
+            vec![("chr1".to_string(), progress_bar.clone())],
+            Duration::from_millis(20),
+        );
+
+        smart_save_vcf_from_url(
+            &url,
+            &expected_md5,
+            [(100, 200)].into_iter(),
+            &dir.join("chr1.vcf.gz"),
+            progress_bar,
+            &DownloadOptions::default(),
+        )
+        .unwrap();
+        progress_json.finish().unwrap();
+
+        let json = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<ProgressEvent> = json
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(events.len() > 2, "{}", json);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].downloaded <= pair[1].downloaded));
+        assert!(events[0].downloaded < body_length);
+        assert_eq!(
+            events.last().unwrap(),
+            &ProgressEvent {
+                chrom: "chr1".to_string(),
+                downloaded: body_length,
+                total: None,
+                percent: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use indicatif::style::ProgressTracker;
+use indicatif::{HumanBytes, ProgressState, ProgressStyle};
+
+/// Span of the recent progress the rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// What a `WindowedRate` key displays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RateDisplay {
+    /// Bytes per second
+    Rate,
+    /// Time left at that rate
+    Eta,
+}
+
+/// Download rate over the last `RATE_WINDOW`, rather than over the whole download as
+/// `{bytes_per_sec}` and `{eta_precise}` are: once a stalled download resumes, the rate is back
+/// to normal after `RATE_WINDOW` instead of staying low for as long as it lasted.
+#[derive(Debug, Clone)]
+struct WindowedRate {
+    /// Position of the bar at each tick, the first one being the last tick before the window
+    samples: VecDeque<(Instant, u64)>,
+    display: RateDisplay,
+}
+
+impl WindowedRate {
+    fn new(display: RateDisplay) -> Self {
+        WindowedRate {
+            samples: VecDeque::new(),
+            display,
+        }
+    }
+
+    /// Accounts for the bar being at `pos` at `now`.
+    fn record(&mut self, now: Instant, pos: u64) {
+        self.samples.push_back((now, pos));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|(time, _)| now.duration_since(*time) >= RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second between the start of the window and `now`, `None` before any progress
+    /// could be measured.
+    fn bytes_per_sec(&self, now: Instant) -> Option<f64> {
+        let (start, start_pos) = self.samples.front()?;
+        let (_, pos) = self.samples.back()?;
+        let elapsed = now.duration_since(*start).as_secs_f64();
+        (elapsed > 0.0 && pos > start_pos).then(|| (pos - start_pos) as f64 / elapsed)
+    }
+}
+
+impl ProgressTracker for WindowedRate {
+    fn clone_box(&self) -> Box<dyn ProgressTracker> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, state: &ProgressState, now: Instant) {
+        self.record(now, state.pos());
+    }
+
+    fn reset(&mut self, _: &ProgressState, _: Instant) {
+        self.samples.clear();
+    }
+
+    fn write(&self, state: &ProgressState, w: &mut dyn fmt::Write) {
+        let rate = self.bytes_per_sec(Instant::now());
+        let _ = match (self.display, rate) {
+            (RateDisplay::Rate, Some(rate)) => write!(w, "{}/s", HumanBytes(rate as u64)),
+            (RateDisplay::Rate, None) => write!(w, "-"),
+            (RateDisplay::Eta, Some(rate)) => {
+                let left = (state.len().unwrap_or(0).saturating_sub(state.pos()) as f64 / rate)
+                    .min(u32::MAX as f64) as u64;
+                write!(
+                    w,
+                    "{:02}:{:02}:{:02}",
+                    left / 3600,
+                    left / 60 % 60,
+                    left % 60
+                )
+            }
+            (RateDisplay::Eta, None) => write!(w, "--:--:--"),
+        };
+    }
+}
+
+/// Progress bar style of `template`, which may use `{smoothed_rate}` and `{smoothed_eta}`
+/// for the rate and time left measured over the last few seconds.
+pub(crate) fn smoothed_style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .with_key("smoothed_rate", WindowedRate::new(RateDisplay::Rate))
+        .with_key("smoothed_eta", WindowedRate::new(RateDisplay::Eta))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_recovers_after_stall() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut rate = WindowedRate::new(RateDisplay::Rate);
+        assert_eq!(rate.bytes_per_sec(start), None);
+
+        // 1000 bytes per second for 10 seconds
+        for tick in 0..=100 {
+            rate.record(at(tick * 100), tick * 100);
+        }
+        let steady = rate.bytes_per_sec(at(10_000)).unwrap();
+        assert!((steady - 1000.0).abs() < 1.0, "{}", steady);
+
+        // Stalled for a minute: the rate drops with nothing new coming in
+        assert!(rate.bytes_per_sec(at(70_000)).unwrap() < 100.0);
+
+        // Back to 1000 bytes per second, which is measured again once the window went by
+        for tick in 0..=60 {
+            rate.record(at(70_000 + tick * 100), 10_000 + tick * 100);
+        }
+        let recovered = rate.bytes_per_sec(at(76_000)).unwrap();
+        assert!((recovered - 1000.0).abs() < 1.0, "{}", recovered);
+    }
+}
@@ -0,0 +1,2016 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::DownloadError;
+use crate::intervals::{merge_intervals, subtract_intervals};
+
+/// One row of the URL list: where to download a chromosome's VCF, and its expected md5 or sha256 checksum.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct URLDownloadRecord {
+    pub chromosome: String,
+    pub md5sum: String,
+    pub url: String,
+    /// Optional fourth column (e.g. `exomes`), telling apart the VCFs of a chromosome coming from
+    /// different datasets. Their outputs are named `<chromosome>.<dataset>.vcf.gz`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dataset: Option<String>,
+}
+
+/// Name of the output of `chromosome` for `dataset`, without extension.
+pub fn output_name(chromosome: &str, dataset: Option<&str>) -> String {
+    match dataset {
+        Some(dataset) => format!("{}.{}", chromosome, dataset),
+        None => chromosome.to_string(),
+    }
+}
+
+/// File names of the outputs given by `--output-template`, e.g. `gnomad_{chrom}_filtered.vcf.gz`.
+/// The placeholders are `{chrom}`, `{dataset}` (empty for rows without one) and `{date}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputTemplate {
+    template: String,
+    date: String,
+}
+
+impl OutputTemplate {
+    /// Placeholders `template` may use, besides `{chrom}` which it must use.
+    const PLACEHOLDERS: &'static [&'static str] = &["{chrom}", "{dataset}", "{date}"];
+
+    /// Checks that `template` names one file per chromosome, `{date}` expanding to `date`.
+    pub fn new(template: &str, date: &str) -> Result<OutputTemplate, DownloadError> {
+        let error = |reason: &str| {
+            DownloadError::Parse(format!("Invalid output template {}: {}", template, reason))
+        };
+        if !template.contains("{chrom}") {
+            return Err(error(
+                "it lacks {chrom}, so chromosomes would overwrite each other",
+            ));
+        }
+        if template.contains(std::path::is_separator) {
+            return Err(error("it names a file in the output directory, not a path"));
+        }
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let placeholder = rest[start..]
+                .find('}')
+                .map(|end| &rest[start..=start + end])
+                .unwrap_or(&rest[start..]);
+            if !Self::PLACEHOLDERS.contains(&placeholder) {
+                return Err(error(&format!(
+                    "unknown placeholder {}, known ones are {}",
+                    placeholder,
+                    Self::PLACEHOLDERS.join(", ")
+                )));
+            }
+            rest = &rest[start + placeholder.len()..];
+        }
+        Ok(OutputTemplate {
+            template: template.to_string(),
+            date: date.to_string(),
+        })
+    }
+
+    /// Whether outputs of the same chromosome get different names for different datasets.
+    pub fn has_dataset(&self) -> bool {
+        self.template.contains("{dataset}")
+    }
+
+    /// File name of the output of `chromosome` for `dataset`.
+    pub fn file_name(&self, chromosome: &str, dataset: Option<&str>) -> String {
+        self.template
+            .replace("{chrom}", chromosome)
+            .replace("{dataset}", dataset.unwrap_or(""))
+            .replace("{date}", &self.date)
+    }
+}
+
+/// Today's UTC date, as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let days = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / 86400) as i64;
+    // Civil from days, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Per-dataset URL lists, as read by `read_dataset_url_lists`. Rows without a dataset are under `None`.
+pub type DatasetUrlLists = BTreeMap<Option<String>, HashMap<String, (String, String)>>;
+
+/// Number of BED intervals dropped on each chromosome as shorter than `min_interval_size` (see
+/// `BedReadOptions`), for the download summary.
+pub type DroppedIntervals = HashMap<String, u64>;
+
+/// One BED interval, in BED coordinates (0-based, half-open).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BEDRecord {
+    pub chromosome: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl BEDRecord {
+    /// BED intervals are 0-based half-open, while VCF `POS` is 1-based.
+    /// Shifting both bounds by one gives the half-open interval of 1-based
+    /// positions covered by this record, so `chr1 100 200` becomes `[101, 201)`
+    /// and VCF positions can be compared against it directly.
+    pub fn vcf_interval(&self) -> (u64, u64) {
+        (self.start + 1, self.end + 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VCFRecord {
+    pub chromosome: String,
+    pub pos: u64,
+}
+
+/// What to do when the URL list gives two different entries for the same chromosome.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateUrlPolicy {
+    /// Stop with an error
+    Error,
+    /// Keep the first entry and print both conflicting entries
+    #[default]
+    Warn,
+    /// Silently keep the first entry
+    First,
+}
+
+/// What to do with a BED record whose start is not before its end.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidIntervalPolicy {
+    /// Stop with an error
+    #[default]
+    Error,
+    /// Print a warning and ignore the record
+    Skip,
+}
+
+/// Format of the region files read by `read_bed_regions_with`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RegionFormat {
+    /// Tab-separated `chromosome start end`, 0-based with the end excluded
+    #[default]
+    Bed,
+    /// Picard interval list: a SAM header (`@` lines), then tab-separated `chromosome start end
+    /// strand name`, 1-based with both ends included
+    IntervalList,
+    /// One `chromosome:start-end` region per line, 1-based with both ends included (see
+    /// `parse_region`). Empty lines and `#` comments are ignored
+    RegionList,
+}
+
+/// How a BED file is read by `read_bed_regions_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BedReadOptions {
+    pub format: RegionFormat,
+    /// Intervals are already sorted by start within each chromosome, see `read_sorted_bed_regions`
+    pub sorted: bool,
+    pub on_invalid_interval: InvalidIntervalPolicy,
+    /// Intervals shorter than this many bases are dropped before merging, the number dropped on
+    /// each chromosome being logged
+    pub min_interval_size: u64,
+    /// The first line names the columns (`chromosome`, `start`, `end`, in any order)
+    pub has_header: bool,
+}
+
+/// How a URL list is read by `read_dataset_url_lists_with`.
+#[derive(Debug, Clone, Default)]
+pub struct UrlListOptions {
+    pub on_duplicate: DuplicateUrlPolicy,
+    /// The first line names the columns (`chromosome`, `md5sum`, `url` and optionally
+    /// `dataset`, in any order)
+    pub has_header: bool,
+    pub columns: UrlListColumns,
+}
+
+/// A column of a URL list, by position or by name in its header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    /// 0-based position, written 1-based on the command line
+    Index(usize),
+    Name(String),
+}
+
+impl std::str::FromStr for Column {
+    type Err = String;
+
+    /// Parses a 1-based position, or else a name.
+    fn from_str(column: &str) -> Result<Self, Self::Err> {
+        match column.parse::<usize>() {
+            Ok(0) => Err("columns are numbered from 1".to_string()),
+            Ok(position) => Ok(Column::Index(position - 1)),
+            Err(_) if column.is_empty() => Err("empty column name".to_string()),
+            Err(_) => Ok(Column::Name(column.to_string())),
+        }
+    }
+}
+
+/// Where the fields of a `URLDownloadRecord` are in a URL list of another layout, e.g. a mirror
+/// publishing `url,md5` lists. When none is given, columns are those of `URLDownloadRecord`.
+/// Otherwise, the fields left unset are in their usual column (found by name with a header),
+/// except for the dataset, only read when given or, with a header, named `dataset`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UrlListColumns {
+    pub chromosome: Option<Column>,
+    pub checksum: Option<Column>,
+    pub url: Option<Column>,
+    pub dataset: Option<Column>,
+}
+
+/// `UrlListColumns` resolved to positions against the header of a URL list.
+struct ColumnPositions {
+    chromosome: usize,
+    checksum: usize,
+    url: usize,
+    dataset: Option<usize>,
+}
+
+impl UrlListColumns {
+    fn is_default(&self) -> bool {
+        *self == UrlListColumns::default()
+    }
+
+    /// Positions of the columns in a list with `headers`, if it has any.
+    fn positions(
+        &self,
+        headers: Option<&csv::StringRecord>,
+    ) -> Result<ColumnPositions, DownloadError> {
+        let position = |column: &Option<Column>, name: &str, default: usize| {
+            let named = |name: &str| {
+                let headers = headers.ok_or_else(|| {
+                    DownloadError::Parse(format!(
+                        "URL list column {} is named, but the list has no header",
+                        name
+                    ))
+                })?;
+                headers
+                    .iter()
+                    .position(|header| header == name)
+                    .ok_or_else(|| {
+                        DownloadError::Parse(format!("The URL list has no {} column", name))
+                    })
+            };
+            match column {
+                Some(Column::Index(index)) => Ok(*index),
+                Some(Column::Name(name)) => named(name),
+                None if headers.is_some() => named(name),
+                None => Ok(default),
+            }
+        };
+        let dataset = match &self.dataset {
+            None if !headers.is_some_and(|headers| headers.iter().any(|h| h == "dataset")) => None,
+            dataset => Some(position(dataset, "dataset", 3)?),
+        };
+        Ok(ColumnPositions {
+            chromosome: position(&self.chromosome, "chromosome", 0)?,
+            checksum: position(&self.checksum, "md5sum", 1)?,
+            url: position(&self.url, "url", 2)?,
+            dataset,
+        })
+    }
+}
+
+impl ColumnPositions {
+    fn record(&self, row: &csv::StringRecord) -> Result<URLDownloadRecord, DownloadError> {
+        let field = |index: usize| {
+            row.get(index).map(str::to_string).ok_or_else(|| {
+                DownloadError::Parse(format!(
+                    "Invalid URL record: line {} has no column {}",
+                    row.position().map_or(0, |p| p.line()),
+                    index + 1
+                ))
+            })
+        };
+        Ok(URLDownloadRecord {
+            chromosome: field(self.chromosome)?,
+            md5sum: field(self.checksum)?,
+            url: field(self.url)?,
+            dataset: self
+                .dataset
+                .and_then(|index| row.get(index))
+                .filter(|dataset| !dataset.is_empty())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// How chromosome names are rewritten, so that the BED file and the URL list agree on them.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChrNaming {
+    /// Use names as they are
+    #[default]
+    Keep,
+    /// Prefix names with `chr` (`1` -> `chr1`)
+    AddChr,
+    /// Remove the `chr` prefix of names (`chr1` -> `1`)
+    StripChr,
+}
+
+impl ChrNaming {
+    pub fn apply(self, chromosome: &str) -> String {
+        match self {
+            ChrNaming::Keep => chromosome.to_string(),
+            ChrNaming::AddChr if chromosome.starts_with("chr") => chromosome.to_string(),
+            ChrNaming::AddChr => format!("chr{}", chromosome),
+            ChrNaming::StripChr => chromosome
+                .strip_prefix("chr")
+                .unwrap_or(chromosome)
+                .to_string(),
+        }
+    }
+
+    /// Renames the chromosomes of a URL list (entries of `md5sum` and `url`). Two different
+    /// entries ending up with the same name are handled as duplicate rows, by `on_duplicate`:
+    /// the one already named so is kept first (`chr1` rather than `1` with `AddChr`).
+    pub fn rename_urls(
+        self,
+        urls: HashMap<String, (String, String)>,
+        on_duplicate: DuplicateUrlPolicy,
+    ) -> Result<HashMap<String, (String, String)>, DownloadError> {
+        let mut urls: Vec<(String, (String, String))> = urls.into_iter().collect();
+        urls.sort_by_key(|(chromosome, _)| {
+            (self.apply(chromosome) != *chromosome, chromosome.clone())
+        });
+        let mut renamed: HashMap<String, (String, (String, String))> = HashMap::new();
+        for (chromosome, entry) in urls {
+            match renamed.entry(self.apply(&chromosome)) {
+                Entry::Vacant(vacant) => {
+                    vacant.insert((chromosome, entry));
+                }
+                Entry::Occupied(occupied) if occupied.get().1 == entry => {}
+                Entry::Occupied(occupied) => {
+                    let (first, (first_md5sum, first_url)) = occupied.get();
+                    let message = format!(
+                        "Chromosomes {} and {} of the URL list are both renamed {}: {},{} and {},{}",
+                        first,
+                        chromosome,
+                        occupied.key(),
+                        first_md5sum,
+                        first_url,
+                        entry.0,
+                        entry.1
+                    );
+                    match on_duplicate {
+                        DuplicateUrlPolicy::Error => return Err(DownloadError::Parse(message)),
+                        DuplicateUrlPolicy::Warn => {
+                            log::warn!("{}, keeping the one of {}", message, first)
+                        }
+                        DuplicateUrlPolicy::First => {}
+                    }
+                }
+            }
+        }
+        Ok(renamed
+            .into_iter()
+            .map(|(name, (_, entry))| (name, entry))
+            .collect())
+    }
+
+    /// Renames the chromosomes of BED regions, merging the regions of chromosomes that end up
+    /// with the same name.
+    pub fn rename_regions(
+        self,
+        regions_per_chr: HashMap<String, Vec<(u64, u64)>>,
+    ) -> HashMap<String, Vec<(u64, u64)>> {
+        let mut renamed: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for (chromosome, regions) in regions_per_chr {
+            match renamed.entry(self.apply(&chromosome)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(regions);
+                }
+                Entry::Occupied(mut entry) => {
+                    let merged = entry.get_mut();
+                    merged.extend(regions);
+                    merged.sort_by_key(|r| r.0);
+                    *merged = merge_intervals(std::mem::take(merged));
+                }
+            }
+        }
+        renamed
+    }
+
+    /// Renames the chromosomes of an indexed BED file. Unlike regions held in memory, sections
+    /// cannot be merged, so two chromosomes ending up with the same name are an error.
+    pub fn rename_sections(
+        self,
+        sections: HashMap<String, BedSection>,
+    ) -> Result<HashMap<String, BedSection>, DownloadError> {
+        let mut renamed = HashMap::new();
+        for (chromosome, section) in sections {
+            match renamed.entry(self.apply(&chromosome)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(section);
+                }
+                Entry::Occupied(entry) => {
+                    return Err(DownloadError::Parse(format!(
+                        "The BED file has regions on {} under two names",
+                        entry.key()
+                    )))
+                }
+            }
+        }
+        Ok(renamed)
+    }
+}
+
+/// Wraps `reader` in a gzip decoder if its content starts with the gzip magic bytes
+/// (which also covers bgzf), so that compressed and plain inputs are read the same way.
+pub fn decompressed<'a, R: Read + 'a>(reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Writes `records` as a headerless `chromosome,md5sum,url[,dataset]` list, readable by `read_url_list`.
+pub fn write_url_list<W: Write>(writer: W, records: &[URLDownloadRecord]) -> std::io::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_writer(writer);
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()
+}
+
+/// Reads a headerless `chromosome,md5sum,url` list, keeping the first entry of each chromosome.
+/// Conflicting entries for a chromosome are handled according to `on_duplicate`.
+/// The list may be gzipped. A list whose rows belong to several datasets is an error, see
+/// `read_dataset_url_lists` to read those.
+pub fn read_url_list<R: Read>(
+    reader: R,
+    on_duplicate: DuplicateUrlPolicy,
+) -> Result<HashMap<String, (String, String)>, DownloadError> {
+    let mut url_lists = read_dataset_url_lists(reader, on_duplicate)?;
+    if url_lists.len() > 1 {
+        let datasets: Vec<&str> = url_lists
+            .keys()
+            .map(|dataset| dataset.as_deref().unwrap_or("none"))
+            .collect();
+        return Err(DownloadError::Parse(format!(
+            "The URL list has rows of several datasets: {}",
+            datasets.join(", ")
+        )));
+    }
+    Ok(url_lists
+        .pop_first()
+        .map(|(_, urls)| urls)
+        .unwrap_or_default())
+}
+
+/// Same as `read_url_list`, for a list whose rows may have a fourth `dataset` column: the rows
+/// of each dataset are kept apart, so that a chromosome can be listed once per dataset.
+/// Columns are separated by commas, or by tabs if the first line has any.
+pub fn read_dataset_url_lists<R: Read>(
+    reader: R,
+    on_duplicate: DuplicateUrlPolicy,
+) -> Result<DatasetUrlLists, DownloadError> {
+    read_dataset_url_lists_with(
+        reader,
+        &UrlListOptions {
+            on_duplicate,
+            ..Default::default()
+        },
+    )
+}
+
+/// Same as `read_dataset_url_lists`, configured by `options`.
+pub fn read_dataset_url_lists_with<R: Read>(
+    reader: R,
+    options: &UrlListOptions,
+) -> Result<DatasetUrlLists, DownloadError> {
+    let read_error = |source| DownloadError::Io {
+        context: "Cannot read URL list".to_string(),
+        source,
+    };
+    let mut reader = BufReader::new(decompressed(reader).map_err(read_error)?);
+    let delimiter = url_list_delimiter(reader.fill_buf().map_err(read_error)?);
+    let mut urls_reader = csv::ReaderBuilder::new()
+        .has_headers(options.has_header)
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(reader);
+    let parse_error = |e| DownloadError::Parse(format!("Invalid URL record: {}", e));
+    let headers = if options.has_header {
+        Some(urls_reader.headers().map_err(parse_error)?.clone())
+    } else {
+        None
+    };
+    let positions = if options.columns.is_default() {
+        None
+    } else {
+        Some(options.columns.positions(headers.as_ref())?)
+    };
+
+    let mut url_lists = DatasetUrlLists::new();
+    for result in urls_reader.records() {
+        let row = result.map_err(parse_error)?;
+        let record: URLDownloadRecord = match &positions {
+            Some(positions) => positions.record(&row)?,
+            None => row.deserialize(headers.as_ref()).map_err(parse_error)?,
+        };
+        if record.url.is_empty() {
+            return Err(DownloadError::MissingUrl(record.chromosome));
+        }
+        let name = output_name(&record.chromosome, record.dataset.as_deref());
+        let urls = url_lists.entry(record.dataset).or_default();
+        match urls.get(&record.chromosome) {
+            None => {
+                urls.insert(record.chromosome, (record.md5sum, record.url));
+            }
+            Some((md5sum, url)) if *md5sum == record.md5sum && *url == record.url => {}
+            Some((first_md5sum, first_url)) => {
+                let message = format!(
+                    "Chromosome {} is listed twice in the URL list: {},{} and {},{}",
+                    name, first_md5sum, first_url, record.md5sum, record.url
+                );
+                match options.on_duplicate {
+                    DuplicateUrlPolicy::Error => return Err(DownloadError::Parse(message)),
+                    DuplicateUrlPolicy::Warn => {
+                        log::warn!("{}, keeping the first one", message)
+                    }
+                    DuplicateUrlPolicy::First => {}
+                }
+            }
+        }
+    }
+    Ok(url_lists)
+}
+
+/// Column delimiter of a URL list starting with `start`: a tab if the first line has one
+/// (URLs have no tabs), a comma otherwise.
+fn url_list_delimiter(start: &[u8]) -> u8 {
+    let first_line = start
+        .split(|&byte| byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    if first_line.contains(&b'\t') {
+        b'\t'
+    } else {
+        b','
+    }
+}
+
+/// Writes the chromosomes of `url_lists` and their URLs as a tab-separated table, sorted by
+/// name (with their dataset, if any, as in output names).
+pub fn write_contig_list<W: Write>(
+    mut writer: W,
+    url_lists: &DatasetUrlLists,
+) -> std::io::Result<()> {
+    let mut contigs: Vec<(String, &str)> = url_lists
+        .iter()
+        .flat_map(|(dataset, urls)| {
+            urls.iter().map(move |(chromosome, (_, url))| {
+                (output_name(chromosome, dataset.as_deref()), url.as_str())
+            })
+        })
+        .collect();
+    contigs.sort();
+    writeln!(writer, "chromosome\turl")?;
+    for (name, url) in contigs {
+        writeln!(writer, "{}\t{}", name, url)?;
+    }
+    Ok(())
+}
+
+/// Writes per-chromosome intervals in VCF coordinates (see `BEDRecord::vcf_interval`) back as
+/// BED rows, in the given order.
+pub fn write_bed_regions<W: Write>(
+    mut writer: W,
+    regions: &[(String, Vec<(u64, u64)>)],
+) -> std::io::Result<()> {
+    for (chromosome, intervals) in regions {
+        for (start, end) in intervals {
+            writeln!(writer, "{}\t{}\t{}", chromosome, start - 1, end - 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a BED file into per-chromosome sorted and merged intervals, in VCF coordinates (see `BEDRecord::vcf_interval`).
+/// The BED file may be gzipped. Records whose start is not before their end are an error.
+pub fn read_bed_regions<R: Read>(
+    reader: R,
+) -> Result<HashMap<String, Vec<(u64, u64)>>, DownloadError> {
+    read_bed_regions_with(reader, &BedReadOptions::default())
+}
+
+/// Same as `read_bed_regions`, for a BED file whose intervals are already sorted by start
+/// within each chromosome: they are not sorted again, and an out-of-order interval is an error.
+pub fn read_sorted_bed_regions<R: Read>(
+    reader: R,
+) -> Result<HashMap<String, Vec<(u64, u64)>>, DownloadError> {
+    read_bed_regions_with(
+        reader,
+        &BedReadOptions {
+            sorted: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// Same as `read_bed_regions`, configured by `options`. Files of another `options.format` are
+/// read into the same intervals, `has_header` only applying to BED files.
+pub fn read_bed_regions_with<R: Read>(
+    reader: R,
+    options: &BedReadOptions,
+) -> Result<HashMap<String, Vec<(u64, u64)>>, DownloadError> {
+    read_bed_regions_with_dropped(reader, options).map(|(regions, _)| regions)
+}
+
+/// Per-chromosome intervals, with the number of intervals dropped on each chromosome.
+type RegionsWithDropped = (HashMap<String, Vec<(u64, u64)>>, DroppedIntervals);
+
+/// Same as `read_bed_regions_with`, also returning how many intervals were dropped on each
+/// chromosome.
+pub fn read_bed_regions_with_dropped<R: Read>(
+    reader: R,
+    options: &BedReadOptions,
+) -> Result<RegionsWithDropped, DownloadError> {
+    let sorted = options.sorted;
+    let reader = decompressed(reader).map_err(DownloadError::io("Cannot read BED file"))?;
+    let mut regions: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    let mut dropped: HashMap<String, u64> = HashMap::new();
+    let add_record = |line: u64, record: BEDRecord| {
+        let Some(interval) = checked_interval(line, &record, options, &mut dropped)? else {
+            return Ok(());
+        };
+        if sorted {
+            let previous = regions.get(&record.chromosome).and_then(|r| r.last());
+            if let Some(&(previous_start, _)) = previous {
+                check_sorted(&record, previous_start)?;
+            }
+        }
+        regions.entry(record.chromosome).or_default().push(interval);
+        Ok(())
+    };
+    match options.format {
+        RegionFormat::Bed => read_bed_records(reader, options, add_record)?,
+        format => read_listed_regions(reader, format, add_record)?,
+    }
+
+    for regions in regions.values_mut() {
+        if !sorted {
+            regions.sort_by_key(|r| r.0);
+        }
+        *regions = merge_intervals(std::mem::take(regions));
+    }
+    log_dropped_intervals(&dropped, options.min_interval_size);
+    Ok((regions, dropped))
+}
+
+/// Passes the records of a BED file to `add_record`, along with their line.
+fn read_bed_records<R, F>(
+    reader: R,
+    options: &BedReadOptions,
+    mut add_record: F,
+) -> Result<(), DownloadError>
+where
+    R: Read,
+    F: FnMut(u64, BEDRecord) -> Result<(), DownloadError>,
+{
+    let mut bed_reader = csv::ReaderBuilder::new()
+        .has_headers(options.has_header)
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_reader(reader);
+
+    let headers = if options.has_header {
+        Some(
+            bed_reader
+                .headers()
+                .map_err(|e| DownloadError::Parse(format!("Invalid BED header: {}", e)))?
+                .clone(),
+        )
+    } else {
+        None
+    };
+    for result in bed_reader.records() {
+        let raw_record = result.map_err(bed_parse_error)?;
+        let record: BEDRecord = raw_record
+            .deserialize(headers.as_ref())
+            .map_err(bed_parse_error)?;
+        add_record(raw_record.position().map_or(0, |p| p.line()), record)?;
+    }
+    Ok(())
+}
+
+/// Passes the regions of an interval list or a region list to `add_record`, as BED records
+/// along with their line.
+fn read_listed_regions<R, F>(
+    reader: R,
+    format: RegionFormat,
+    mut add_record: F,
+) -> Result<(), DownloadError>
+where
+    R: Read,
+    F: FnMut(u64, BEDRecord) -> Result<(), DownloadError>,
+{
+    for (line_number, line) in (1..).zip(BufReader::new(reader).lines()) {
+        let line = line.map_err(DownloadError::io("Cannot read region file"))?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = match format {
+            RegionFormat::IntervalList if line.starts_with('@') => continue,
+            RegionFormat::IntervalList => parse_interval_list_record(line).map_err(|reason| {
+                DownloadError::Parse(format!(
+                    "Invalid interval list record at line {}: {} ({})",
+                    line_number, line, reason
+                ))
+            })?,
+            RegionFormat::RegionList if line.starts_with('#') => continue,
+            RegionFormat::RegionList | RegionFormat::Bed => {
+                let (chromosome, (start, end)) = parse_region(line.trim())?;
+                BEDRecord {
+                    chromosome,
+                    start: start - 1,
+                    end: end - 1,
+                }
+            }
+        };
+        add_record(line_number, record)?;
+    }
+    Ok(())
+}
+
+/// Converts a Picard interval list record (1-based, both ends included) into a BED record.
+fn parse_interval_list_record(line: &str) -> Result<BEDRecord, String> {
+    let mut fields = line.split('\t');
+    let mut field = |name: &str| fields.next().ok_or(format!("missing {}", name));
+    let chromosome = field("chromosome")?.to_string();
+    let mut position = |name: &str| {
+        let position = field(name)?;
+        position
+            .parse::<u64>()
+            .map_err(|_| format!("{} {} is not a position", name, position))
+    };
+    let start = position("start")?;
+    let end = position("end")?;
+    if start == 0 {
+        return Err("positions start at 1".to_string());
+    }
+    Ok(BEDRecord {
+        chromosome,
+        start: start - 1,
+        end,
+    })
+}
+
+fn bed_parse_error(e: csv::Error) -> DownloadError {
+    DownloadError::Parse(format!("Invalid BED record: {}", e))
+}
+
+/// The VCF interval of `record`, or `None` if it is skipped as invalid or dropped as shorter than
+/// `options.min_interval_size`, in which case it is counted in `dropped`.
+fn checked_interval(
+    line: u64,
+    record: &BEDRecord,
+    options: &BedReadOptions,
+    dropped: &mut HashMap<String, u64>,
+) -> Result<Option<(u64, u64)>, DownloadError> {
+    if record.start >= record.end {
+        let message = format!(
+            "line {}: {} {} {}: start is not before end",
+            line, record.chromosome, record.start, record.end
+        );
+        match options.on_invalid_interval {
+            InvalidIntervalPolicy::Error => {
+                return Err(DownloadError::Parse(format!(
+                    "Invalid BED record: {}",
+                    message
+                )))
+            }
+            InvalidIntervalPolicy::Skip => {
+                log::warn!("Skipping BED record at {}", message);
+                return Ok(None);
+            }
+        }
+    }
+    if record.end - record.start < options.min_interval_size {
+        *dropped.entry(record.chromosome.clone()).or_default() += 1;
+        return Ok(None);
+    }
+    Ok(Some(record.vcf_interval()))
+}
+
+/// Fails unless `record` starts at or after `previous_start`, the VCF start of the previous
+/// interval of its chromosome.
+fn check_sorted(record: &BEDRecord, previous_start: u64) -> Result<(), DownloadError> {
+    if record.vcf_interval().0 < previous_start {
+        return Err(DownloadError::Parse(format!(
+            "BED file is not sorted: {} {} {} comes after an interval starting at {}",
+            record.chromosome,
+            record.start,
+            record.end,
+            previous_start - 1
+        )));
+    }
+    Ok(())
+}
+
+fn log_dropped_intervals(dropped: &DroppedIntervals, min_interval_size: u64) {
+    let mut dropped: Vec<(&String, &u64)> = dropped.iter().collect();
+    dropped.sort();
+    for (chromosome, count) in dropped {
+        log::info!(
+            "Dropped {} BED intervals shorter than {} bp on {}",
+            count,
+            min_interval_size,
+            chromosome
+        );
+    }
+}
+
+/// The intervals of one chromosome of a BED file sorted and grouped by chromosome, as found by
+/// `index_sorted_bed`. Only the byte range of the chromosome's lines is held, its intervals being
+/// read from the file again each time they are iterated over.
+#[derive(Debug, Clone)]
+pub struct BedSection {
+    path: Arc<PathBuf>,
+    headers: Option<Arc<csv::StringRecord>>,
+    /// Byte range of the chromosome's lines in the file
+    start: u64,
+    end: u64,
+    min_interval_size: u64,
+    padding: u64,
+}
+
+impl BedSection {
+    /// Same section, its intervals being widened by `padding` bases as by `pad_regions`.
+    pub fn padded(self, padding: u64) -> Self {
+        BedSection { padding, ..self }
+    }
+
+    /// The sorted and merged VCF intervals of the section, as `read_bed_regions_with` gives them.
+    pub fn intervals(&self) -> BedSectionIntervals {
+        BedSectionIntervals {
+            section: self.clone(),
+            reader: None,
+            record: csv::StringRecord::new(),
+            position: self.start,
+            pending: None,
+        }
+    }
+}
+
+/// Iterator over the intervals of a `BedSection`, reading its lines one at a time. Cloning it
+/// opens the file again from where it stands, without copying anything that was read.
+///
+/// # Panics
+///
+/// The lines were checked by `index_sorted_bed`, so the iterator panics if they cannot be read
+/// anymore, e.g. because the file changed since.
+#[derive(Debug)]
+pub struct BedSectionIntervals {
+    section: BedSection,
+    /// Opened on the first call to `next`, along with the offset it was opened at
+    reader: Option<(csv::Reader<std::io::Take<BufReader<File>>>, u64)>,
+    record: csv::StringRecord,
+    /// Offset of the next line to read
+    position: u64,
+    /// Interval to be merged with the next ones that overlap it
+    pending: Option<(u64, u64)>,
+}
+
+impl Clone for BedSectionIntervals {
+    fn clone(&self) -> Self {
+        BedSectionIntervals {
+            section: self.section.clone(),
+            reader: None,
+            record: csv::StringRecord::new(),
+            position: self.position,
+            pending: self.pending,
+        }
+    }
+}
+
+impl BedSectionIntervals {
+    /// Next interval of the section that `read_bed_regions_with` would keep, before merging.
+    fn next_interval(&mut self) -> Option<(u64, u64)> {
+        let section = &self.section;
+        let unreadable = |e: &dyn std::fmt::Display| -> ! {
+            panic!(
+                "Cannot read BED file {} again: {}",
+                section.path.display(),
+                e
+            )
+        };
+        loop {
+            if section.end <= self.position {
+                return None;
+            }
+            if self.reader.is_none() {
+                let mut file =
+                    File::open(section.path.as_path()).unwrap_or_else(|e| unreadable(&e));
+                file.seek(SeekFrom::Start(self.position))
+                    .unwrap_or_else(|e| unreadable(&e));
+                let reader = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .delimiter(b'\t')
+                    .flexible(true)
+                    .from_reader(BufReader::new(file).take(section.end - self.position));
+                self.reader = Some((reader, self.position));
+            }
+            let (reader, opened_at) = self.reader.as_mut().unwrap();
+            if !reader
+                .read_record(&mut self.record)
+                .unwrap_or_else(|e| unreadable(&e))
+            {
+                self.position = section.end;
+                return None;
+            }
+            self.position = *opened_at + reader.position().byte();
+            let record: BEDRecord = self
+                .record
+                .deserialize(section.headers.as_deref())
+                .unwrap_or_else(|e| unreadable(&e));
+            // Invalid and short intervals were reported by `index_sorted_bed`
+            if record.start < record.end && record.end - record.start >= section.min_interval_size {
+                let (start, end) = record.vcf_interval();
+                return Some((
+                    start.saturating_sub(section.padding).max(1),
+                    end + section.padding,
+                ));
+            }
+        }
+    }
+}
+
+impl Iterator for BedSectionIntervals {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        while let Some(interval) = self.next_interval() {
+            match &mut self.pending {
+                Some(pending) if interval.0 <= pending.1 => pending.1 = pending.1.max(interval.1),
+                pending => {
+                    if let Some(merged) = pending.replace(interval) {
+                        return Some(merged);
+                    }
+                }
+            }
+        }
+        self.pending.take()
+    }
+}
+
+/// Checks the BED file at `path` as `read_bed_regions_with` does, finding where the lines of
+/// each chromosome are instead of holding their intervals, so that they can be read as each
+/// download goes. The file must be uncompressed, sorted as for `read_sorted_bed_regions`, and
+/// grouped by chromosome: the lines of a chromosome all follow one another. The intervals dropped
+/// on each chromosome are counted as by `read_bed_regions_with_dropped`.
+pub fn index_sorted_bed(
+    path: &Path,
+    options: &BedReadOptions,
+) -> Result<(HashMap<String, BedSection>, DroppedIntervals), DownloadError> {
+    if options.format != RegionFormat::Bed {
+        return Err(DownloadError::Parse(format!(
+            "{} is not a BED file, it cannot be read as each download goes",
+            path.display()
+        )));
+    }
+    let open_error = || DownloadError::io(format!("Cannot open BED file {}", path.display()));
+    let mut reader = BufReader::new(File::open(path).map_err(open_error())?);
+    if reader
+        .fill_buf()
+        .map_err(open_error())?
+        .starts_with(&[0x1f, 0x8b])
+    {
+        return Err(DownloadError::Parse(format!(
+            "{} is compressed, it cannot be read as each download goes",
+            path.display()
+        )));
+    }
+    let mut bed_reader = csv::ReaderBuilder::new()
+        .has_headers(options.has_header)
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_reader(reader);
+    let headers = if options.has_header {
+        Some(Arc::new(
+            bed_reader
+                .headers()
+                .map_err(|e| DownloadError::Parse(format!("Invalid BED header: {}", e)))?
+                .clone(),
+        ))
+    } else {
+        None
+    };
+
+    let path = Arc::new(path.to_path_buf());
+    let mut sections = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut dropped = HashMap::new();
+    // Chromosome of the section being read, where it starts and its last VCF start if any
+    let mut current: Option<(String, u64, Option<u64>)> = None;
+    // Sections without any interval kept are left out, as `read_bed_regions_with` leaves out
+    // their chromosome
+    let mut close_section = |current: Option<(String, u64, Option<u64>)>, end: u64| {
+        if let Some((chromosome, start, Some(_))) = current {
+            sections.insert(
+                chromosome,
+                BedSection {
+                    path: path.clone(),
+                    headers: headers.clone(),
+                    start,
+                    end,
+                    min_interval_size: options.min_interval_size,
+                    padding: 0,
+                },
+            );
+        }
+    };
+    let mut raw_record = csv::StringRecord::new();
+    loop {
+        let position = bed_reader.position().byte();
+        if !bed_reader
+            .read_record(&mut raw_record)
+            .map_err(bed_parse_error)?
+        {
+            close_section(current.take(), position);
+            break;
+        }
+        let record: BEDRecord = raw_record
+            .deserialize(headers.as_deref())
+            .map_err(bed_parse_error)?;
+        if current
+            .as_ref()
+            .is_none_or(|(chromosome, ..)| *chromosome != record.chromosome)
+        {
+            if !seen.insert(record.chromosome.clone()) {
+                return Err(DownloadError::Parse(format!(
+                    "BED file is not grouped by chromosome: {} comes back at line {}",
+                    record.chromosome,
+                    raw_record.position().map_or(0, |p| p.line())
+                )));
+            }
+            close_section(current.take(), position);
+            current = Some((record.chromosome.clone(), position, None));
+        }
+        let line = raw_record.position().map_or(0, |p| p.line());
+        let Some(interval) = checked_interval(line, &record, options, &mut dropped)? else {
+            continue;
+        };
+        let (_, _, previous_start) = current.as_mut().unwrap();
+        if let Some(previous_start) = *previous_start {
+            check_sorted(&record, previous_start)?;
+        }
+        *previous_start = Some(interval.0);
+    }
+    log_dropped_intervals(&dropped, options.min_interval_size);
+    Ok((sections, dropped))
+}
+
+/// Parses a `chromosome:start-end` region, 1-based with both ends included as in samtools,
+/// into a chromosome and a VCF interval. Thousands separators (`chr1:1,000-2,000`) are allowed.
+pub fn parse_region(region: &str) -> Result<(String, (u64, u64)), DownloadError> {
+    let invalid = |reason: &str| {
+        DownloadError::Parse(format!(
+            "Invalid region {}: {} (expected chromosome:start-end)",
+            region, reason
+        ))
+    };
+    let (chromosome, range) = region
+        .rsplit_once(':')
+        .ok_or_else(|| invalid("missing range"))?;
+    let (start, end) = range
+        .split_once('-')
+        .ok_or_else(|| invalid("missing end"))?;
+    let parse_position = |position: &str| {
+        position
+            .replace(',', "")
+            .parse::<u64>()
+            .map_err(|_| invalid(&format!("{} is not a position", position)))
+    };
+    let (start, end) = (parse_position(start)?, parse_position(end)?);
+    if chromosome.is_empty() {
+        return Err(invalid("missing chromosome"));
+    }
+    if start == 0 {
+        return Err(invalid("positions start at 1"));
+    }
+    if start > end {
+        return Err(invalid("start is after end"));
+    }
+    Ok((chromosome.to_string(), (start, end + 1)))
+}
+
+/// Same as `read_bed_regions`, but from `chromosome:start-end` regions (see `parse_region`).
+pub fn read_regions<S: AsRef<str>>(
+    regions: &[S],
+) -> Result<HashMap<String, Vec<(u64, u64)>>, DownloadError> {
+    let mut regions_per_chr: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    for region in regions {
+        let (chromosome, interval) = parse_region(region.as_ref())?;
+        regions_per_chr
+            .entry(chromosome)
+            .or_default()
+            .push(interval);
+    }
+    for regions in regions_per_chr.values_mut() {
+        regions.sort_by_key(|r| r.0);
+        *regions = merge_intervals(std::mem::take(regions));
+    }
+    Ok(regions_per_chr)
+}
+
+/// Union of several per-chromosome sorted and merged regions (e.g. of several BED files), the
+/// overlapping regions being merged.
+pub fn union_regions<I>(regions: I) -> HashMap<String, Vec<(u64, u64)>>
+where
+    I: IntoIterator<Item = HashMap<String, Vec<(u64, u64)>>>,
+{
+    let mut union: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+    for regions_per_chr in regions {
+        for (chromosome, regions) in regions_per_chr {
+            match union.entry(chromosome) {
+                Entry::Vacant(entry) => {
+                    entry.insert(regions);
+                }
+                Entry::Occupied(mut entry) => {
+                    let merged = entry.get_mut();
+                    merged.extend(regions);
+                    merged.sort_by_key(|r| r.0);
+                    *merged = merge_intervals(std::mem::take(merged));
+                }
+            }
+        }
+    }
+    union
+}
+
+/// Removes from per-chromosome sorted and merged regions the parts covered by `excluded` (e.g.
+/// read from a blacklist BED file), a region being split in two around an excluded one inside
+/// it. The chromosomes left without any region are removed.
+pub fn subtract_regions(
+    regions_per_chr: HashMap<String, Vec<(u64, u64)>>,
+    excluded: &HashMap<String, Vec<(u64, u64)>>,
+) -> HashMap<String, Vec<(u64, u64)>> {
+    regions_per_chr
+        .into_iter()
+        .filter_map(|(chromosome, regions)| {
+            let regions = match excluded.get(&chromosome) {
+                Some(excluded) => subtract_intervals(regions, excluded),
+                None => regions,
+            };
+            (!regions.is_empty()).then_some((chromosome, regions))
+        })
+        .collect()
+}
+
+/// Removes the regions shorter than `min_interval_size` bases, e.g. the pieces left around
+/// excluded regions by `subtract_regions`, the number dropped on each chromosome being logged as
+/// and returned by `read_bed_regions_with_dropped`. The chromosomes left without any region are
+/// removed.
+pub fn drop_short_regions(
+    regions_per_chr: HashMap<String, Vec<(u64, u64)>>,
+    min_interval_size: u64,
+) -> (HashMap<String, Vec<(u64, u64)>>, DroppedIntervals) {
+    let mut dropped = HashMap::new();
+    let regions_per_chr = regions_per_chr
+        .into_iter()
+        .filter_map(|(chromosome, mut regions)| {
+            let count = regions.len();
+            regions.retain(|(start, end)| end - start >= min_interval_size);
+            if regions.len() < count {
+                dropped.insert(chromosome.clone(), (count - regions.len()) as u64);
+            }
+            (!regions.is_empty()).then_some((chromosome, regions))
+        })
+        .collect();
+    log_dropped_intervals(&dropped, min_interval_size);
+    (regions_per_chr, dropped)
+}
+
+/// Widens sorted and merged regions (in VCF coordinates) by `padding` bases on both sides,
+/// without going below position 1, and merges the regions that overlap once padded.
+pub fn pad_regions(regions: Vec<(u64, u64)>, padding: u64) -> Vec<(u64, u64)> {
+    merge_intervals(
+        regions
+            .into_iter()
+            .map(|(start, end)| (start.saturating_sub(padding).max(1), end + padding))
+            .collect(),
+    )
+}
+
+/// Chromosomes having BED regions but no entry in the URL list, sorted by name.
+pub fn chromosomes_without_url<V, U>(
+    regions_per_chr: &HashMap<String, V>,
+    urls: &HashMap<String, U>,
+) -> Vec<String> {
+    let mut missing: Vec<String> = regions_per_chr
+        .keys()
+        .filter(|chromosome| !urls.contains_key(*chromosome))
+        .cloned()
+        .collect();
+    missing.sort();
+    missing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intervals::SortedIntervalIntersect;
+
+    fn bed_record(start: u64, end: u64) -> BEDRecord {
+        BEDRecord {
+            chromosome: "chr1".to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn test_bed_to_vcf_boundaries() {
+        let beds = [
+            bed_record(0, 1),
+            bed_record(100, 200),
+            bed_record(500, 1000),
+        ];
+        let mut intersection_check =
+            SortedIntervalIntersect::new(beds.iter().map(BEDRecord::vcf_interval));
+
+        // BED [0, 1) only covers VCF position 1
+        assert_eq!(intersection_check.in_interval(1), Some(true));
+        assert_eq!(intersection_check.in_interval(2), Some(false));
+
+        // BED [100, 200) covers VCF positions 101..=200
+        assert_eq!(intersection_check.in_interval(100), Some(false));
+        assert_eq!(intersection_check.in_interval(101), Some(true));
+        assert_eq!(intersection_check.in_interval(200), Some(true));
+        assert_eq!(intersection_check.in_interval(201), Some(false));
+
+        // BED [500, 1000) covers VCF positions 501..=1000
+        assert_eq!(intersection_check.in_interval(500), Some(false));
+        assert_eq!(intersection_check.in_interval(501), Some(true));
+        assert_eq!(intersection_check.in_interval(1000), Some(true));
+        assert_eq!(intersection_check.in_interval(1001), None);
+    }
+
+    #[test]
+    fn test_malformed_bed_row() {
+        let bed = "chr1\t100\t200\nchr1\tabc\t300\n";
+        assert!(matches!(
+            read_bed_regions(bed.as_bytes()),
+            Err(DownloadError::Parse(_))
+        ));
+        assert!(matches!(
+            read_bed_regions("chr1\t100\n".as_bytes()),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_malformed_url_row() {
+        assert!(matches!(
+            read_url_list("chr1,abcdef\n".as_bytes(), DuplicateUrlPolicy::default()),
+            Err(DownloadError::Parse(_))
+        ));
+        assert!(matches!(
+            read_url_list("chr1,abcdef,\n".as_bytes(), DuplicateUrlPolicy::default()),
+            Err(DownloadError::MissingUrl(chromosome)) if chromosome == "chr1"
+        ));
+    }
+
+    #[test]
+    fn test_chromosomes_without_url() {
+        let regions =
+            read_bed_regions("chr1\t100\t200\nX\t100\t200\nchrM\t1\t10\n".as_bytes()).unwrap();
+        let urls = read_url_list(
+            "chr1,abcdef,http://a\nchrX,abcdef,http://b\n".as_bytes(),
+            DuplicateUrlPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(chromosomes_without_url(&regions, &urls), vec!["X", "chrM"]);
+    }
+
+    #[test]
+    fn test_duplicate_url_entries() {
+        let urls = "chr1,abcdef,http://a\nchr1,abcdef,http://a\nchr1,012345,http://b\n";
+        assert!(matches!(
+            read_url_list(urls.as_bytes(), DuplicateUrlPolicy::Error),
+            Err(DownloadError::Parse(message)) if message.contains("http://a") && message.contains("http://b")
+        ));
+        for policy in [DuplicateUrlPolicy::Warn, DuplicateUrlPolicy::First] {
+            let urls = read_url_list(urls.as_bytes(), policy).unwrap();
+            assert_eq!(urls["chr1"], ("abcdef".to_string(), "http://a".to_string()));
+        }
+
+        assert!(read_url_list(
+            "chr1,abcdef,http://a\nchr1,abcdef,http://a\n".as_bytes(),
+            DuplicateUrlPolicy::Error
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_dataset_url_lists() {
+        let url_list = "chr1,abcdef,http://g1,genomes\nchr1,012345,http://e1,exomes\nchr2,abcdef,http://g2,genomes\n";
+        let url_lists =
+            read_dataset_url_lists(url_list.as_bytes(), DuplicateUrlPolicy::Error).unwrap();
+        assert_eq!(
+            url_lists.keys().collect::<Vec<_>>(),
+            [&Some("exomes".to_string()), &Some("genomes".to_string())]
+        );
+        assert_eq!(
+            url_lists[&Some("exomes".to_string())]["chr1"],
+            ("012345".to_string(), "http://e1".to_string())
+        );
+        assert_eq!(url_lists[&Some("genomes".to_string())].len(), 2);
+        assert!(matches!(
+            read_url_list(url_list.as_bytes(), DuplicateUrlPolicy::Error),
+            Err(DownloadError::Parse(message)) if message.contains("exomes, genomes")
+        ));
+
+        let plain = read_dataset_url_lists(
+            "chr1,abcdef,http://a\n".as_bytes(),
+            DuplicateUrlPolicy::Error,
+        )
+        .unwrap();
+        assert_eq!(plain.keys().collect::<Vec<_>>(), [&None]);
+        assert_eq!(output_name("chr1", Some("exomes")), "chr1.exomes");
+        assert_eq!(output_name("chr1", None), "chr1");
+    }
+
+    #[test]
+    fn test_output_template() {
+        let expand = |template: &str, dataset: Option<&str>| {
+            OutputTemplate::new(template, "2024-05-01")
+                .unwrap()
+                .file_name("chr1", dataset)
+        };
+        assert_eq!(
+            expand("gnomad_{chrom}_filtered.vcf.gz", None),
+            "gnomad_chr1_filtered.vcf.gz"
+        );
+        assert_eq!(
+            expand("{chrom}.{dataset}.vcf.gz", Some("exomes")),
+            "chr1.exomes.vcf.gz"
+        );
+        assert_eq!(
+            expand("{date}_{chrom}{dataset}.vcf", None),
+            "2024-05-01_chr1.vcf"
+        );
+
+        for template in [
+            "gnomad.vcf.gz",
+            "{dataset}.vcf.gz",
+            "out/{chrom}.vcf.gz",
+            "{chrom}_{sample}.vcf.gz",
+            "{chrom}_{date.vcf.gz",
+        ] {
+            assert!(matches!(
+                OutputTemplate::new(template, "2024-05-01"),
+                Err(DownloadError::Parse(_))
+            ));
+        }
+
+        let date = today();
+        assert_eq!(date.len(), 10);
+        assert!(date.as_str() >= "2024-01-01");
+    }
+
+    #[test]
+    fn test_tab_delimited_url_list() {
+        let tsv =
+            "chr1\tabcdef\thttp://example.com/a,b.vcf.bgz\nchr2\t012345\thttp://example.com/c\n";
+        let read = |url_list: &str| read_url_list(url_list.as_bytes(), DuplicateUrlPolicy::Error);
+        let csv =
+            "chr1,abcdef,\"http://example.com/a,b.vcf.bgz\"\nchr2,012345,http://example.com/c\n";
+        assert_eq!(read(tsv).unwrap(), read(csv).unwrap());
+        assert_eq!(
+            read(tsv).unwrap()["chr1"],
+            (
+                "abcdef".to_string(),
+                "http://example.com/a,b.vcf.bgz".to_string()
+            )
+        );
+        assert_eq!(url_list_delimiter(b"chr1,abc,http://a\nchr2\tabc"), b',');
+    }
+
+    #[test]
+    fn test_headered_inputs() {
+        let url_list = "url,chromosome,md5sum\nhttp://example.com/chr1.vcf.bgz,chr1,abcdef\n";
+        let urls = read_dataset_url_lists_with(
+            url_list.as_bytes(),
+            &UrlListOptions {
+                has_header: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            urls[&None],
+            HashMap::from([(
+                "chr1".to_string(),
+                (
+                    "abcdef".to_string(),
+                    "http://example.com/chr1.vcf.bgz".to_string()
+                )
+            )])
+        );
+        // Without the option, the header is read as a row
+        assert!(
+            read_url_list(url_list.as_bytes(), DuplicateUrlPolicy::Error)
+                .unwrap()
+                .contains_key("url")
+        );
+
+        let bed = "end\tstart\tchromosome\n200\t100\tchr1\n";
+        let regions = read_bed_regions_with(
+            bed.as_bytes(),
+            &BedReadOptions {
+                has_header: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(regions["chr1"], vec![(101, 201)]);
+        assert!(read_bed_regions(bed.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_url_list_columns() {
+        let expected = HashMap::from([
+            (
+                "chr1".to_string(),
+                (
+                    "abcdef".to_string(),
+                    "http://example.com/chr1.vcf.bgz".to_string(),
+                ),
+            ),
+            (
+                "chr2".to_string(),
+                (
+                    "012345".to_string(),
+                    "http://example.com/chr2.vcf.bgz".to_string(),
+                ),
+            ),
+        ]);
+        let read = |url_list: &str, has_header, columns: [&str; 3]| {
+            let [chromosome, checksum, url] = columns.map(|column| Some(column.parse().unwrap()));
+            read_dataset_url_lists_with(
+                url_list.as_bytes(),
+                &UrlListOptions {
+                    has_header,
+                    columns: UrlListColumns {
+                        chromosome,
+                        checksum,
+                        url,
+                        dataset: None,
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+
+        let url_list = "http://example.com/chr1.vcf.bgz,123,abcdef,chr1\n\
+                        http://example.com/chr2.vcf.bgz,456,012345,chr2\n";
+        assert_eq!(
+            read(url_list, false, ["4", "3", "1"]).unwrap()[&None],
+            expected
+        );
+
+        let url_list = format!("url,size,md5,contig\n{}", url_list);
+        assert_eq!(
+            read(&url_list, true, ["contig", "md5", "url"]).unwrap()[&None],
+            expected
+        );
+        // Positions and names can be mixed, and the unset fields are found by their usual name
+        let urls = read_dataset_url_lists_with(
+            url_list.as_bytes(),
+            &UrlListOptions {
+                has_header: true,
+                columns: UrlListColumns {
+                    chromosome: Some(Column::Index(3)),
+                    checksum: Some(Column::Name("md5".to_string())),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert_eq!(urls.unwrap()[&None], expected);
+
+        for (url_list, has_header, columns) in [
+            // No header to find the names in
+            ("chr1,abcdef,http://a\n", false, ["contig", "2", "3"]),
+            (
+                "chromosome,md5sum,url\nchr1,abcdef,http://a\n",
+                true,
+                ["contig", "2", "3"],
+            ),
+            // Not enough columns
+            ("chr1,abcdef,http://a\n", false, ["1", "2", "4"]),
+        ] {
+            assert!(
+                matches!(
+                    read(url_list, has_header, columns),
+                    Err(DownloadError::Parse(_))
+                ),
+                "{}",
+                url_list
+            );
+        }
+        assert!("0".parse::<Column>().is_err());
+    }
+
+    #[test]
+    fn test_contig_list() {
+        let url_lists = read_dataset_url_lists(
+            "chrX,abcdef,http://x\nchr1,abcdef,http://1\nchr10,abcdef,http://10\nchr2,abcdef,http://2\n"
+                .as_bytes(),
+            DuplicateUrlPolicy::Error,
+        )
+        .unwrap();
+        let mut table = Vec::new();
+        write_contig_list(&mut table, &url_lists).unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "chromosome\turl\nchr1\thttp://1\nchr10\thttp://10\nchr2\thttp://2\nchrX\thttp://x\n"
+        );
+    }
+
+    #[test]
+    fn test_write_bed_regions() {
+        let bed = "chr1\t100\t200\nchr1\t300\t400\nchr2\t0\t10\n";
+        let regions = read_bed_regions(bed.as_bytes()).unwrap();
+        let mut written = Vec::new();
+        write_bed_regions(
+            &mut written,
+            &[
+                ("chr1".to_string(), regions["chr1"].clone()),
+                ("chr2".to_string(), regions["chr2"].clone()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), bed);
+    }
+
+    #[test]
+    fn test_gzipped_bed() {
+        use std::io::Write;
+
+        let bed = "chr1\t300\t400\nchr1\t100\t200\nchr2\t0\t10\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bed.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        assert_eq!(
+            read_bed_regions(&gzipped[..]).unwrap(),
+            read_bed_regions(bed.as_bytes()).unwrap()
+        );
+        assert_eq!(read_bed_regions(&gzipped[..]).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_gzipped_url_list() {
+        use std::io::Write;
+
+        let url_list = "chr1,abcdef,http://example.com/chr1.vcf.bgz\n\
+                        chr2,012345,http://example.com/chr2.vcf.bgz\n";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(url_list.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        assert_eq!(
+            read_url_list(&gzipped[..], DuplicateUrlPolicy::Error).unwrap(),
+            HashMap::from([
+                (
+                    "chr1".to_string(),
+                    (
+                        "abcdef".to_string(),
+                        "http://example.com/chr1.vcf.bgz".to_string()
+                    )
+                ),
+                (
+                    "chr2".to_string(),
+                    (
+                        "012345".to_string(),
+                        "http://example.com/chr2.vcf.bgz".to_string()
+                    )
+                ),
+            ])
+        );
+
+        // The delimiter is guessed from the decompressed content
+        let tsv = url_list.replace(',', "\t");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(tsv.as_bytes()).unwrap();
+        assert_eq!(
+            read_url_list(&encoder.finish().unwrap()[..], DuplicateUrlPolicy::Error).unwrap(),
+            read_url_list(url_list.as_bytes(), DuplicateUrlPolicy::Error).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_region_formats() {
+        let read = |content: &str, format| {
+            read_bed_regions_with(
+                content.as_bytes(),
+                &BedReadOptions {
+                    format,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let bed = read(
+            "chr1\t99\t150\nchr1\t149\t250\nchr2\t0\t1\n",
+            RegionFormat::Bed,
+        );
+        let interval_list = read(
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n\
+             chr1\t100\t150\t+\ttarget_1\nchr1\t150\t250\t+\ttarget_2\nchr2\t1\t1\t-\ttarget_3\n",
+            RegionFormat::IntervalList,
+        );
+        let region_list = read(
+            "# targets\nchr1:100-150\n\nchr1:150-250\r\nchr2:1-1\n",
+            RegionFormat::RegionList,
+        );
+        assert_eq!(bed["chr1"], [(100, 251)]);
+        assert_eq!(interval_list, bed);
+        assert_eq!(region_list, bed);
+
+        // The same regions filter the same records
+        let vcf = "#CHROM\tPOS\nchr1\t99\nchr1\t100\nchr1\t250\nchr1\t251\n";
+        let filtered = |regions: &HashMap<String, Vec<(u64, u64)>>| {
+            let mut output = Vec::new();
+            crate::filter::filter_vcf_stream(
+                vcf.as_bytes(),
+                &mut output,
+                regions["chr1"].iter().copied(),
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(filtered(&bed), "#CHROM\tPOS\nchr1\t100\nchr1\t250\n");
+        assert_eq!(filtered(&interval_list), filtered(&bed));
+
+        let invalid = read_bed_regions_with(
+            "chr1\t0\t10\t+\tt\n".as_bytes(),
+            &BedReadOptions {
+                format: RegionFormat::IntervalList,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(invalid, Err(DownloadError::Parse(_))));
+    }
+
+    #[test]
+    fn test_sorted_bed() {
+        let sorted = "chr1\t100\t200\nchr1\t150\t300\nchr2\t0\t10\nchr1\t500\t600\n";
+        assert_eq!(
+            read_sorted_bed_regions(sorted.as_bytes()).unwrap(),
+            read_bed_regions(sorted.as_bytes()).unwrap()
+        );
+
+        let unsorted = "chr1\t300\t400\nchr2\t0\t10\nchr1\t100\t200\n";
+        assert!(read_bed_regions(unsorted.as_bytes()).is_ok());
+        assert!(matches!(
+            read_sorted_bed_regions(unsorted.as_bytes()),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_stream_sorted_bed() {
+        let dir = crate::test_utils::test_dir("stream_sorted_bed");
+        let bed = "chr1\t100\t200\nchr1\t150\t300\nchr1\t300\t301\nchr1\t500\t600\n\
+                   chr2\t0\t10\nchr2\t20\t21\nchr3\t5\t6\n";
+        let options = BedReadOptions {
+            sorted: true,
+            min_interval_size: 2,
+            ..Default::default()
+        };
+        std::fs::write(dir.join("regions.bed"), bed).unwrap();
+        let (sections, dropped) = index_sorted_bed(&dir.join("regions.bed"), &options).unwrap();
+        let (regions, read_dropped) =
+            read_bed_regions_with_dropped(bed.as_bytes(), &options).unwrap();
+        assert_eq!(dropped, read_dropped);
+        let streamed: HashMap<String, Vec<(u64, u64)>> = sections
+            .iter()
+            .map(|(chromosome, section)| (chromosome.clone(), section.intervals().collect()))
+            .collect();
+        // chr3 only has a short interval, so it has no section
+        assert_eq!(streamed, regions);
+
+        // A clone goes on from where the iterator stands
+        let mut intervals = sections["chr1"].intervals();
+        assert_eq!(intervals.next(), Some((101, 301)));
+        assert_eq!(intervals.clone().collect::<Vec<_>>(), [(501, 601)]);
+        assert_eq!(intervals.collect::<Vec<_>>(), [(501, 601)]);
+        assert_eq!(
+            sections["chr1"]
+                .clone()
+                .padded(200)
+                .intervals()
+                .collect::<Vec<_>>(),
+            pad_regions(regions["chr1"].clone(), 200)
+        );
+
+        let headered = "chromosome\tstart\tend\nchr1\t100\t200\n";
+        std::fs::write(dir.join("headered.bed"), headered).unwrap();
+        let options = BedReadOptions {
+            sorted: true,
+            has_header: true,
+            ..Default::default()
+        };
+        let sections = index_sorted_bed(&dir.join("headered.bed"), &options)
+            .unwrap()
+            .0;
+        assert_eq!(
+            sections["chr1"].intervals().collect::<Vec<_>>(),
+            [(101, 201)]
+        );
+
+        for invalid in [
+            "chr1\t100\t200\nchr2\t0\t10\nchr1\t500\t600\n",
+            "chr1\t300\t400\nchr1\t100\t200\n",
+        ] {
+            std::fs::write(dir.join("invalid.bed"), invalid).unwrap();
+            assert!(matches!(
+                index_sorted_bed(&dir.join("invalid.bed"), &BedReadOptions::default()),
+                Err(DownloadError::Parse(_))
+            ));
+        }
+        let mut encoder = flate2::write::GzEncoder::new(
+            File::create(dir.join("regions.bed.gz")).unwrap(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(bed.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        assert!(matches!(
+            index_sorted_bed(&dir.join("regions.bed.gz"), &BedReadOptions::default()),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_chr_naming() {
+        assert_eq!(ChrNaming::StripChr.apply("chr1"), "1");
+        assert_eq!(ChrNaming::StripChr.apply("1"), "1");
+        assert_eq!(ChrNaming::AddChr.apply("1"), "chr1");
+        assert_eq!(ChrNaming::AddChr.apply("chr1"), "chr1");
+        assert_eq!(ChrNaming::Keep.apply("1"), "1");
+
+        let regions = read_bed_regions("chr1\t100\t200\n1\t150\t300\n".as_bytes()).unwrap();
+        let entry = |chromosome: &str| (String::new(), format!("{}.vcf.bgz", chromosome));
+        let urls = HashMap::from([
+            ("1".to_string(), entry("1")),
+            ("chr2".to_string(), entry("chr2")),
+        ]);
+        for naming in [ChrNaming::StripChr, ChrNaming::AddChr] {
+            let regions = naming.rename_regions(regions.clone());
+            let urls = naming
+                .rename_urls(urls.clone(), DuplicateUrlPolicy::Error)
+                .unwrap();
+            let chromosome = naming.apply("1");
+            assert_eq!(regions[&chromosome], vec![(101, 301)]);
+            assert!(urls.contains_key(&chromosome));
+            assert!(chromosomes_without_url(&regions, &urls).is_empty());
+        }
+
+        // Both `chr1` and `1`, sent in every order, are a duplicate once renamed
+        for _ in 0..10 {
+            let urls = HashMap::from([
+                ("chr1".to_string(), entry("chr1")),
+                ("1".to_string(), entry("1")),
+            ]);
+            assert!(matches!(
+                ChrNaming::AddChr.rename_urls(urls.clone(), DuplicateUrlPolicy::Error),
+                Err(DownloadError::Parse(message)) if message.contains("chr1 and 1")
+            ));
+            for policy in [DuplicateUrlPolicy::Warn, DuplicateUrlPolicy::First] {
+                assert_eq!(
+                    ChrNaming::AddChr.rename_urls(urls.clone(), policy).unwrap(),
+                    HashMap::from([("chr1".to_string(), entry("chr1"))])
+                );
+                assert_eq!(
+                    ChrNaming::StripChr
+                        .rename_urls(urls.clone(), policy)
+                        .unwrap(),
+                    HashMap::from([("1".to_string(), entry("1"))])
+                );
+            }
+        }
+        // The same entry under both names is no conflict
+        let urls = HashMap::from([
+            ("chr1".to_string(), entry("1")),
+            ("1".to_string(), entry("1")),
+        ]);
+        assert_eq!(
+            ChrNaming::AddChr
+                .rename_urls(urls, DuplicateUrlPolicy::Error)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_pad_regions() {
+        let regions = read_bed_regions("chr1\t10\t20\nchr1\t100\t200\nchr1\t300\t400\n".as_bytes())
+            .unwrap()
+            .remove("chr1")
+            .unwrap();
+        let padded = pad_regions(regions.clone(), 20);
+        // The first interval is clamped at the start of the chromosome
+        assert_eq!(padded, vec![(1, 41), (81, 221), (281, 421)]);
+        // Intervals overlapping once padded are merged
+        assert_eq!(pad_regions(regions, 50), vec![(1, 451)]);
+
+        let mut intersection_check = SortedIntervalIntersect::new(padded.into_iter());
+        for (pos, kept) in [
+            (1, true),
+            (30, true),
+            (60, false),
+            (90, true),
+            (250, false),
+            (300, true),
+            (421, false),
+        ] {
+            assert_eq!(
+                intersection_check.in_interval(pos) == Some(true),
+                kept,
+                "POS {}",
+                pos
+            );
+        }
+    }
+
+    #[test]
+    fn test_inverted_bed_interval() {
+        let bed = "chr1\t100\t200\nchr1\t500\t200\nchr1\t300\t300\nchr1\t600\t700\n";
+        match read_bed_regions(bed.as_bytes()) {
+            Err(DownloadError::Parse(message)) => {
+                assert!(message.contains("line 2"), "{}", message)
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let regions = read_bed_regions_with(
+            bed.as_bytes(),
+            &BedReadOptions {
+                on_invalid_interval: InvalidIntervalPolicy::Skip,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(regions["chr1"], vec![(101, 201), (601, 701)]);
+    }
+
+    #[test]
+    fn test_min_interval_size() {
+        let bed = "chr1\t100\t101\nchr1\t200\t300\nchr1\t300\t305\nchr2\t5\t6\n";
+        let (regions, dropped) = read_bed_regions_with_dropped(
+            bed.as_bytes(),
+            &BedReadOptions {
+                min_interval_size: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // The short interval touching (200, 300) is dropped before it could be merged with it
+        assert_eq!(
+            regions,
+            HashMap::from([("chr1".to_string(), vec![(201, 301)])])
+        );
+        assert_eq!(
+            dropped,
+            HashMap::from([("chr1".to_string(), 2), ("chr2".to_string(), 1)])
+        );
+
+        let vcf = "#CHROM\tPOS\nchr1\t101\t.\tA\tG\nchr1\t250\t.\tA\tG\nchr1\t302\t.\tA\tG\n";
+        let mut output = Vec::new();
+        crate::filter::filter_vcf_stream(
+            vcf.as_bytes(),
+            &mut output,
+            regions["chr1"].iter().copied(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "#CHROM\tPOS\nchr1\t250\t.\tA\tG\n"
+        );
+    }
+
+    #[test]
+    fn test_union_regions() {
+        let panel_a = read_bed_regions("chr1\t100\t200\nchr1\t500\t600\nchr2\t0\t10\n".as_bytes());
+        let panel_b = read_bed_regions("chr1\t150\t300\nchr3\t0\t10\n".as_bytes());
+        assert_eq!(
+            union_regions([panel_a.unwrap(), panel_b.unwrap()]),
+            HashMap::from([
+                ("chr1".to_string(), vec![(101, 301), (501, 601)]),
+                ("chr2".to_string(), vec![(1, 11)]),
+                ("chr3".to_string(), vec![(1, 11)]),
+            ])
+        );
+        assert!(union_regions([]).is_empty());
+    }
+
+    #[test]
+    fn test_subtract_regions() {
+        let regions = read_bed_regions(
+            "chr1\t100\t200\nchr1\t300\t400\nchr2\t0\t100\nchr3\t0\t10\nchr4\t0\t10\n".as_bytes(),
+        )
+        .unwrap();
+        let excluded = read_bed_regions(
+            // Cutting the ends of the first chr1 region and through the middle of the second one
+            "chr1\t50\t120\nchr1\t180\t250\nchr1\t340\t360\n\
+             chr2\t10\t20\nchr2\t30\t40\n\
+             chr3\t0\t10\n\
+             chr5\t0\t10\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            subtract_regions(regions, &excluded),
+            HashMap::from([
+                ("chr1".to_string(), vec![(121, 181), (301, 341), (361, 401)]),
+                ("chr2".to_string(), vec![(1, 11), (21, 31), (41, 101)]),
+                // chr3 is fully excluded, chr4 not at all
+                ("chr4".to_string(), vec![(1, 11)]),
+            ])
+        );
+
+        // An excluded interval covering several regions removes all of them
+        let regions =
+            read_bed_regions("chr1\t100\t200\nchr1\t300\t400\nchr1\t500\t600\n".as_bytes());
+        let excluded = read_bed_regions("chr1\t150\t550\n".as_bytes()).unwrap();
+        assert_eq!(
+            subtract_regions(regions.unwrap(), &excluded)["chr1"],
+            [(101, 151), (551, 601)]
+        );
+    }
+
+    #[test]
+    fn test_drop_short_regions() {
+        let regions = read_bed_regions("chr1\t100\t200\nchr1\t300\t400\nchr2\t0\t100\n".as_bytes());
+        let excluded = read_bed_regions("chr1\t105\t395\nchr2\t5\t100\n".as_bytes()).unwrap();
+        // The pieces left by the subtraction are as short as the BED intervals being dropped
+        assert_eq!(
+            drop_short_regions(subtract_regions(regions.unwrap(), &excluded), 10),
+            (
+                HashMap::new(),
+                HashMap::from([("chr1".to_string(), 2), ("chr2".to_string(), 1)])
+            )
+        );
+        let regions = read_bed_regions("chr1\t100\t200\nchr1\t300\t400\n".as_bytes()).unwrap();
+        assert_eq!(
+            drop_short_regions(subtract_regions(regions, &excluded), 5).0["chr1"],
+            [(101, 106), (396, 401)]
+        );
+    }
+
+    #[test]
+    fn test_inline_regions() {
+        assert_eq!(
+            read_regions(&["chr1:100-200"]).unwrap(),
+            read_bed_regions("chr1\t99\t200\n".as_bytes()).unwrap()
+        );
+        assert_eq!(
+            read_regions(&["chr2:1,000-2,000", "HLA-A*01:01:1-10", "chr2:1500-2500"]).unwrap(),
+            HashMap::from([
+                ("chr2".to_string(), vec![(1000, 2501)]),
+                ("HLA-A*01:01".to_string(), vec![(1, 11)]),
+            ])
+        );
+        for invalid in [
+            "chr1",
+            "chr1:100",
+            ":1-10",
+            "chr1:0-10",
+            "chr1:20-10",
+            "chr1:a-10",
+        ] {
+            assert!(
+                matches!(read_regions(&[invalid]), Err(DownloadError::Parse(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::error::DownloadError;
+use crate::records::URLDownloadRecord;
+
+/// A published gnomAD release, with the URL list of its per-chromosome VCFs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Release {
+    /// Major version, as selected on the command line (`v4`)
+    pub version: &'static str,
+    /// Full release number (`4.1`)
+    pub release: &'static str,
+    pub dataset: &'static str,
+    pub reference: &'static str,
+    /// `chromosome,md5sum,url` rows, as published by gnomAD
+    url_list: &'static str,
+}
+
+/// Releases known to `gen-urls`. Adding a release only takes its URL list and a row here.
+pub const RELEASES: &[Release] = &[
+    Release {
+        version: "v3",
+        release: "3.1.2",
+        dataset: "genomes",
+        reference: "grch38",
+        url_list: include_str!("../gnomadv3_urls_download.csv"),
+    },
+    Release {
+        version: "v4",
+        release: "4.1",
+        dataset: "genomes",
+        reference: "grch38",
+        url_list: include_str!("../gnomadv4_urls_download.csv"),
+    },
+];
+
+impl Release {
+    /// The release matching `version` (either `v4` or `4.1`), `dataset` and `reference`,
+    /// compared case-insensitively.
+    pub fn find(version: &str, dataset: &str, reference: &str) -> Result<Release, DownloadError> {
+        RELEASES
+            .iter()
+            .find(|release| {
+                (release.version.eq_ignore_ascii_case(version) || release.release == version)
+                    && release.dataset.eq_ignore_ascii_case(dataset)
+                    && release.reference.eq_ignore_ascii_case(reference)
+            })
+            .copied()
+            .ok_or_else(|| {
+                let known: Vec<String> = RELEASES
+                    .iter()
+                    .map(|release| {
+                        format!(
+                            "{} ({}) {} {}",
+                            release.version, release.release, release.dataset, release.reference
+                        )
+                    })
+                    .collect();
+                DownloadError::Parse(format!(
+                    "No gnomAD release {} {} {}, known releases are: {}",
+                    version,
+                    dataset,
+                    reference,
+                    known.join(", ")
+                ))
+            })
+    }
+
+    /// URL list rows of every chromosome of the release.
+    pub fn urls(&self) -> Vec<URLDownloadRecord> {
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(self.url_list.as_bytes())
+            .deserialize()
+            .map(|record| record.expect("Embedded URL lists are valid"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::records::{read_url_list, write_url_list, DuplicateUrlPolicy};
+
+    #[test]
+    fn test_generated_url_lists() {
+        let mut contigs: Vec<String> = (1..=22).map(|i| format!("chr{}", i)).collect();
+        contigs.extend(["chrX".to_string(), "chrY".to_string()]);
+
+        for release in RELEASES {
+            let mut url_list = Vec::new();
+            write_url_list(&mut url_list, &release.urls()).unwrap();
+            let urls = read_url_list(url_list.as_slice(), DuplicateUrlPolicy::Error).unwrap();
+
+            let mut chromosomes: Vec<&String> = urls.keys().collect();
+            chromosomes.sort_by_key(|chrom| contigs.iter().position(|contig| contig == *chrom));
+            assert_eq!(chromosomes, contigs.iter().collect::<Vec<_>>());
+            for (md5sum, url) in urls.values() {
+                assert_eq!(md5sum.len(), 32);
+                assert!(url.contains(&format!("/release/{}/", release.release)));
+                assert!(url.contains(release.dataset));
+            }
+        }
+
+        assert_eq!(
+            Release::find("4.1", "Genomes", "GRCh38").unwrap(),
+            Release::find("v4", "genomes", "grch38").unwrap()
+        );
+        assert!(Release::find("v4", "genomes", "grch37").is_err());
+    }
+}
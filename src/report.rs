@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::download::DownloadOutcome;
+use crate::error::DownloadError;
+use crate::records::DroppedIntervals;
+
+/// Entry of the JSON report written with `--report`, one per chromosome.
+///
+/// Record counts and sizes are only known for successful downloads.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChromosomeReport {
+    pub chromosome: String,
+    pub url: String,
+    pub expected_checksum: String,
+    pub actual_checksum: Option<String>,
+    pub total_records: Option<u64>,
+    pub kept_records: Option<u64>,
+    pub bytes_downloaded: Option<u64>,
+    pub elapsed_seconds: f64,
+    /// BED intervals of the chromosome dropped as shorter than `--min-interval-size`
+    #[serde(default)]
+    pub dropped_intervals: u64,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+impl ChromosomeReport {
+    fn new(outcome: &DownloadOutcome, dropped: &DroppedIntervals) -> Self {
+        let stats = outcome.result.as_ref().ok();
+        let actual_checksum = match &outcome.result {
+            Ok(stats) => stats.actual_checksum.clone(),
+            Err(DownloadError::ChecksumMismatch { actual, .. }) => Some(actual.clone()),
+            Err(_) => None,
+        };
+        ChromosomeReport {
+            chromosome: outcome.chrom_name.clone(),
+            url: outcome.url.clone(),
+            expected_checksum: outcome.expected_checksum.clone(),
+            actual_checksum,
+            total_records: stats.map(|stats| stats.total_records),
+            kept_records: stats.map(|stats| stats.kept_records),
+            bytes_downloaded: stats.map(|stats| stats.bytes_downloaded),
+            elapsed_seconds: outcome.elapsed.as_secs_f64(),
+            dropped_intervals: dropped.get(&outcome.chrom_name).copied().unwrap_or(0),
+            success: outcome.result.is_ok(),
+            error: outcome.result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+/// Writes the outcome of every download as a JSON array of `ChromosomeReport`, along with the
+/// BED intervals `dropped` on its chromosome.
+pub fn write_report<W: Write>(
+    writer: W,
+    outcomes: &[DownloadOutcome],
+    dropped: &DroppedIntervals,
+) -> std::io::Result<()> {
+    let reports: Vec<ChromosomeReport> = outcomes
+        .iter()
+        .map(|outcome| ChromosomeReport::new(outcome, dropped))
+        .collect();
+    serde_json::to_writer_pretty(writer, &reports)?;
+    Ok(())
+}
+
+/// Chromosomes whose download failed, according to a report written by `write_report`.
+pub fn read_failed_chromosomes<R: Read>(reader: R) -> Result<HashSet<String>, DownloadError> {
+    let reports: Vec<ChromosomeReport> = serde_json::from_reader(reader)
+        .map_err(|e| DownloadError::Parse(format!("Invalid report: {}", e)))?;
+    Ok(reports
+        .into_iter()
+        .filter(|report| !report.success)
+        .map(|report| report.chromosome)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::download::DownloadStats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_report_fields() {
+        let outcomes = [
+            DownloadOutcome {
+                chrom_name: "chr1".to_string(),
+                url: "http://example.com/chr1.vcf.bgz".to_string(),
+                expected_checksum: "abc".to_string(),
+                elapsed: Duration::from_millis(1500),
+                result: Ok(DownloadStats {
+                    total_records: 3,
+                    bad_lines: 0,
+                    kept_records: 1,
+                    bytes_downloaded: 100,
+                    actual_checksum: Some("abc".to_string()),
+                    empty_intervals: None,
+                }),
+            },
+            DownloadOutcome {
+                chrom_name: "chr2".to_string(),
+                url: "http://example.com/chr2.vcf.bgz".to_string(),
+                expected_checksum: "abc".to_string(),
+                elapsed: Duration::from_secs(2),
+                result: Err(DownloadError::ChecksumMismatch {
+                    expected: "abc".to_string(),
+                    actual: "def".to_string(),
+                }),
+            },
+        ];
+
+        let mut json = Vec::new();
+        let dropped = DroppedIntervals::from([("chr1".to_string(), 2)]);
+        write_report(&mut json, &outcomes, &dropped).unwrap();
+        let reports: Vec<ChromosomeReport> = serde_json::from_slice(&json).unwrap();
+
+        assert_eq!(
+            reports[0],
+            ChromosomeReport {
+                chromosome: "chr1".to_string(),
+                url: "http://example.com/chr1.vcf.bgz".to_string(),
+                expected_checksum: "abc".to_string(),
+                actual_checksum: Some("abc".to_string()),
+                total_records: Some(3),
+                kept_records: Some(1),
+                bytes_downloaded: Some(100),
+                elapsed_seconds: 1.5,
+                dropped_intervals: 2,
+                success: true,
+                error: None,
+            }
+        );
+        assert!(!reports[1].success);
+        assert_eq!(reports[1].actual_checksum.as_deref(), Some("def"));
+        assert_eq!(reports[1].kept_records, None);
+        assert_eq!(reports[1].dropped_intervals, 0);
+        assert!(reports[1].error.is_some());
+
+        assert_eq!(
+            read_failed_chromosomes(json.as_slice()).unwrap(),
+            HashSet::from(["chr2".to_string()])
+        );
+        assert!(matches!(
+            read_failed_chromosomes("{\"chromosome\": \"chr1\"}".as_bytes()),
+            Err(DownloadError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,214 @@
+//! Helpers shared by the unit tests: small VCFs and a local HTTP server to download them from.
+
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const TEST_VCF: &[&str] = &[
+    "##fileformat=VCFv4.3",
+    "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO",
+    "chr1\t50\t.\tA\tG\t.\tPASS\t.",
+    "chr1\t150\t.\tC\tT\t.\tPASS\t.",
+    "chr1\t250\t.\tG\tA\t.\tPASS\t.",
+];
+
+pub fn bgzf_bytes(lines: &[&str]) -> Vec<u8> {
+    let mut writer = noodles::bgzf::io::Writer::new(Vec::new());
+    for line in lines {
+        writeln!(writer, "{}", line).unwrap();
+    }
+    writer.finish().unwrap()
+}
+
+pub fn md5_hex<B: AsRef<[u8]>>(bytes: B) -> String {
+    crate::checksum::hex(&<md5::Md5 as md5::Digest>::digest(bytes))
+}
+
+pub fn read_bgzf_lines(path: &Path) -> Vec<String> {
+    noodles::bgzf::Reader::new(File::open(path).unwrap())
+        .lines()
+        .map(Result::unwrap)
+        .collect()
+}
+
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smart_gnomad_downloader_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Serves `body` over HTTP on a random local port, honoring `Range: bytes=<offset>-[<end>]`
+/// headers. Returns the URL to query and the list of requested offsets.
+pub fn serve(body: Vec<u8>) -> (String, Arc<Mutex<Vec<usize>>>) {
+    serve_with_failures(body, 0)
+}
+
+/// Same as `serve`, but the first `failures` requests get a `503 Service Unavailable`.
+pub fn serve_with_failures(body: Vec<u8>, failures: usize) -> (String, Arc<Mutex<Vec<usize>>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/test.vcf.bgz", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let requests_clone = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut offset = 0;
+            let mut end = body.len();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(range) = header.to_lowercase().strip_prefix("range: bytes=") {
+                    let (start, last) = range.trim().split_once('-').unwrap();
+                    offset = start.parse().unwrap();
+                    if !last.is_empty() {
+                        end = last.parse::<usize>().unwrap() + 1;
+                    }
+                }
+            }
+            let mut requests = requests_clone.lock().unwrap();
+            requests.push(offset);
+            if requests.len() <= failures {
+                write!(
+                    stream,
+                    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                continue;
+            }
+            drop(requests);
+            let status = if offset > 0 || end < body.len() {
+                "206 Partial Content"
+            } else {
+                "200 OK"
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+                status,
+                end - offset
+            )
+            .unwrap();
+            stream.write_all(&body[offset..end]).unwrap();
+        }
+    });
+    (url, requests)
+}
+
+/// Serves `body` without a `Content-Length` header, the end of the body being marked by the
+/// connection closing. Range headers are ignored.
+pub fn serve_without_length(body: Vec<u8>) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/test.vcf.bgz", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    url
+}
+
+/// Same as `serve_without_length`, but answers `401 Unauthorized` to requests missing the
+/// `required` header line (compared case-insensitively, e.g. `"Authorization: Bearer token"`).
+pub fn serve_requiring_header(body: Vec<u8>, required: &str) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/test.vcf.bgz", listener.local_addr().unwrap());
+    let required = required.to_lowercase();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut authorized = false;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                authorized |= header.trim().to_lowercase() == required;
+            }
+            if authorized {
+                write!(stream, "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").unwrap();
+                stream.write_all(&body).unwrap();
+            } else {
+                write!(
+                    stream,
+                    "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        }
+    });
+    url
+}
+
+/// Same as `serve_without_length`, waiting `delay` after sending each byte of the body.
+pub fn serve_slowly(body: Vec<u8>, delay: Duration) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/test.vcf.bgz", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").unwrap();
+            for byte in &body {
+                // The client gave up
+                if stream.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(delay);
+            }
+        }
+    });
+    url
+}
+
+/// Answers any request with a redirect to `target`, returning the URL to query.
+pub fn serve_redirect(target: String) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/redirect.vcf.bgz", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            write!(
+                stream,
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                target
+            )
+            .unwrap();
+        }
+    });
+    url
+}
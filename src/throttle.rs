@@ -0,0 +1,104 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Download rate of `max_rate` bytes per second shared by every `ThrottledReader` reading under
+/// it: the readers active at a given time split the rate between them, so that all of it is
+/// used however many downloads are left.
+#[derive(Debug)]
+pub struct RateLimit {
+    max_rate: u64,
+    /// When the bytes read so far by all the readers are due
+    due: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(max_rate: u64) -> Self {
+        RateLimit {
+            max_rate: max_rate.max(1),
+            due: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Counts `bytes` just read, returning how long to wait before reading more. Time spent
+    /// idle is not made up for by a burst afterwards.
+    fn consume(&self, bytes: u64) -> Duration {
+        let mut due = self.due.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        *due = (*due).max(now) + Duration::from_secs_f64(bytes as f64 / self.max_rate as f64);
+        due.saturating_duration_since(now)
+    }
+}
+
+/// Reader sleeping as needed to keep the throughput of `inner`, along with the other readers
+/// under the same `RateLimit`, under its rate.
+pub struct ThrottledReader<R> {
+    inner: R,
+    limit: Arc<RateLimit>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    pub fn new(inner: R, limit: Arc<RateLimit>) -> Self {
+        ThrottledReader { inner, limit }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Reading at most a second's worth of data keeps the bursts small
+        let max_len = buf
+            .len()
+            .min(self.limit.max_rate.try_into().unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..max_len])?;
+        thread::sleep(self.limit.consume(read as u64));
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_throttled_reader() {
+        let data = vec![7u8; 3000];
+        let start = Instant::now();
+        let mut read = Vec::new();
+        ThrottledReader::new(data.as_slice(), Arc::new(RateLimit::new(2000)))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_shared_rate_limit() {
+        let limit = Arc::new(RateLimit::new(4000));
+        let read_all = |length: usize| {
+            let limit = limit.clone();
+            thread::spawn(move || {
+                let data = vec![7u8; length];
+                let mut read = Vec::new();
+                ThrottledReader::new(data.as_slice(), limit)
+                    .read_to_end(&mut read)
+                    .unwrap();
+                assert_eq!(read.len(), length);
+            })
+        };
+
+        // Two readers at once share the rate
+        let start = Instant::now();
+        let (first, second) = (read_all(2000), read_all(2000));
+        first.join().unwrap();
+        second.join().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+
+        // A reader left alone gets all of it
+        let start = Instant::now();
+        read_all(2000).join().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500));
+        assert!(elapsed < Duration::from_millis(1000), "{:?}", elapsed);
+    }
+}
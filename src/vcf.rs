@@ -0,0 +1,200 @@
+//! Reading the fields of VCF lines as they are streamed, without parsing whole records.
+
+use std::fmt;
+
+/// Index of the CHROM column of a data line.
+pub const CHROM: usize = 0;
+/// Index of the POS column of a data line.
+pub const POS: usize = 1;
+/// Index of the REF column of a data line.
+pub const REF: usize = 3;
+/// Index of the FILTER column of a data line.
+pub const FILTER: usize = 6;
+/// Index of the INFO column of a data line.
+pub const INFO: usize = 7;
+/// Index of the FORMAT column of a data line, followed by the sample columns.
+pub const FORMAT: usize = 8;
+
+/// Why a field could not be read from a VCF line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VcfParseError {
+    /// The line is a header line (`##` meta-information or `#CHROM`), not a record
+    Header,
+    /// The line has fewer tab-separated fields than the one asked for
+    MissingField(&'static str),
+    /// The POS field is not a number fitting in 64 bits
+    InvalidPos(String),
+}
+
+impl fmt::Display for VcfParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VcfParseError::Header => write!(f, "header line, not a record"),
+            VcfParseError::MissingField(field) => write!(f, "missing {} field", field),
+            VcfParseError::InvalidPos(pos) => {
+                write!(f, "POS {} is not a number, or does not fit in 64 bits", pos)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VcfParseError {}
+
+/// Whether a line is a header line rather than a record.
+pub fn is_header(line: &str) -> bool {
+    line.starts_with('#')
+}
+
+/// Field `index` (e.g. `INFO`) of a data line, `None` if the line is too short. Fields are
+/// separated by tabs only, spaces being part of the fields.
+pub fn field(line: &str, index: usize) -> Option<&str> {
+    line.split('\t').nth(index)
+}
+
+/// Extracts the POS column of a data line.
+pub fn parse_pos(line: &str) -> Result<u64, VcfParseError> {
+    if is_header(line) {
+        return Err(VcfParseError::Header);
+    }
+    // Called on every data line: locate the two first tabs rather than splitting the line
+    let bytes = line.as_bytes();
+    let start = bytes
+        .iter()
+        .position(|&byte| byte == b'\t')
+        .ok_or(VcfParseError::MissingField("POS"))?
+        + 1;
+    let end = bytes[start..]
+        .iter()
+        .position(|&byte| byte == b'\t')
+        .map_or(bytes.len(), |length| start + length);
+    let pos = &line[start..end];
+    pos.parse()
+        .map_err(|_| VcfParseError::InvalidPos(pos.to_string()))
+}
+
+/// The fields `indices` of a line (data or `#CHROM`), in this order, `None` if one is missing.
+pub fn select_fields(line: &str, indices: &[usize]) -> Option<String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let selected = indices
+        .iter()
+        .map(|&index| fields.get(index).copied())
+        .collect::<Option<Vec<&str>>>()?;
+    Some(selected.join("\t"))
+}
+
+/// FILTER column of a data line.
+pub fn filter(line: &str) -> Option<&str> {
+    field(line, FILTER)
+}
+
+/// INFO column of a data line.
+pub fn info(line: &str) -> Option<&str> {
+    field(line, INFO)
+}
+
+/// Value of `key` in an INFO column, the empty string for a flag. Keys are matched whole, so
+/// that `AF` is not found in `AF_afr=0.1`.
+pub fn info_value<'a>(info: &'a str, key: &str) -> Option<&'a str> {
+    info.split(';')
+        .find_map(|entry| match entry.strip_prefix(key)? {
+            "" => Some(""),
+            rest => rest.strip_prefix('='),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_pos() {
+        assert_eq!(parse_pos("chr1\t100\t.\tA\tG\t.\tPASS\t."), Ok(100));
+        // Only the first two fields are needed
+        assert_eq!(parse_pos("chr1\t100"), Ok(100));
+        assert_eq!(parse_pos("chrUn\t4300000001\t."), Ok(4_300_000_001));
+
+        assert_eq!(
+            parse_pos("##fileformat=VCFv4.3"),
+            Err(VcfParseError::Header)
+        );
+        assert_eq!(
+            parse_pos("#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO"),
+            Err(VcfParseError::Header)
+        );
+        assert_eq!(parse_pos(""), Err(VcfParseError::MissingField("POS")));
+        assert_eq!(parse_pos("chr1"), Err(VcfParseError::MissingField("POS")));
+        assert_eq!(
+            parse_pos("chr1\tabc\t."),
+            Err(VcfParseError::InvalidPos("abc".to_string()))
+        );
+        assert_eq!(
+            parse_pos("chr1\t-5\t."),
+            Err(VcfParseError::InvalidPos("-5".to_string()))
+        );
+        assert_eq!(
+            parse_pos("chr1\t\t."),
+            Err(VcfParseError::InvalidPos(String::new()))
+        );
+        assert_eq!(
+            parse_pos("chr1\t99999999999999999999"),
+            Err(VcfParseError::InvalidPos(
+                "99999999999999999999".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_space_delimited_line() {
+        // Spaces do not separate fields: the whole line is the CHROM field
+        let line = "chr1 100 . A G . PASS AF=0.1";
+        assert_eq!(parse_pos(line), Err(VcfParseError::MissingField("POS")));
+        assert_eq!(field(line, CHROM), Some(line));
+        assert_eq!(info(line), None);
+
+        // Spaces within a field are kept
+        let line = "chr1\t100\t.\tA\tG\t.\tPASS\tDESC=a b";
+        assert_eq!(info(line), Some("DESC=a b"));
+        assert_eq!(
+            parse_pos("chr1\t 100"),
+            Err(VcfParseError::InvalidPos(" 100".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        let line = "chr1\t100\trs1\tAT\tG\t50\tq10;s50\tAF=0.1;DB";
+        assert_eq!(field(line, CHROM), Some("chr1"));
+        assert_eq!(field(line, REF), Some("AT"));
+        assert_eq!(filter(line), Some("q10;s50"));
+        assert_eq!(info(line), Some("AF=0.1;DB"));
+        assert_eq!(field(line, 8), None);
+
+        // Sites-only lines may stop before INFO
+        let line = "chr1\t100\t.\tA\tG\t.\tPASS";
+        assert_eq!(filter(line), Some("PASS"));
+        assert_eq!(info(line), None);
+    }
+
+    #[test]
+    fn test_select_fields() {
+        let line = "chr1\t100\t.\tA\tG\t.\tPASS\t.\tGT\t0/1\t1/1";
+        assert_eq!(
+            select_fields(line, &[CHROM, POS, FORMAT, 10]),
+            Some("chr1\t100\tGT\t1/1".to_string())
+        );
+        assert_eq!(select_fields(line, &[10, 9]), Some("1/1\t0/1".to_string()));
+        assert_eq!(select_fields(line, &[CHROM, 11]), None);
+    }
+
+    #[test]
+    fn test_info_value() {
+        let info = "AF_afr=0.1;AF=0.2,0.3;DB;END=500";
+        assert_eq!(info_value(info, "AF"), Some("0.2,0.3"));
+        assert_eq!(info_value(info, "AF_afr"), Some("0.1"));
+        assert_eq!(info_value(info, "DB"), Some(""));
+        assert_eq!(info_value(info, "END"), Some("500"));
+        assert_eq!(info_value(info, "E"), None);
+        assert_eq!(info_value(info, "SVLEN"), None);
+        assert_eq!(info_value(".", "AF"), None);
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use indicatif::ProgressBar;
+
+use crate::checksum::{ChecksumAlgorithm, ChecksumWriter};
+use crate::download::verified_marker_path;
+use crate::error::DownloadError;
+
+/// Integrity of an existing output, as checked by `verify_output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyStatus {
+    /// The output is unchanged since it was produced from a verified download
+    Ok,
+    /// The output has no `.verified` marker (e.g. downloaded with `--no-verify`) to check it against
+    Unverified,
+    /// The output was produced from a download with another checksum than the expected one
+    SourceChanged,
+    /// The output was modified or corrupted since it was produced
+    Corrupted,
+}
+
+impl VerifyStatus {
+    pub fn is_ok(self) -> bool {
+        self == VerifyStatus::Ok
+    }
+}
+
+impl fmt::Display for VerifyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Unverified => "unverified",
+            VerifyStatus::SourceChanged => "source changed",
+            VerifyStatus::Corrupted => "corrupted",
+        })
+    }
+}
+
+/// Checks the output of a past download of a source with checksum `expected_checksum` against
+/// its `.verified` marker, without downloading anything.
+pub fn verify_output(
+    output_path: &Path,
+    expected_checksum: &str,
+) -> Result<VerifyStatus, DownloadError> {
+    let Ok(marker) = std::fs::read_to_string(verified_marker_path(output_path)) else {
+        return Ok(VerifyStatus::Unverified);
+    };
+    let field = |name: &str| {
+        marker
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('\t'))
+    };
+    let (Some(source_checksum), Some(output_md5)) = (field("source_checksum"), field("output_md5"))
+    else {
+        return Ok(VerifyStatus::Unverified);
+    };
+    if !source_checksum.eq_ignore_ascii_case(expected_checksum) {
+        return Ok(VerifyStatus::SourceChanged);
+    }
+
+    let read_error = |source| DownloadError::Io {
+        context: format!("Cannot read {}", output_path.display()),
+        source,
+    };
+    let mut checksum_writer =
+        ChecksumWriter::new(Some(ChecksumAlgorithm::Md5), ProgressBar::hidden(), None);
+    std::io::copy(
+        &mut File::open(output_path).map_err(read_error)?,
+        &mut checksum_writer,
+    )
+    .map_err(read_error)?;
+    match checksum_writer.digest(output_md5, &output_path.display().to_string()) {
+        Ok(_) => Ok(VerifyStatus::Ok),
+        Err(DownloadError::ChecksumMismatch { .. }) => Ok(VerifyStatus::Corrupted),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the status of each checked output as a tab-separated table.
+pub fn write_verify_table<W: Write>(
+    mut writer: W,
+    statuses: &[(String, VerifyStatus)],
+) -> std::io::Result<()> {
+    writeln!(writer, "chromosome\tstatus")?;
+    for (chrom_name, status) in statuses {
+        writeln!(writer, "{}\t{}", chrom_name, status)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::download::{smart_save_vcf_from_url, DownloadOptions};
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_verify_outputs() {
+        let body = bgzf_bytes(TEST_VCF);
+        let expected_md5 = md5_hex(&body);
+        let (url, _) = serve(body);
+        let dir = test_dir("verify_outputs");
+        for chrom_name in ["chr1", "chr2"] {
+            smart_save_vcf_from_url(
+                &url,
+                &expected_md5,
+                [(100, 200)].into_iter(),
+                &dir.join(format!("{}.vcf.gz", chrom_name)),
+                ProgressBar::hidden(),
+                &DownloadOptions::default(),
+            )
+            .unwrap();
+        }
+        let mut corrupted = std::fs::read(dir.join("chr2.vcf.gz")).unwrap();
+        corrupted[30] ^= 0xff;
+        std::fs::write(dir.join("chr2.vcf.gz"), corrupted).unwrap();
+        std::fs::write(dir.join("chr3.vcf.gz"), "no marker").unwrap();
+
+        let status = |chrom_name: &str, checksum: &str| {
+            verify_output(&dir.join(format!("{}.vcf.gz", chrom_name)), checksum).unwrap()
+        };
+        assert_eq!(status("chr1", &expected_md5), VerifyStatus::Ok);
+        assert_eq!(
+            status("chr1", &expected_md5.to_uppercase()),
+            VerifyStatus::Ok
+        );
+        assert_eq!(status("chr1", "abcdef"), VerifyStatus::SourceChanged);
+        assert_eq!(status("chr2", &expected_md5), VerifyStatus::Corrupted);
+        assert_eq!(status("chr3", &expected_md5), VerifyStatus::Unverified);
+
+        let mut table = Vec::new();
+        write_verify_table(
+            &mut table,
+            &[
+                ("chr1".to_string(), VerifyStatus::Ok),
+                ("chr2".to_string(), VerifyStatus::Corrupted),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(table).unwrap(),
+            "chromosome\tstatus\nchr1\tok\nchr2\tcorrupted\n"
+        );
+    }
+}
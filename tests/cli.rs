@@ -0,0 +1,551 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use md5::Digest;
+
+/// Fresh temporary directory of the test `name`.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smart_gnomad_downloader_cli_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Serves the VCF `bytes` compressed as bgzf (see `serve`), returning its URL and the MD5 of the
+/// download.
+fn served_vcf(bytes: &[u8]) -> (String, String) {
+    let mut writer = noodles::bgzf::io::Writer::new(Vec::new());
+    writer.write_all(bytes).unwrap();
+    let body = writer.finish().unwrap();
+    let md5 = md5::Md5::digest(&body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    (serve(body), md5)
+}
+
+/// Serves `body` to any request over HTTP on a random local port, returning the URL to query.
+fn serve(body: Vec<u8>) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/chr1.vcf.bgz", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    url
+}
+
+/// Redirects any request to `target`, returning the URL to query.
+fn serve_redirect(target: String) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://{}/mirror/chr1.vcf.bgz",
+        listener.local_addr().unwrap()
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                target
+            )
+            .unwrap();
+        }
+    });
+    url
+}
+
+#[test]
+fn test_several_beds() {
+    let dir = test_dir("several_beds");
+    std::fs::write(dir.join("panel_a.bed"), "chr1\t100\t200\n").unwrap();
+    std::fs::write(dir.join("panel_b.bed"), "chr1\t150\t260\nchr2\t0\t10\n").unwrap();
+    let vcf = "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t50\t.\tA\tG\t.\tPASS\t.
+chr1\t150\t.\tC\tT\t.\tPASS\t.
+chr1\t250\t.\tG\tA\t.\tPASS\t.
+chr1\t300\t.\tT\tC\t.\tPASS\t.
+";
+    let (url, md5) = served_vcf(vcf.as_bytes());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .arg("--bed")
+        .arg(dir.join("panel_a.bed"))
+        .arg("-b")
+        .arg(dir.join("panel_b.bed"))
+        .args([
+            "--url",
+            &url,
+            "--md5",
+            &md5,
+            "--chrom",
+            "chr1",
+            "--no-compress",
+        ])
+        .arg("--output-dir")
+        .arg(&dir)
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+
+    assert!(output.success());
+    // The overlapping intervals of chr1 are merged into 101-260
+    assert_eq!(
+        std::fs::read_to_string(dir.join("chr1.vcf")).unwrap(),
+        "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t150\t.\tC\tT\t.\tPASS\t.
+chr1\t250\t.\tG\tA\t.\tPASS\t.
+"
+    );
+}
+
+#[test]
+fn test_bed_from_stdin() {
+    let dir = test_dir("bed_stdin");
+    std::fs::write(
+        dir.join("urls.csv"),
+        "chr1,abcdef,http://127.0.0.1:9/chr1.vcf.bgz\nchr2,abcdef,http://127.0.0.1:9/chr2.vcf.bgz\n",
+    )
+    .unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .args(["--bed", "-", "--url-list"])
+        .arg(dir.join("urls.csv"))
+        .arg("--dry-run")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"chr2\t0\t10\nchr1\t100\t200\nchr1\t300\t400\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "chromosome\tintervals\tspan_bp\turl\n\
+         chr1\t2\t200\thttp://127.0.0.1:9/chr1.vcf.bgz\n\
+         chr2\t1\t10\thttp://127.0.0.1:9/chr2.vcf.bgz\n"
+    );
+}
+
+#[test]
+fn test_single_url() {
+    let dir = test_dir("single_url");
+    std::fs::write(dir.join("regions.bed"), "chr1\t100\t200\nchr2\t100\t200\n").unwrap();
+    let vcf = "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t50\t.\tA\tG\t.\tPASS\t.
+chr1\t150\t.\tC\tT\t.\tPASS\t.
+";
+    let (url, md5) = served_vcf(vcf.as_bytes());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .arg("--bed")
+        .arg(dir.join("regions.bed"))
+        .args([
+            "--url",
+            &url,
+            "--md5",
+            &md5,
+            "--chrom",
+            "chr1",
+            "--no-compress",
+        ])
+        .arg("--output-dir")
+        .arg(&dir)
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("chr1.vcf")).unwrap(),
+        "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t150\t.\tC\tT\t.\tPASS\t.
+"
+    );
+    // chr2 is not wanted rather than missing from the URL list
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("No URL found"));
+
+    let conflicting = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .arg("--bed")
+        .arg(dir.join("regions.bed"))
+        .args([
+            "--url",
+            &url,
+            "--md5",
+            &md5,
+            "--chrom",
+            "chr1",
+            "--url-list",
+            "urls.csv",
+        ])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!conflicting.success());
+}
+
+#[test]
+fn test_quiet() {
+    let dir = test_dir("quiet");
+    let (url, md5) = served_vcf(b"#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n");
+
+    let stderr = |quiet: bool| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"));
+        command
+            .args([
+                "--region",
+                "chr1:1-10",
+                "--url",
+                &url,
+                "--md5",
+                &md5,
+                "--chrom",
+                "chr1",
+            ])
+            .arg("--output-dir")
+            .arg(&dir)
+            .arg("--overwrite")
+            .env_remove("RUST_LOG");
+        if quiet {
+            command.arg("--quiet");
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+
+    assert!(stderr(false).contains("Downloading file from"));
+    let quiet = stderr(true);
+    assert!(!quiet.contains("Downloading file from"));
+    assert!(!quiet.contains("Successfully downloaded"));
+}
+
+#[test]
+fn test_color_never() {
+    let dir = test_dir("color_never");
+    let (url, md5) = served_vcf(
+        b"#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t5\t.\tA\tG\t.\tPASS\t.\n",
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .args([
+            "--region",
+            "chr1:1-10",
+            "--url",
+            &url,
+            "--md5",
+            &md5,
+            "--chrom",
+            "chr1",
+            "--color",
+            "never",
+        ])
+        .arg("--output-dir")
+        .arg(&dir)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains('\x1b'), "{:?}", stderr);
+    // The progress is logged as plain lines instead
+    assert!(
+        stderr.contains("chr1: ") && stderr.contains("(100.0%)"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Total: "), "{}", stderr);
+}
+
+#[test]
+fn test_failed_download_outcomes() {
+    let dir = test_dir("outcomes");
+    let (url, md5) = served_vcf(b"#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n");
+    std::fs::write(
+        dir.join("urls.csv"),
+        format!(
+            "chr1,{},{}\nchr2,0123456789abcdef0123456789abcdef,{}\nchr3,{},http://127.0.0.1:9/chr3.vcf.bgz\n",
+            md5, url, url, md5
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .args([
+            "--region",
+            "chr1:1-10",
+            "--region",
+            "chr2:1-10",
+            "--region",
+            "chr3:1-10",
+        ])
+        .arg("--url-list")
+        .arg(dir.join("urls.csv"))
+        .arg("--output-dir")
+        .arg(dir.join("output"))
+        .args(["--retries", "0"])
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+
+    // The first failure in chromosome order, the checksum mismatch of chr2
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Successfully downloaded chr1"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Failed to download chr2 (checksum mismatch)"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("Failed to download chr3 (network error)"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains(&format!(
+            "Checksum mismatch for {}: expected 0123456789abcdef0123456789abcdef, got {}",
+            url, md5
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_redirected_download() {
+    let dir = test_dir("redirect");
+    let (target, md5) = served_vcf(b"#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n");
+    let url = serve_redirect(target.clone());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .args(["--region", "chr1:1-10", "--url", &url, "--md5", &md5])
+        .args(["--chrom", "chr1", "--verbose"])
+        .arg("--output-dir")
+        .arg(&dir)
+        .env("RUST_LOG", "smart_gnomad_downloader=debug")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!("Downloading {} from {}", url, target)),
+        "{}",
+        stderr
+    );
+    assert!(dir.join("chr1.vcf.gz").exists());
+}
+
+#[test]
+fn test_only_failed() {
+    let dir = test_dir("only_failed");
+    std::fs::write(
+        dir.join("urls.csv"),
+        "chr1,abcdef,http://127.0.0.1:9/chr1.vcf.bgz\nchr2,abcdef,http://127.0.0.1:9/chr2.vcf.bgz\n",
+    )
+    .unwrap();
+    let report = |chromosome: &str, success: bool| {
+        format!(
+            r#"{{"chromosome": "{}", "url": "http://127.0.0.1:9/{}.vcf.bgz", "expected_checksum": "abcdef",
+                "actual_checksum": null, "total_records": null, "kept_records": null,
+                "bytes_downloaded": null, "elapsed_seconds": 1.0, "success": {}, "error": null}}"#,
+            chromosome, chromosome, success
+        )
+    };
+    std::fs::write(
+        dir.join("report.json"),
+        format!("[{}, {}]", report("chr1", true), report("chr2", false)),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .args(["--region", "chr1:1-10", "--region", "chr2:1-10"])
+        .arg("--url-list")
+        .arg(dir.join("urls.csv"))
+        .arg("--only-failed")
+        .arg(dir.join("report.json"))
+        .arg("--dry-run")
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "chromosome\tintervals\tspan_bp\turl\n\
+         chr2\t1\t10\thttp://127.0.0.1:9/chr2.vcf.bgz\n"
+    );
+}
+
+#[test]
+fn test_empty_bed() {
+    let dir = test_dir("empty_bed");
+    std::fs::write(
+        dir.join("urls.csv"),
+        "chr1,abcdef,http://127.0.0.1:9/chr1.vcf.bgz\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("empty.bed"), "").unwrap();
+    std::fs::write(dir.join("short.bed"), "chr1\t100\t105\n").unwrap();
+    let run = |bed: &str, extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+            .arg("--bed")
+            .arg(dir.join(bed))
+            .arg("--url-list")
+            .arg(dir.join("urls.csv"))
+            .arg("--output-dir")
+            .arg(dir.join("out"))
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+
+    for (bed, extra_args) in [
+        ("empty.bed", &[][..]),
+        ("short.bed", &["--min-interval-size", "10"][..]),
+    ] {
+        let output = run(bed, extra_args);
+        assert_eq!(output.status.code(), Some(2), "{}", bed);
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("No region to download"));
+    }
+
+    let output = run("empty.bed", &["--allow-empty-bed"]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("No region to download"));
+}
+
+#[test]
+fn test_exclude_bed_with_pad() {
+    let dir = test_dir("exclude_pad");
+    std::fs::write(
+        dir.join("urls.csv"),
+        "chr1,abcdef,http://127.0.0.1:9/chr1.vcf.bgz\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("regions.bed"), "chr1\t0\t1000\n").unwrap();
+    std::fs::write(
+        dir.join("blacklist.bed"),
+        "chr1\t400\t600\nchr1\t1140\t1145\n",
+    )
+    .unwrap();
+    let plan = |extra_args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+            .arg("--bed")
+            .arg(dir.join("regions.bed"))
+            .arg("--exclude-bed")
+            .arg(dir.join("blacklist.bed"))
+            .arg("--url-list")
+            .arg(dir.join("urls.csv"))
+            .arg("--dry-run")
+            .args(extra_args)
+            .stderr(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let row = |intervals: u64, span_bp: u64| {
+        format!(
+            "chromosome\tintervals\tspan_bp\turl\n\
+             chr1\t{}\t{}\thttp://127.0.0.1:9/chr1.vcf.bgz\n",
+            intervals, span_bp
+        )
+    };
+
+    assert_eq!(plan(&[]), row(2, 800));
+    // Padded to 1-1150, then split around the excluded regions: the padding cannot bring them
+    // back
+    assert_eq!(plan(&["--pad", "150"]), row(3, 945));
+    // The 5 bp left after the second excluded region are dropped
+    assert_eq!(
+        plan(&["--pad", "150", "--min-interval-size", "10"]),
+        row(2, 940)
+    );
+}
+
+#[test]
+fn test_dropped_intervals_summary() {
+    let dir = test_dir("dropped_intervals");
+    let (url, md5) = served_vcf(
+        b"#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\nchr1\t5\t.\tA\tG\t.\tPASS\t.\n",
+    );
+    std::fs::write(
+        dir.join("regions.bed"),
+        "chr1\t0\t100\nchr1\t200\t201\nchr1\t300\t302\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_smart_gnomad_downloader"))
+        .arg("--bed")
+        .arg(dir.join("regions.bed"))
+        .args(["--url", &url, "--md5", &md5, "--chrom", "chr1"])
+        .args(["--min-interval-size", "10", "--quiet"])
+        .arg("--output-dir")
+        .arg(&dir)
+        .arg("--report")
+        .arg(dir.join("report.json"))
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    // Shown along with the other counts, even with --quiet
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let summary: Vec<Vec<&str>> = stderr
+        .lines()
+        .skip_while(|line| !line.starts_with("chromosome\t"))
+        .map(|line| line.split('\t').collect())
+        .collect();
+    assert_eq!(summary[0].last(), Some(&"dropped_intervals"), "{}", stderr);
+    assert_eq!(summary[1][0], "chr1");
+    assert_eq!(summary[1].last(), Some(&"2"));
+    assert_eq!(summary[2].last(), Some(&"2"));
+    let report: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report[0]["dropped_intervals"], 2);
+}
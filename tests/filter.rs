@@ -0,0 +1,41 @@
+use smart_gnomad_downloader::records::read_bed_regions;
+use smart_gnomad_downloader::{filter_vcf_stream, DownloadError};
+
+const VCF: &str = "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t100\t.\tA\tG\t.\tPASS\t.
+chr1\t101\t.\tC\tT\t.\tPASS\t.
+chr1\t200\t.\tG\tA\t.\tPASS\t.
+chr1\t201\t.\tT\tC\t.\tPASS\t.
+chr1\t1500\t.\tA\tT\t.\tPASS\t.
+";
+
+#[test]
+fn test_filter_in_memory_vcf() {
+    let regions = read_bed_regions("chr1\t100\t200\nchr1\t1000\t2000\n".as_bytes()).unwrap();
+
+    let mut output = Vec::new();
+    filter_vcf_stream(VCF.as_bytes(), &mut output, regions["chr1"].iter().copied()).unwrap();
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "\
+##fileformat=VCFv4.3
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO
+chr1\t101\t.\tC\tT\t.\tPASS\t.
+chr1\t200\t.\tG\tA\t.\tPASS\t.
+chr1\t1500\t.\tA\tT\t.\tPASS\t.
+"
+    );
+}
+
+#[test]
+fn test_filter_invalid_pos() {
+    let vcf = "#CHROM\tPOS\nchr1\tabc\n";
+    let mut output = Vec::new();
+    assert!(matches!(
+        filter_vcf_stream(vcf.as_bytes(), &mut output, [(1, 10)].into_iter()),
+        Err(DownloadError::Parse(_))
+    ));
+}